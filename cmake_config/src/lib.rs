@@ -18,15 +18,39 @@ assert_eq!(vec![
                 key: "FOO".into(),
                 cmake_type: CMakeType::Bool,
                 value: "ON".into(),
+                help: None,
+                advanced: false,
             },
             RawFlag {
                 key: "BAR".into(),
                 cmake_type: CMakeType::String,
                 value: "BAZ".into(),
+                help: None,
+                advanced: false,
             },
 ], flags);
 ```
 
+The `//` help comments preceding an entry and its `-ADVANCED` marker are
+retained, and `write_raw` turns flags back into CMakeCache.txt text.
+`write_initial_cache` instead writes them as a script of `set(... CACHE ...)`
+commands, for seeding a build directory with `cmake -C <script>`.
+
+```
+use std::io::{BufReader, Cursor};
+let s = r"//Flags for the C compiler
+CMAKE_C_FLAGS:STRING=-DFOO=1 -Wall
+CMAKE_C_FLAGS-ADVANCED:INTERNAL=1";
+let flags = cmake_config::parse_raw(BufReader::new(Cursor::new(s))).unwrap();
+assert_eq!(1, flags.len());
+assert_eq!("-DFOO=1 -Wall", flags[0].value);
+assert_eq!(Some("Flags for the C compiler".to_string()), flags[0].help);
+assert!(flags[0].advanced);
+
+let text = cmake_config::raw_to_string(&flags);
+assert_eq!(flags, cmake_config::parse_raw(BufReader::new(Cursor::new(text))).unwrap());
+```
+
 Optionally, Flags can be summarized from their initial `RawFlag` form
 into `SimpleFlag` instances for representation in Rust.
 
@@ -36,29 +60,56 @@ let raw = RawFlag {
           key: "FOO".into(),
           cmake_type: CMakeType::Bool,
           value: "ON".into(),
+          help: None,
+          advanced: false,
 };
 let simplified = SimpleFlag::from(&raw);
 assert_eq!(SimpleFlag::Boolish(Key("FOO".into()), true), simplified);
 ```
 
+The CMake type hint and the shape of the value select the variant: `BOOL`
+properties become `Boolish`, `PATH` and `FILEPATH` ones `Pathish`, and
+untyped strings become `Integerish` for plain decimal numbers, `Listish` for
+semicolon-separated lists and `Stringish` otherwise.
+
+```
+use cmake_config::{CMakeType, RawFlag, SimpleFlag, Key};
+let raw = RawFlag {
+          key: "KernelMaxNumNodes".into(),
+          cmake_type: CMakeType::String,
+          value: "4".into(),
+          help: None,
+          advanced: false,
+};
+let simplified = SimpleFlag::from(&raw);
+assert_eq!(SimpleFlag::Integerish(Key("KernelMaxNumNodes".into()), 4), simplified);
+assert_eq!(
+    "pub const KernelMaxNumNodes:usize = 4;",
+    simplified.generate_rust_const_item().code
+);
+```
+
 `SimpleFlag` also provides a convenience method, `generate_rust_const_item`,
-which produces the text of a Rust-lang const definition for that flag.
+which produces the text of a Rust-lang const definition for that flag. Keys
+that are not valid Rust identifiers are mangled by `mangle_rust_identifier`.
+Integer properties always become `usize` consts, whatever their value.
 
 */
 #[macro_use]
 extern crate lazy_static;
 extern crate regex;
-extern crate syn;
 
 #[cfg(test)]
 #[macro_use]
 extern crate proptest;
+#[cfg(test)]
+extern crate syn;
 
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Error as IoError};
+use std::io::{BufRead, BufReader, Error as IoError, Write};
 use std::path::Path;
-use syn::{parse_str, Expr, ExprLit, Lit};
 
 lazy_static! {
     static ref RUST_VALID_IDENTIFIER_REGEX: Regex =
@@ -71,6 +122,11 @@ pub struct RawFlag {
     pub key: String,
     pub cmake_type: CMakeType,
     pub value: String,
+    /// The `//` comment lines preceding the property, joined by newlines
+    pub help: Option<String>,
+    /// Whether the property is marked as advanced, which hides it in the
+    /// CMake GUIs by default
+    pub advanced: bool,
 }
 
 /// The type hint associated with a CMake property
@@ -91,6 +147,12 @@ pub enum CMakeType {
 pub enum SimpleFlag {
     Stringish(Key, String),
     Boolish(Key, bool),
+    /// A non-negative integer, such as `KernelMaxNumNodes`
+    Integerish(Key, u64),
+    /// The value of a `PATH` or `FILEPATH` property
+    Pathish(Key, String),
+    /// A semicolon-separated CMake list
+    Listish(Key, Vec<String>),
 }
 
 /// A newtype wrapper for the key / property-name
@@ -111,60 +173,115 @@ where
 
 impl<'a> From<&'a RawFlag> for SimpleFlag {
     fn from(raw: &'a RawFlag) -> Self {
+        let key = Key(raw.key.clone());
         match raw.cmake_type {
-            CMakeType::Bool => SimpleFlag::Boolish(
-                Key(raw.key.clone()),
-                interpret_value_as_boolish(raw.value.clone()),
-            ),
-            CMakeType::Path
-            | CMakeType::FilePath
-            | CMakeType::String
-            | CMakeType::Internal
-            | CMakeType::Static
-            | CMakeType::Uninitialized => {
-                SimpleFlag::Stringish(Key(raw.key.clone()), raw.value.clone())
+            CMakeType::Bool => SimpleFlag::Boolish(key, interpret_value_as_boolish(&raw.value)),
+            CMakeType::Path | CMakeType::FilePath => SimpleFlag::Pathish(key, raw.value.clone()),
+            CMakeType::String | CMakeType::Uninitialized => {
+                SimpleFlag::from_string_value(key, &raw.value)
+            }
+            CMakeType::Internal | CMakeType::Static => {
+                SimpleFlag::Stringish(key, raw.value.clone())
             }
         }
     }
 }
 
 impl SimpleFlag {
-    /// Produce code that could be used in a Rust language file
-    /// to represent the flag as a `const` item.
-    pub fn generate_rust_const_item(&self) -> Result<RustConstItem, RustCodeGenerationError> {
+    /// Interpret an untyped string value the way CMake code would use it:
+    /// as an integer when it is written as a plain decimal number, as a
+    /// list when it contains semicolons, and as a string otherwise.
+    pub fn from_string_value<S: AsRef<str>>(key: Key, value: S) -> SimpleFlag {
+        let value = value.as_ref();
+        match value.parse::<u64>() {
+            // Only canonical numbers, so that e.g. "007" keeps its spelling
+            Ok(n) if n.to_string() == value => SimpleFlag::Integerish(key, n),
+            _ if value.contains(';') => {
+                SimpleFlag::Listish(key, value.split(';').map(String::from).collect())
+            }
+            _ => SimpleFlag::Stringish(key, value.to_string()),
+        }
+    }
+
+    pub fn key(&self) -> &Key {
         match self {
-            SimpleFlag::Stringish(Key(k), v) => {
-                if !is_valid_rust_identifier(&k) {
-                    return Err(RustCodeGenerationError::InvalidIdentifier(k.clone()));
-                }
-                let literal = format!("\"{}\"", &v);
-                let valid_literal = match parse_str::<Expr>(&literal) {
-                    Ok(Expr::Lit(ExprLit {
-                        lit: Lit::Str(_), ..
-                    })) => true,
-                    _ => false,
-                };
-                // TODO - consider applying string escaping as a fallback?
-                if !valid_literal {
-                    return Err(RustCodeGenerationError::InvalidStringLiteral(v.clone()));
-                }
-                let code = format!("pub const {}:&'static str = {};", &k, &literal);
-                Ok(RustConstItem {
-                    code,
-                    identifier: k.clone(),
-                })
+            SimpleFlag::Stringish(k, _) => k,
+            SimpleFlag::Boolish(k, _) => k,
+            SimpleFlag::Integerish(k, _) => k,
+            SimpleFlag::Pathish(k, _) => k,
+            SimpleFlag::Listish(k, _) => k,
+        }
+    }
+
+    /// The value as text, with lists joined by semicolons as in CMake
+    pub fn value_string(&self) -> String {
+        match self {
+            SimpleFlag::Stringish(_, v) => v.clone(),
+            SimpleFlag::Boolish(_, v) => v.to_string(),
+            SimpleFlag::Integerish(_, v) => v.to_string(),
+            SimpleFlag::Pathish(_, v) => v.clone(),
+            SimpleFlag::Listish(_, v) => v.join(";"),
+        }
+    }
+
+    /// Produce code that could be used in a Rust language file
+    /// to represent the flag as a `const` item. Keys that are not usable
+    /// as identifiers are mangled with `mangle_rust_identifier`, and the
+    /// original key is kept in a doc attribute.
+    pub fn generate_rust_const_item(&self) -> RustConstItem {
+        let Key(k) = self.key();
+        let identifier = mangle_rust_identifier(k);
+        let doc = if &identifier == k {
+            String::new()
+        } else {
+            format!(
+                "#[doc = {}] ",
+                rust_string_literal(&format!("The `{}` CMake property", k))
+            )
+        };
+        let (rust_type, value) = match self {
+            SimpleFlag::Stringish(_, v) | SimpleFlag::Pathish(_, v) => {
+                ("&'static str".to_string(), rust_string_literal(v))
             }
-            SimpleFlag::Boolish(Key(k), v) => {
-                if !is_valid_rust_identifier(&k) {
-                    return Err(RustCodeGenerationError::InvalidIdentifier(k.clone()));
-                }
-                Ok(RustConstItem {
-                    code: format!("pub const {}:bool = {};", &k, v),
-                    identifier: k.clone(),
-                })
+            SimpleFlag::Boolish(_, v) => ("bool".to_string(), v.to_string()),
+            // Always usize, so that the type of a const does not change with
+            // its value between configurations. A value too large for the
+            // target's usize fails to compile rather than changing type.
+            SimpleFlag::Integerish(_, v) => ("usize".to_string(), v.to_string()),
+            SimpleFlag::Listish(_, v) => {
+                let literals: Vec<String> = v.iter().map(|e| rust_string_literal(e)).collect();
+                (
+                    "&'static [&'static str]".to_string(),
+                    format!("&[{}]", literals.join(", ")),
+                )
             }
+        };
+        RustConstItem {
+            code: format!("{}pub const {}:{} = {};", doc, identifier, rust_type, value),
+            identifier,
+        }
+    }
+}
+
+/// A Rust string literal holding the given text, with the characters that
+/// may not appear verbatim in a literal escaped
+pub fn rust_string_literal(v: &str) -> String {
+    let mut literal = String::with_capacity(v.len() + 2);
+    literal.push('"');
+    for c in v.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            '\0' => literal.push_str("\\0"),
+            c if c.is_control() => literal.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => literal.push(c),
         }
     }
+    literal.push('"');
+    literal
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Ord, PartialOrd)]
@@ -179,10 +296,74 @@ pub fn is_valid_rust_identifier<S: AsRef<str>>(s: S) -> bool {
     RUST_VALID_IDENTIFIER_REGEX.is_match(s.as_ref())
 }
 
+/// The keywords of both Rust editions, including the reserved ones
+const RUST_KEYWORDS: &[&str] = &[
+    "Self", "abstract", "alignof", "as", "async", "await", "become", "box", "break", "const",
+    "continue", "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for",
+    "if", "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "offsetof",
+    "override", "priv", "proc", "pub", "pure", "ref", "return", "self", "sizeof", "static",
+    "struct", "super", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+/// Turn a CMake key into a Rust identifier. Valid identifiers are kept as
+/// they are, otherwise every character besides ASCII letters, digits and
+/// underscores is replaced by an underscore, an underscore is prepended
+/// when the result would start with a digit or consist of underscores only,
+/// and one is appended to keywords.
+///
+/// Distinct keys may map to the same identifier, which
+/// `check_identifier_collisions` detects.
+pub fn mangle_rust_identifier<S: AsRef<str>>(key: S) -> String {
+    let key = key.as_ref();
+    if is_valid_rust_identifier(key) && !RUST_KEYWORDS.contains(&key) {
+        return key.to_string();
+    }
+    let mut identifier: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if identifier.starts_with(|c: char| c.is_ascii_digit()) || identifier.chars().all(|c| c == '_')
+    {
+        identifier.insert(0, '_');
+    }
+    if identifier == "_" {
+        identifier.push('_');
+    }
+    if RUST_KEYWORDS.contains(&identifier.as_str()) {
+        identifier.push('_');
+    }
+    identifier
+}
+
+/// Check that no two of the keys map to the same Rust identifier
+pub fn check_identifier_collisions<'a, I>(keys: I) -> Result<(), RustCodeGenerationError>
+where
+    I: IntoIterator<Item = &'a Key>,
+{
+    let mut identifiers: BTreeMap<String, &str> = BTreeMap::new();
+    for Key(key) in keys {
+        let identifier = mangle_rust_identifier(key);
+        if let Some(first_key) = identifiers.get(&identifier) {
+            return Err(RustCodeGenerationError::IdentifierCollision {
+                identifier,
+                first_key: first_key.to_string(),
+                second_key: key.clone(),
+            });
+        }
+        identifiers.insert(identifier, key);
+    }
+    Ok(())
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum RustCodeGenerationError {
-    InvalidIdentifier(String),
-    InvalidStringLiteral(String),
+    /// Two keys, possibly the same one twice, map to the same identifier
+    IdentifierCollision {
+        identifier: String,
+        first_key: String,
+        second_key: String,
+    },
 }
 
 pub fn interpret_value_as_boolish<S: AsRef<str>>(s: S) -> bool {
@@ -221,32 +402,83 @@ impl CMakeType {
 }
 
 /// The usual things that might go wrong when interpreting
-/// a CMakeCache blob of data
+/// a CMakeCache blob of data. Line numbers count from 1.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ParseError {
-    InvalidTypeHint,
-    PropertyMissingKeyTypeValueTriple,
+    InvalidTypeHint(usize),
+    PropertyMissingKeyTypeValueTriple(usize),
     IoFailure,
 }
 
+impl ParseError {
+    /// The line the error was found on, if it concerns a particular line
+    pub fn line(&self) -> Option<usize> {
+        match *self {
+            ParseError::InvalidTypeHint(line) => Some(line),
+            ParseError::PropertyMissingKeyTypeValueTriple(line) => Some(line),
+            ParseError::IoFailure => None,
+        }
+    }
+}
+
 impl From<IoError> for ParseError {
     fn from(_: IoError) -> Self {
         ParseError::IoFailure
     }
 }
 
+/// The suffix of the internal entries that mark another entry as advanced
+const ADVANCED_SUFFIX: &str = "-ADVANCED";
+
 /// Most generic entry point, intended to interpret a buffered reader
 /// of CMakeCache textual data into the represented flags.
 ///
+/// The `//` help comment lines directly preceding an entry are kept as its
+/// help text, and the `<KEY>-ADVANCED` internal entries are folded into the
+/// `advanced` field of the entry they refer to.
+///
 /// Technically could be formulate-able as returning an iterator, and presently
 /// only uses `Vec` out of convenience.
 pub fn parse_raw<B: BufRead>(b: B) -> Result<Vec<RawFlag>, ParseError> {
-    let mut v = Vec::new();
-    for line_result in b.lines() {
+    let mut v: Vec<RawFlag> = Vec::new();
+    let mut help: Vec<String> = Vec::new();
+    for (index, line_result) in b.lines().enumerate() {
         let line = line_result?;
-        if let Some(flag) = parse_line(line)? {
-            v.push(flag);
+        // Help text keeps its trailing whitespace
+        let indent = line
+            .find(|c: char| !c.is_whitespace())
+            .unwrap_or_else(|| line.len());
+        if line[indent..].starts_with("//") {
+            help.push(line[indent + 2..].to_string());
+            continue;
+        }
+        match parse_line(index + 1, &line)? {
+            Some(mut flag) => {
+                if !help.is_empty() {
+                    flag.help = Some(help.join("\n"));
+                }
+                v.push(flag);
+            }
+            // Help text only applies to the entry directly below it
+            None => (),
         }
+        help.clear();
+    }
+
+    let mut markers = Vec::new();
+    for (index, flag) in v.iter().enumerate() {
+        if flag.cmake_type != CMakeType::Internal || !flag.key.ends_with(ADVANCED_SUFFIX) {
+            continue;
+        }
+        let target_key = &flag.key[..flag.key.len() - ADVANCED_SUFFIX.len()];
+        if let Some(target) = v.iter().position(|f| f.key == target_key) {
+            markers.push((index, target, interpret_value_as_boolish(&flag.value)));
+        }
+    }
+    // Remove the markers back to front, so that the indices stay valid
+    for &(marker, target, advanced) in markers.iter().rev() {
+        v[target].advanced = advanced;
+        v.remove(marker);
     }
     Ok(v)
 }
@@ -257,35 +489,178 @@ pub fn parse_file_to_raw<P: AsRef<Path>>(file_path: P) -> Result<Vec<RawFlag>, P
     parse_raw(BufReader::new(File::open(file_path)?))
 }
 
-fn parse_line<S: AsRef<str>>(l: S) -> Result<Option<RawFlag>, ParseError> {
+/// Parse a single `KEY:TYPE=VALUE` line, the way CMake itself does: the key
+/// ends at the first `:` unless it is quoted, the type at the first `=`, and
+/// the value takes the remainder of the line, without the single quotes
+/// CMake adds around values with leading or trailing whitespace.
+fn parse_line<S: AsRef<str>>(line_number: usize, l: S) -> Result<Option<RawFlag>, ParseError> {
     let line = l.as_ref().trim();
     // skip comments and empty lines
     if line.starts_with('#') || line.starts_with("//") || line.is_empty() {
         return Ok(None);
     }
-    // split line into tokens: key:type=value -> ["key", "type", "value"]
-    let tokens: Vec<&str> = line.split(|c| c == ':' || c == '=').collect();
-    if tokens.len() < 3 {
-        return Err(ParseError::PropertyMissingKeyTypeValueTriple);
-    }
-    let (key, maybe_type_hint, value) = (
-        tokens[0].trim(),
-        CMakeType::parse(tokens[1].trim()),
-        tokens[2].trim(),
-    );
-    let type_hint = if let Some(th) = maybe_type_hint {
-        th
+    let missing_triple = ParseError::PropertyMissingKeyTypeValueTriple(line_number);
+    let (key, rest) = if line.starts_with('"') {
+        let end = line[1..].find('"').ok_or_else(|| missing_triple.clone())? + 1;
+        (&line[1..end], &line[end + 1..])
     } else {
-        return Err(ParseError::InvalidTypeHint);
+        let end = line
+            .find(|c| c == ':' || c == '=')
+            .ok_or_else(|| missing_triple.clone())?;
+        (&line[..end], &line[end..])
     };
+    if !rest.starts_with(':') {
+        return Err(missing_triple);
+    }
+    let equals = rest.find('=').ok_or_else(|| missing_triple.clone())?;
+    let type_hint = CMakeType::parse(rest[1..equals].trim())
+        .ok_or_else(|| ParseError::InvalidTypeHint(line_number))?;
+    let mut value = &rest[equals + 1..];
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        value = &value[1..value.len() - 1];
+    }
     let flag = RawFlag {
-        key: key.to_string(),
+        key: key.trim().to_string(),
         cmake_type: type_hint,
         value: value.to_string(),
+        help: None,
+        advanced: false,
     };
     Ok(Some(flag))
 }
 
+/// Write flags out as CMakeCache.txt text, which `parse_raw` reads back into
+/// the same flags. Help text is written as `//` comments above each entry,
+/// and advanced entries are marked by `<KEY>-ADVANCED` internal entries at
+/// the end.
+pub fn write_raw<'a, I, W>(flags: I, writer: &mut W) -> Result<(), IoError>
+where
+    I: IntoIterator<Item = &'a RawFlag>,
+    W: Write,
+{
+    writer.write_all(
+        b"# This is the CMakeCache file.\n\
+          # It was written by cmake_config.\n\n",
+    )?;
+    let mut advanced_keys = Vec::new();
+    for flag in flags {
+        write_entry(writer, flag)?;
+        if flag.advanced {
+            advanced_keys.push(flag.key.clone());
+        }
+    }
+    for key in advanced_keys {
+        write_entry(
+            writer,
+            &RawFlag {
+                key: format!("{}{}", key, ADVANCED_SUFFIX),
+                cmake_type: CMakeType::Internal,
+                value: "1".to_string(),
+                help: Some(format!("ADVANCED property for variable: {}", key)),
+                advanced: false,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Convenience wrapper around `write_raw` producing a `String`
+pub fn raw_to_string<'a, I>(flags: I) -> String
+where
+    I: IntoIterator<Item = &'a RawFlag>,
+{
+    let mut text = Vec::new();
+    write_raw(flags, &mut text).expect("Writing to a Vec cannot fail");
+    String::from_utf8_lossy(&text).into_owned()
+}
+
+fn write_entry<W: Write>(writer: &mut W, flag: &RawFlag) -> Result<(), IoError> {
+    if let Some(ref help) = flag.help {
+        for line in help.split('\n') {
+            writeln!(writer, "//{}", line)?;
+        }
+    }
+    // CMake quotes the keys its parser would otherwise split
+    let key_needs_quotes = flag.key.contains(|c| c == ':' || c == '=')
+        || flag.key.starts_with('#')
+        || flag.key.starts_with("//")
+        || flag.key != flag.key.trim();
+    let value_needs_quotes = flag.value != flag.value.trim()
+        || (flag.value.len() >= 2 && flag.value.starts_with('\'') && flag.value.ends_with('\''));
+    let quote = |needs_quotes: bool, q: &'static str| if needs_quotes { q } else { "" };
+    writeln!(
+        writer,
+        "{kq}{key}{kq}:{cmake_type}={vq}{value}{vq}\n",
+        kq = quote(key_needs_quotes, "\""),
+        key = flag.key,
+        cmake_type = flag.cmake_type.cmake_name(),
+        vq = quote(value_needs_quotes, "'"),
+        value = flag.value
+    )
+}
+
+/// Write flags out as an initial-cache script, for use with `cmake -C`,
+/// made of one `set(<KEY> <VALUE> CACHE <TYPE> <HELP> FORCE)` command per
+/// flag. `FORCE` makes the script take precedence over the values an
+/// existing CMakeCache.txt holds, and advanced flags are marked with
+/// `mark_as_advanced`.
+pub fn write_initial_cache<'a, I, W>(flags: I, writer: &mut W) -> Result<(), IoError>
+where
+    I: IntoIterator<Item = &'a RawFlag>,
+    W: Write,
+{
+    writer.write_all(b"# Initial cache script written by cmake_config, for use with cmake -C\n")?;
+    for flag in flags {
+        writeln!(
+            writer,
+            "set({} {} CACHE {} {} FORCE)",
+            cmake_quoted_argument(&flag.key),
+            cmake_quoted_argument(&flag.value),
+            flag.cmake_type.cmake_name(),
+            cmake_quoted_argument(flag.help.as_ref().map(String::as_str).unwrap_or(""))
+        )?;
+        if flag.advanced {
+            writeln!(
+                writer,
+                "mark_as_advanced({})",
+                cmake_quoted_argument(&flag.key)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Convenience wrapper around `write_initial_cache` producing a `String`
+pub fn initial_cache_to_string<'a, I>(flags: I) -> String
+where
+    I: IntoIterator<Item = &'a RawFlag>,
+{
+    let mut text = Vec::new();
+    write_initial_cache(flags, &mut text).expect("Writing to a Vec cannot fail");
+    String::from_utf8_lossy(&text).into_owned()
+}
+
+/// A CMake quoted argument holding the given text verbatim, with the
+/// characters CMake would otherwise interpret escaped
+fn cmake_quoted_argument(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '\\' | '"' | '$' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -293,64 +668,173 @@ mod tests {
 
     #[test]
     fn parse_line_empty_edge_cases() {
-        assert_eq!(Ok(None), parse_line(""));
-        assert_eq!(Ok(None), parse_line("# Comment"));
-        assert_eq!(Ok(None), parse_line("// Comment"));
-        assert_eq!(Ok(None), parse_line("#key:BOOL=TRUE"));
-        assert_eq!(Ok(None), parse_line("//key:BOOL=TRUE"));
+        assert_eq!(Ok(None), parse_line(1, ""));
+        assert_eq!(Ok(None), parse_line(1, "# Comment"));
+        assert_eq!(Ok(None), parse_line(1, "// Comment"));
+        assert_eq!(Ok(None), parse_line(1, "#key:BOOL=TRUE"));
+        assert_eq!(Ok(None), parse_line(1, "//key:BOOL=TRUE"));
     }
 
     #[test]
     fn parse_line_missing_content() {
         assert_eq!(
-            Err(ParseError::PropertyMissingKeyTypeValueTriple),
-            parse_line("=")
+            Err(ParseError::PropertyMissingKeyTypeValueTriple(1)),
+            parse_line(1, "=")
+        );
+        assert_eq!(
+            Err(ParseError::PropertyMissingKeyTypeValueTriple(2)),
+            parse_line(2, ":")
+        );
+        assert_eq!(Err(ParseError::InvalidTypeHint(3)), parse_line(3, ":="));
+        assert_eq!(
+            Err(ParseError::PropertyMissingKeyTypeValueTriple(4)),
+            parse_line(4, "=:")
         );
         assert_eq!(
-            Err(ParseError::PropertyMissingKeyTypeValueTriple),
-            parse_line(":")
+            Err(ParseError::PropertyMissingKeyTypeValueTriple(5)),
+            parse_line(5, "\"KEY:STRING=value")
+        );
+    }
+
+    #[test]
+    fn parse_line_splits_on_first_separators() {
+        let flag = parse_line(1, "CMAKE_C_FLAGS:STRING=-DA=1 -DB:C=2")
+            .unwrap()
+            .unwrap();
+        assert_eq!("CMAKE_C_FLAGS", flag.key);
+        assert_eq!(CMakeType::String, flag.cmake_type);
+        assert_eq!("-DA=1 -DB:C=2", flag.value);
+
+        let flag = parse_line(1, "\"A:B=C\":PATH=/x").unwrap().unwrap();
+        assert_eq!("A:B=C", flag.key);
+        assert_eq!(CMakeType::Path, flag.cmake_type);
+        assert_eq!("/x", flag.value);
+
+        let flag = parse_line(1, "PADDED:STRING=' spaced '").unwrap().unwrap();
+        assert_eq!(" spaced ", flag.value);
+    }
+
+    #[test]
+    fn parse_raw_help_and_advanced() {
+        let s = r"# Hash comments are not help text
+//First line
+//second line
+FOO:BOOL=ON
+
+//Separated by a blank line
+
+BAR:STRING=BAZ
+ORPHAN-ADVANCED:INTERNAL=1
+FOO-ADVANCED:INTERNAL=1
+BAD LINE";
+        let error = parse_raw(BufReader::new(s.as_bytes())).unwrap_err();
+        assert_eq!(ParseError::PropertyMissingKeyTypeValueTriple(11), error);
+        assert_eq!(Some(11), error.line());
+
+        let flags = parse_raw(BufReader::new(s[..s.len() - "BAD LINE".len()].as_bytes())).unwrap();
+        assert_eq!(
+            vec![
+                RawFlag {
+                    key: "FOO".into(),
+                    cmake_type: CMakeType::Bool,
+                    value: "ON".into(),
+                    help: Some("First line\nsecond line".into()),
+                    advanced: true,
+                },
+                RawFlag {
+                    key: "BAR".into(),
+                    cmake_type: CMakeType::String,
+                    value: "BAZ".into(),
+                    help: None,
+                    advanced: false,
+                },
+                RawFlag {
+                    key: "ORPHAN-ADVANCED".into(),
+                    cmake_type: CMakeType::Internal,
+                    value: "1".into(),
+                    help: None,
+                    advanced: false,
+                },
+            ],
+            flags
+        );
+    }
+
+    #[test]
+    fn initial_cache_script() {
+        let flags = vec![
+            RawFlag {
+                key: "KernelVerificationBuild".into(),
+                cmake_type: CMakeType::Bool,
+                value: "OFF".into(),
+                help: Some("Set from fel4.toml".into()),
+                advanced: false,
+            },
+            RawFlag {
+                key: "CROSS_COMPILER_PREFIX".into(),
+                cmake_type: CMakeType::String,
+                value: "say \"${HOME}\\bin\";a\nb".into(),
+                help: None,
+                advanced: true,
+            },
+        ];
+        assert_eq!(
+            r#"# Initial cache script written by cmake_config, for use with cmake -C
+set("KernelVerificationBuild" "OFF" CACHE BOOL "Set from fel4.toml" FORCE)
+set("CROSS_COMPILER_PREFIX" "say \"\${HOME}\\bin\";a\nb" CACHE STRING "" FORCE)
+mark_as_advanced("CROSS_COMPILER_PREFIX")
+"#,
+            initial_cache_to_string(&flags)
         );
-        assert_eq!(Err(ParseError::InvalidTypeHint), parse_line(":="));
-        assert_eq!(Err(ParseError::InvalidTypeHint), parse_line("=:"));
     }
 
     #[test]
     fn sanity_check_bool_const_ok() {
         assert_eq!(
-            Ok(RustConstItem {
+            RustConstItem {
                 code: "pub const HELLO_WORLD:bool = false;".to_string(),
                 identifier: "HELLO_WORLD".to_string(),
-            }),
+            },
             SimpleFlag::Boolish(Key("HELLO_WORLD".to_string()), false).generate_rust_const_item()
         );
         assert_eq!(
-            Ok(RustConstItem {
+            RustConstItem {
                 code: "pub const helloWorld:bool = false;".to_string(),
                 identifier: "helloWorld".to_string(),
-            }),
+            },
             SimpleFlag::Boolish(Key("helloWorld".to_string()), false).generate_rust_const_item()
         );
         assert_eq!(
-            Ok(RustConstItem {
+            RustConstItem {
                 code: "pub const hello_world:bool = false;".to_string(),
                 identifier: "hello_world".to_string(),
-            }),
+            },
             SimpleFlag::Boolish(Key("hello_world".to_string()), false).generate_rust_const_item()
         );
     }
 
     #[test]
-    fn sanity_check_bool_const_error() {
+    fn sanity_check_bool_const_mangled() {
         assert_eq!(
-            Err(RustCodeGenerationError::InvalidIdentifier("_".into())),
+            RustConstItem {
+                code: "#[doc = \"The `_` CMake property\"] pub const __:bool = false;".to_string(),
+                identifier: "__".to_string(),
+            },
             SimpleFlag::Boolish(Key("_".to_string()), false).generate_rust_const_item()
         );
         assert_eq!(
-            Err(RustCodeGenerationError::InvalidIdentifier("0".into())),
+            RustConstItem {
+                code: "#[doc = \"The `0` CMake property\"] pub const _0:bool = false;".to_string(),
+                identifier: "_0".to_string(),
+            },
             SimpleFlag::Boolish(Key("0".to_string()), false).generate_rust_const_item()
         );
         assert_eq!(
-            Err(RustCodeGenerationError::InvalidIdentifier("0ABC".into())),
+            RustConstItem {
+                code: "#[doc = \"The `0ABC` CMake property\"] pub const _0ABC:bool = false;"
+                    .to_string(),
+                identifier: "_0ABC".to_string(),
+            },
             SimpleFlag::Boolish(Key("0ABC".to_string()), false).generate_rust_const_item()
         );
     }
@@ -358,56 +842,164 @@ mod tests {
     #[test]
     fn sanity_check_str_const() {
         assert_eq!(
-            Ok(RustConstItem {
+            RustConstItem {
                 code: "pub const HELLO_WORLD:&'static str = \"whatever\";".to_string(),
                 identifier: "HELLO_WORLD".to_string(),
-            }),
+            },
             SimpleFlag::Stringish(Key("HELLO_WORLD".to_string()), "whatever".to_string())
                 .generate_rust_const_item()
         );
         assert_eq!(
-            Ok(RustConstItem {
+            RustConstItem {
                 code: "pub const helloWorld:&'static str = \"whatever\";".to_string(),
                 identifier: "helloWorld".to_string(),
-            }),
+            },
             SimpleFlag::Stringish(Key("helloWorld".to_string()), "whatever".to_string())
                 .generate_rust_const_item()
         );
         assert_eq!(
-            Ok(RustConstItem {
+            RustConstItem {
                 code: "pub const hello_world:&'static str = \"whatever\";".to_string(),
                 identifier: "hello_world".to_string(),
-            }),
+            },
             SimpleFlag::Stringish(Key("hello_world".to_string()), "whatever".to_string())
                 .generate_rust_const_item()
         );
     }
 
     #[test]
-    fn sanity_check_str_const_id_error() {
+    fn mangle_identifiers() {
+        assert_eq!("KernelArch", mangle_rust_identifier("KernelArch"));
+        assert_eq!("_0ABC", mangle_rust_identifier("0ABC"));
+        assert_eq!("Kernel_Max_Nodes", mangle_rust_identifier("Kernel-Max.Nodes"));
+        assert_eq!("type_", mangle_rust_identifier("type"));
+        assert_eq!("__", mangle_rust_identifier(""));
+        assert_eq!("___", mangle_rust_identifier("-_"));
+        assert_eq!("caf_", mangle_rust_identifier("caf\u{e9}"));
         assert_eq!(
-            Err(RustCodeGenerationError::InvalidIdentifier("_".into())),
-            SimpleFlag::Stringish(Key("_".to_string()), "a".into()).generate_rust_const_item()
+            Ok(()),
+            check_identifier_collisions(&[Key("a-b".into()), Key("a_c".into())])
         );
         assert_eq!(
-            Err(RustCodeGenerationError::InvalidIdentifier("0".into())),
-            SimpleFlag::Stringish(Key("0".to_string()), "a".into()).generate_rust_const_item()
+            Err(RustCodeGenerationError::IdentifierCollision {
+                identifier: "a_b".into(),
+                first_key: "a-b".into(),
+                second_key: "a.b".into(),
+            }),
+            check_identifier_collisions(&[Key("a-b".into()), Key("a.b".into())])
         );
+    }
+
+    #[test]
+    fn sanity_check_str_const_mangled() {
         assert_eq!(
-            Err(RustCodeGenerationError::InvalidIdentifier("0ABC".into())),
-            SimpleFlag::Stringish(Key("0ABC".to_string()), "a".into()).generate_rust_const_item()
+            RustConstItem {
+                code: "#[doc = \"The `KERNEL-\\\"X\\\"` CMake property\"] \
+                       pub const KERNEL__X_:&'static str = \"a\";"
+                    .to_string(),
+                identifier: "KERNEL__X_".to_string(),
+            },
+            SimpleFlag::Stringish(Key("KERNEL-\"X\"".to_string()), "a".into())
+                .generate_rust_const_item()
         );
     }
 
     #[test]
-    fn sanity_check_str_const_literal_error() {
+    fn refine_typed_values() {
+        let refine = |t: CMakeType, value: &str| {
+            SimpleFlag::from(&RawFlag {
+                key: "K".into(),
+                cmake_type: t,
+                value: value.into(),
+                help: None,
+                advanced: false,
+            })
+        };
+        assert_eq!(
+            SimpleFlag::Integerish(Key("K".into()), 4),
+            refine(CMakeType::String, "4")
+        );
+        assert_eq!(
+            SimpleFlag::Stringish(Key("K".into()), "007".into()),
+            refine(CMakeType::String, "007")
+        );
+        assert_eq!(
+            SimpleFlag::Stringish(Key("K".into()), "-1".into()),
+            refine(CMakeType::String, "-1")
+        );
+        assert_eq!(
+            SimpleFlag::Listish(Key("K".into()), vec!["a".into(), "b".into()]),
+            refine(CMakeType::String, "a;b")
+        );
         assert_eq!(
-            Err(RustCodeGenerationError::InvalidStringLiteral("\\".into())),
+            SimpleFlag::Pathish(Key("K".into()), "/a;b".into()),
+            refine(CMakeType::FilePath, "/a;b")
+        );
+        assert_eq!(
+            SimpleFlag::Stringish(Key("K".into()), "12".into()),
+            refine(CMakeType::Internal, "12")
+        );
+    }
+
+    #[test]
+    fn sanity_check_typed_consts() {
+        assert_eq!(
+            RustConstItem {
+                code: "pub const KernelMaxNumNodes:usize = 4;".to_string(),
+                identifier: "KernelMaxNumNodes".to_string(),
+            },
+            SimpleFlag::Integerish(Key("KernelMaxNumNodes".to_string()), 4)
+                .generate_rust_const_item()
+        );
+        assert_eq!(
+            RustConstItem {
+                code: "pub const Big:usize = 4294967296;".to_string(),
+                identifier: "Big".to_string(),
+            },
+            SimpleFlag::Integerish(Key("Big".to_string()), 1 << 32).generate_rust_const_item()
+        );
+        assert_eq!(
+            RustConstItem {
+                code: "pub const Dir:&'static str = \"/opt/sel4\";".to_string(),
+                identifier: "Dir".to_string(),
+            },
+            SimpleFlag::Pathish(Key("Dir".to_string()), "/opt/sel4".into())
+                .generate_rust_const_item()
+        );
+        assert_eq!(
+            RustConstItem {
+                code: "pub const List:&'static [&'static str] = &[\"a\", \"\"];".to_string(),
+                identifier: "List".to_string(),
+            },
+            SimpleFlag::Listish(Key("List".to_string()), vec!["a".into(), "".into()])
+                .generate_rust_const_item()
+        );
+        assert_eq!(
+            RustConstItem {
+                code: "pub const List:&'static [&'static str] = &[\"\\\\\"];".to_string(),
+                identifier: "List".to_string(),
+            },
+            SimpleFlag::Listish(Key("List".to_string()), vec!["\\".into()])
+                .generate_rust_const_item()
+        );
+    }
+
+    #[test]
+    fn sanity_check_str_const_escaping() {
+        assert_eq!(
+            RustConstItem {
+                code: "pub const a:&'static str = \"\\\\\";".to_string(),
+                identifier: "a".to_string(),
+            },
             SimpleFlag::Stringish(Key("a".to_string()), "\\".into()).generate_rust_const_item()
         );
         assert_eq!(
-            Err(RustCodeGenerationError::InvalidStringLiteral("\"".into())),
-            SimpleFlag::Stringish(Key("a".to_string()), "\"".into()).generate_rust_const_item()
+            RustConstItem {
+                code: "pub const a:&'static str = \"\\\"\\n\\u{1b}[0m\";".to_string(),
+                identifier: "a".to_string(),
+            },
+            SimpleFlag::Stringish(Key("a".to_string()), "\"\n\u{1b}[0m".into())
+                .generate_rust_const_item()
         );
     }
 
@@ -424,7 +1016,12 @@ mod tests {
     }
 
     prop_compose! {
-        fn arb_valid_rustificable_key()(ref key in r"([a-zA-Z][a-zA-Z0-9_]+)|(_[a-zA-Z][a-zA-Z0-9_]+)") -> String {
+        fn arb_valid_rustificable_key()(
+            ref key in r"([a-zA-Z][a-zA-Z0-9_]+)|(_[a-zA-Z][a-zA-Z0-9_]+)"
+                .prop_filter("Rust keywords are mangled", |key| {
+                    !RUST_KEYWORDS.contains(&key.as_str())
+                })
+        ) -> String {
             key.to_string()
         }
     }
@@ -432,11 +1029,29 @@ mod tests {
     prop_compose! {
         fn arb_raw_flag()(ref key in arb_valid_rustificable_key(),
                           ref t in arb_cmake_type(),
-                          ref val in "[^\\s:=#/\"\\\\]*") -> RawFlag {
+                          ref val in "[^\\s:=#/\"'\\\\]*") -> RawFlag {
             RawFlag {
                key: key.to_string(),
                cmake_type: t.clone(),
                value: val.to_string(),
+               help: None,
+               advanced: false,
+            }
+        }
+    }
+
+    prop_compose! {
+        fn arb_documented_raw_flag()(ref key in "[^\"\n\r]+",
+                                     ref t in arb_cmake_type(),
+                                     ref val in "[^\n\r]*",
+                                     ref help in proptest::option::of("[^\n\r]*(\n[^\n\r]*)*"),
+                                     advanced in proptest::bool::ANY) -> RawFlag {
+            RawFlag {
+               key: key.trim().to_string(),
+               cmake_type: t.clone(),
+               value: val.to_string(),
+               help: help.clone(),
+               advanced,
             }
         }
     }
@@ -450,7 +1065,7 @@ mod tests {
     proptest! {
         #[test]
         fn arbitrary_string_no_panic(ref l in ".*") {
-            let _ = parse_line(l);
+            let _ = parse_line(1, l);
         }
 
 
@@ -458,10 +1073,22 @@ mod tests {
         fn arbitrary_valid_parseable_raw(ref raw_flag in arb_raw_flag()) {
             let expected = raw_flag.clone();
             let l = format!("{}:{}={}", raw_flag.key, raw_flag.cmake_type.cmake_name(), raw_flag.value);
-            let f = parse_line(l).expect("Should be parseable!");
+            let f = parse_line(1, l).expect("Should be parseable!");
             assert_eq!(Some(expected), f);
         }
 
+        #[test]
+        fn arbitrary_raw_round_trip(ref flags in proptest::collection::vec(arb_documented_raw_flag(), 0..8)) {
+            // Advanced markers refer to entries by key, so keys must be unique
+            let mut flags = flags.clone();
+            flags.retain(|f| !f.key.is_empty() && !f.key.ends_with(ADVANCED_SUFFIX));
+            flags.sort_by(|a, b| a.key.cmp(&b.key));
+            flags.dedup_by(|a, b| a.key == b.key);
+            let text = raw_to_string(&flags);
+            let parsed = parse_raw(BufReader::new(text.as_bytes())).expect("Should be parseable!");
+            assert_eq!(flags, parsed);
+        }
+
         #[test]
         fn arbitrary_valid_raw_refinable(ref raw_flag in arb_raw_flag()) {
             let f:SimpleFlag = SimpleFlag::from(raw_flag);
@@ -472,18 +1099,40 @@ mod tests {
                     panic!("Should have been Boolish");
                 }
             } else {
-                if let SimpleFlag::Stringish(k, v) = f {
-                    assert_eq!(Key(raw_flag.key.clone()), k);
-                    assert_eq!(raw_flag.value, v);
-                } else {
-                    panic!("Should have been Stringish");
+                assert_eq!(&Key(raw_flag.key.clone()), f.key());
+                assert_eq!(raw_flag.value, f.value_string());
+                match (&raw_flag.cmake_type, &f) {
+                    (CMakeType::Path, SimpleFlag::Pathish(_, _)) => (),
+                    (CMakeType::FilePath, SimpleFlag::Pathish(_, _)) => (),
+                    (CMakeType::Path, _) | (CMakeType::FilePath, _) => {
+                        panic!("Should have been Pathish")
+                    }
+                    (_, SimpleFlag::Boolish(_, _)) => panic!("Should not have been Boolish"),
+                    _ => (),
                 }
             }
         }
 
+        #[test]
+        fn arbitrary_string_literal_round_trip(ref value in any::<String>()) {
+            let item = SimpleFlag::Stringish(Key("K".to_string()), value.clone())
+                .generate_rust_const_item();
+            let prefix = "pub const K:&'static str = ";
+            assert!(item.code.starts_with(prefix) && item.code.ends_with(';'));
+            let literal = &item.code[prefix.len()..item.code.len() - 1];
+            let parsed = syn::parse_str::<syn::LitStr>(literal).expect("Should be a string literal");
+            assert_eq!(value, &parsed.value());
+        }
+
         #[test]
         fn arbitrary_simple_flag_const_able(ref simple_flag in arb_simple_flag()) {
-            let _ = simple_flag.generate_rust_const_item().expect("Should be able to const-ify anything with a rust-compatible identifier");
+            let item = simple_flag.generate_rust_const_item();
+            assert_eq!(item.identifier, simple_flag.key().0);
+        }
+
+        #[test]
+        fn arbitrary_key_mangles_to_identifier(ref key in ".*") {
+            assert!(is_valid_rust_identifier(mangle_rust_identifier(key)));
         }
 
         #[test]
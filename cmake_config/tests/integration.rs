@@ -36,36 +36,50 @@ fn sanity_check_tiny_file_parsing() {
                 key: "FP_PROP".into(),
                 cmake_type: CMakeType::FilePath,
                 value: "/home/whoever/wherever".into(),
+                help: None,
+                advanced: false,
             },
             RawFlag {
                 key: "S_PROP_SOME".into(),
                 cmake_type: CMakeType::String,
                 value: "foo".into(),
+                help: None,
+                advanced: false,
             },
             RawFlag {
                 key: "S_PROP_NONE".into(),
                 cmake_type: CMakeType::String,
                 value: "".into(),
+                help: None,
+                advanced: false,
             },
             RawFlag {
                 key: "B_PROP_ON".into(),
                 cmake_type: CMakeType::Bool,
                 value: "ON".into(),
+                help: None,
+                advanced: false,
             },
             RawFlag {
                 key: "B_PROP_OFF".into(),
                 cmake_type: CMakeType::Bool,
                 value: "OFF".into(),
+                help: None,
+                advanced: false,
             },
             RawFlag {
                 key: "B_PROP_TRUE".into(),
                 cmake_type: CMakeType::Bool,
                 value: "TRUE".into(),
+                help: None,
+                advanced: false,
             },
             RawFlag {
                 key: "B_PROP_FALSE".into(),
                 cmake_type: CMakeType::Bool,
                 value: "FALSE".into(),
+                help: None,
+                advanced: false,
             },
         ],
         v
@@ -75,7 +89,7 @@ fn sanity_check_tiny_file_parsing() {
 
     assert_eq!(
         vec![
-            SimpleFlag::Stringish("FP_PROP".into(), "/home/whoever/wherever".into()),
+            SimpleFlag::Pathish("FP_PROP".into(), "/home/whoever/wherever".into()),
             SimpleFlag::Stringish("S_PROP_SOME".into(), "foo".into()),
             SimpleFlag::Stringish("S_PROP_NONE".into(), "".into()),
             SimpleFlag::Boolish("B_PROP_ON".into(), true),
@@ -1,23 +1,46 @@
 extern crate cargo_metadata;
 
-use cmake_config::{Key, SimpleFlag};
+use cmake_config::{initial_cache_to_string, parse_file_to_raw, Key, SimpleFlag};
 use command_ext::CommandExt;
-use fel4_config::{FlatTomlValue, SupportedTarget};
+use fel4_config::SupportedTarget;
 use std::borrow::Borrow;
-use std::collections::HashSet;
-use std::env::{self, current_dir};
-use std::fs::{self, canonicalize, File};
+use std::env::current_dir;
+use std::fs::{self, canonicalize};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use super::Error;
-use cmake_codegen::{cache_to_interesting_flags, truthy_boolean_flags_as_rust_identifiers};
-use config::{get_resolved_config, BuildCmd, Fel4BuildProfile, ResolvedConfig};
-use generator::Generator;
+use builder::Builder;
+use cmake_codegen::{
+    key_value_flags_as_rust_cfgs, properties_to_simple_flags, simple_flags_to_cache_entries,
+    simple_flags_to_rust_source, truthy_boolean_flags_as_rust_identifiers, CMakeCodegenError,
+    DEFAULT_CFG_PREFIX,
+};
+use config::{get_resolved_config, BuildCmd, Fel4BuildProfile, MessageFormat, ResolvedConfig};
+use fingerprint::{find_lockfile, hash_file, locked_package, write_if_changed, BuildFingerprint};
+use generator::{resolve_components, Generator};
+use machine_message::{emit, run_step_cmd, BuildMessage, BuildStep};
+use root_task_crate::RootTaskCrate;
+use rustflags::{encode_rustflags, merge_fel4_cfgs, rustflags_env_var, RustflagsSources};
+use settings::AllocatorKind;
+use uboot::{uboot_image_path, write_uboot_image, UBOOT_TARGETS_MESSAGE};
+use verify_cmd::ConsistencyReport;
+
+/// The name of the initial-cache script, kept in the artifact directory,
+/// that records the fel4.toml properties as CMake cache entries
+pub const CMAKE_INITIAL_CACHE_FILE_NAME: &str = "fel4-initial-cache.cmake";
+
+/// The name of the Rust source file, kept in the artifact directory, that
+/// holds the fel4.toml properties as the consts of the sel4_config module
+pub const SEL4_CONFIG_FILE_NAME: &str = "sel4_config.rs";
 
 pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
     let build_profile = Fel4BuildProfile::from(subcmd);
-    let config: ResolvedConfig = get_resolved_config(&subcmd.cargo_manifest_path, &build_profile)?;
+    let config: ResolvedConfig = get_resolved_config(
+        &subcmd.cargo_manifest_path,
+        subcmd.package.as_ref().map(String::as_str),
+        &build_profile,
+    )?;
 
     let artifact_path = &config
         .root_dir
@@ -25,69 +48,201 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
         .join(build_profile.artifact_subdir_path());
 
     let target_build_cache_path = config
-        .root_dir
-        .join("target")
+        .target_dir
         .join(config.fel4_config.target.full_name())
         .join(build_profile.as_fel4_config_build_profile().full_name());
 
     info!("\ntarget build cache: {:?}", target_build_cache_path,);
 
+    emit(
+        subcmd.message_format,
+        &BuildMessage::ConfigResolved {
+            package: &config.pkg_name,
+            target: config.fel4_config.target.full_name(),
+            platform: config.fel4_config.platform.full_name(),
+            profile: build_profile.full_name(),
+            artifact_path,
+        },
+    )?;
+
     let cross_layer_locations = CrossLayerLocations {
         fel4_artifact_path: config.root_dir.join(&artifact_path),
         fel4_manifest_path: config.root_dir.join("fel4.toml"),
         rust_target_path: config.root_dir.join(&config.fel4_config.target_specs_path),
+        sel4_config_path: config.root_dir.join(&artifact_path).join(SEL4_CONFIG_FILE_NAME),
     };
 
-    let fel4_flags: Vec<SimpleFlag> = config
-        .fel4_config
-        .properties
-        .iter()
-        .map(|(k, v): (&String, &FlatTomlValue)| {
-            let key = Key(k.to_string());
-            match v {
-                FlatTomlValue::Boolean(b) => SimpleFlag::Boolish(key, *b),
-                FlatTomlValue::String(s) => SimpleFlag::Stringish(key, s.to_string()),
-                FlatTomlValue::Integer(s) => SimpleFlag::Stringish(key, s.to_string()),
-                FlatTomlValue::Float(s) => SimpleFlag::Stringish(key, s.to_string()),
-                FlatTomlValue::Datetime(s) => SimpleFlag::Stringish(key, s.to_string()),
+    let fel4_flags: Vec<SimpleFlag> = properties_to_simple_flags(&config.fel4_config.properties);
+    // Record the properties as an initial-cache script, for reproducing the
+    // seL4 configuration with `cmake -C` outside of the build
+    fs::create_dir_all(&artifact_path)?;
+    let cmake_initial_cache_path = config
+        .root_dir
+        .join(&artifact_path)
+        .join(CMAKE_INITIAL_CACHE_FILE_NAME);
+    write_if_changed(
+        &cmake_initial_cache_path,
+        initial_cache_to_string(&simple_flags_to_cache_entries(&fel4_flags)).as_bytes(),
+    )?;
+    info!("CMake initial cache: '{}'", cmake_initial_cache_path.display());
+    // The user library reads the same consts as the root task's sel4_config
+    // module by including this file
+    write_if_changed(
+        &cross_layer_locations.sel4_config_path,
+        &simple_flags_to_rust_source(&fel4_flags)?,
+    )?;
+
+    if subcmd.tests && config.allocator.kind == AllocatorKind::None {
+        return Err(Error::ConfigError(
+            "The feL4 tests require an allocator, but fel4.toml selects none".to_string(),
+        ));
+    }
+    let cfg_settings = &config.settings.cfg;
+    if let Some(ref names) = cfg_settings.properties {
+        for name in names {
+            if !config.fel4_config.properties.contains_key(name) {
+                warn!("The cfg property '{}' is not set in fel4.toml for this build", name);
+            } else if fel4_flags.iter().any(|flag| match flag {
+                SimpleFlag::Boolish(Key(key), _) => key == name,
+                _ => false,
+            }) {
+                warn!(
+                    "The cfg property '{}' is a boolean, which is passed as a `feature` cfg \
+                     when true rather than as a key/value cfg",
+                    name
+                );
             }
-        })
-        .collect();
-    let rustflags_env_var = merge_feature_flags_with_rustflags_env_var(
+        }
+    }
+    let key_value_cfgs = key_value_flags_as_rust_cfgs(
+        &fel4_flags,
+        cfg_settings
+            .prefix
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_CFG_PREFIX),
+        cfg_settings.properties.as_ref().map(Vec::as_slice),
+    )?;
+    // Compose the flags from every source cargo would read them from, as
+    // setting them on the commands overrides the cargo configuration
+    let rustflags = merge_fel4_cfgs(
+        RustflagsSources::from_env()?.resolve(config.fel4_config.target.full_name())?,
         &truthy_boolean_flags_as_rust_identifiers(&fel4_flags)?,
+        &key_value_cfgs,
     );
 
     // Generate the source code entry point (root task) for the application
-    // that will wrap the end-user's code as executing within a sub-thread
-    let root_task_path = config.root_dir.join("src").join("bin");
-    fs::create_dir_all(&root_task_path).map_err(|e| {
-        Error::IO(format!(
-            "Difficulty creating directory, {:?} : {}",
-            &root_task_path, e
-        ))
-    })?;
-    let mut root_file = File::create(root_task_path.join("root-task.rs").as_path())
-        .map_err(|e| Error::IO(format!("Could not create root-task file. {}", e)))?;
+    // that will wrap the end-user's code as executing within a sub-thread.
+    // It lives in a helper crate under the target directory, which leaves
+    // the user's source tree alone.
+    let root_task_crate = RootTaskCrate::new(&config.target_dir, &config.pkg_name);
+    let stale_root_file_path = config.root_dir.join("src").join("bin").join("root-task.rs");
+    if stale_root_file_path.exists() {
+        warn!(
+            "'{}' is no longer used, the root task is now generated in '{}'",
+            stale_root_file_path.display(),
+            root_task_crate.dir.display()
+        );
+    }
+    let components = resolve_components(&config.settings.components, &config.root_task_thread)?;
+    let mut root_task_source = Vec::new();
     Generator::new(
-        &mut root_file,
+        &mut root_task_source,
         &config.pkg_module_name,
         &config.arch,
         &fel4_flags,
+        &components,
+        &config.root_task_thread,
+        &config.allocator,
     ).generate()?;
+    let root_task_fresh = !root_task_crate.write(
+        &config.pkg_name,
+        &config.manifest_path,
+        config.allocator.dependency_feature(),
+        &root_task_source,
+    )?;
+    if config.allocator.kind == AllocatorKind::WeeAlloc {
+        if let Some(previous) =
+            root_task_crate.record_wee_alloc_heap_size(config.allocator.heap_size)?
+        {
+            warn!(
+                "The heap size changed from {} to {} bytes, which wee_alloc only picks up when \
+                 it is rebuilt, run `cargo fel4 clean` for the change to take effect",
+                previous, config.allocator.heap_size
+            );
+        }
+    }
+    if root_task_fresh {
+        info!(
+            "The generated root task '{}' is up to date",
+            root_task_crate.source_path.display()
+        );
+    }
+    emit(
+        subcmd.message_format,
+        &BuildMessage::RootTaskGenerated {
+            path: &root_task_crate.source_path,
+            fresh: root_task_fresh,
+        },
+    )?;
 
-    match is_current_dir_root_dir(&config.root_dir) {
-        Ok(are_same) if !are_same => return Err(Error::ExitStatusError("The build command does not work with a cargo manifest directory that differs from the current working directory due to limitations of Xargo".to_string())),
-        Err(e) => return Err(Error::IO(format!("Error with current dir comparison: {}", e))),
-        _ => ()
+    let builder = Builder::resolve(
+        subcmd
+            .backend
+            .or(config.settings.build.backend)
+            .unwrap_or_default(),
+        &config.root_dir,
+        &config.fel4_config.target,
+    )?;
+    if builder.requires_root_dir_as_current_dir() {
+        match is_current_dir_root_dir(&config.root_dir) {
+            Ok(are_same) if !are_same => return Err(Error::ExitStatusError("The build command does not work with a cargo manifest directory that differs from the current working directory due to limitations of Xargo, consider the build-std backend".to_string())),
+            Err(e) => return Err(Error::IO(format!("Error with current dir comparison: {}", e))),
+            _ => ()
+        }
     }
+    let (rustflags_var, rustflags_value) = rustflags_env_var(&builder, &rustflags)?;
     // Build the generated root task binary
-    construct_root_task_build_command(subcmd, &config, &cross_layer_locations)
-        .env("RUSTFLAGS", &rustflags_env_var)
-        .run_cmd()?;
+    run_step_cmd(
+        construct_root_task_build_command(
+            subcmd,
+            &config,
+            &builder,
+            &root_task_crate,
+            &cross_layer_locations,
+        )
+            .add_rustflags_env_var(rustflags_var, &rustflags_value),
+        BuildStep::RootTask,
+        subcmd.message_format,
+    )?;
 
     let sysimg_path = artifact_path.join("feL4img");
     let kernel_path = artifact_path.join("kernel");
-    fs::create_dir_all(&artifact_path)?;
+
+    // libsel4-sys is rebuilt through the user's manifest, and so resolved
+    // through the user's (or their workspace's) lockfile
+    let locked_libsel4_sys = || {
+        find_lockfile(&config.root_dir)
+            .map(|path| locked_package(&path, "libsel4-sys"))
+            .unwrap_or_default()
+    };
+    // The inputs of the elfloader image, which is rebuilt only when they
+    // differ from those of the previous build
+    let mut fingerprint = BuildFingerprint {
+        properties: fel4_flags
+            .iter()
+            .map(|flag| (flag.key().0.clone(), flag.value_string()))
+            .collect(),
+        target_spec: hash_file(
+            &cross_layer_locations
+                .rust_target_path
+                .join(format!("{}.json", config.fel4_config.target.full_name())),
+        ),
+        rustflags: encode_rustflags(&rustflags),
+        builder: format!("{:?}", builder),
+        libsel4_sys: locked_libsel4_sys(),
+        root_task: hash_file(&target_build_cache_path.join("root-task")),
+    };
 
     // For ARM targets, we currently take advantage of the
     // seL4 elfloader-tool to bootstrap the system and kick
@@ -96,28 +251,33 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
     // with an extra environment variable which gives
     // elfloader-tool a path to the root-task binary
     match config.fel4_config.target {
-        SupportedTarget::Armv7Sel4Fel4 => {
-            construct_libsel4_build_command(subcmd, &config, &cross_layer_locations)
-                .env(
-                    "FEL4_ROOT_TASK_IMAGE_PATH",
-                    target_build_cache_path.join("root-task"),
-                )
-                .env("RUSTFLAGS", &rustflags_env_var)
-                .run_cmd()?;
-
-            // seL4 CMake rules will just output everything to `kernel`
-            // we copy it so it's consistent with our image name but
-            // won't trigger a rebuild (as it would if we were to move it)
-            fs::copy(&kernel_path, &sysimg_path)?;
-        }
-        SupportedTarget::Aarch64Sel4Fel4 => {
-            construct_libsel4_build_command(subcmd, &config, &cross_layer_locations)
-                .env(
-                    "FEL4_ROOT_TASK_IMAGE_PATH",
-                    target_build_cache_path.join("root-task"),
-                )
-                .env("RUSTFLAGS", &rustflags_env_var)
-                .run_cmd()?;
+        SupportedTarget::Armv7Sel4Fel4 | SupportedTarget::Aarch64Sel4Fel4 => {
+            if fingerprint.is_fresh(&artifact_path) {
+                info!("The elfloader image '{}' is up to date", kernel_path.display());
+                emit(
+                    subcmd.message_format,
+                    &BuildMessage::InvocationFresh {
+                        step: BuildStep::Libsel4SysRebuild,
+                    },
+                )?;
+            } else {
+                run_step_cmd(
+                    construct_libsel4_build_command(
+                        subcmd,
+                        &config,
+                        &builder,
+                        &cross_layer_locations,
+                    ).env(
+                        "FEL4_ROOT_TASK_IMAGE_PATH",
+                        target_build_cache_path.join("root-task"),
+                    )
+                    .add_rustflags_env_var(rustflags_var, &rustflags_value),
+                    BuildStep::Libsel4SysRebuild,
+                    subcmd.message_format,
+                )?;
+                // The rebuild creates the lockfile when there is none yet
+                fingerprint.libsel4_sys = locked_libsel4_sys();
+            }
 
             // seL4 CMake rules will just output everything to `kernel`
             // we copy it so it's consistent with our image name but
@@ -130,36 +290,27 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
     }
 
     {
-        // Extract the resolved CMake config details and filter down to ones that might
-        // be useful for cross-reference with the fel4-config derived values
-        let interesting_raw_flags_from_cmake = cache_to_interesting_flags(
-            config.root_dir.join(&artifact_path).join("CMakeCache.txt"),
-        )?;
-        let simple_cmake_flags: HashSet<SimpleFlag> = interesting_raw_flags_from_cmake
-            .iter()
-            .map(SimpleFlag::from)
-            .collect();
-        let simple_fel4_flags: HashSet<SimpleFlag> = fel4_flags.into_iter().collect();
-        if !&simple_fel4_flags.is_subset(&simple_cmake_flags) {
-            for s in &simple_fel4_flags {
-                if simple_cmake_flags.contains(s) {
-                    continue;
-                }
-                println!("Found a fel4 flag {:?} that was not in the cmake flags", s);
-                let key = match s {
-                    SimpleFlag::Boolish(Key(k), _) | SimpleFlag::Stringish(Key(k), _) => k.clone(),
-                };
-                for raw_flag in &interesting_raw_flags_from_cmake {
-                    if raw_flag.key == key {
-                        println!(
-                            "    But there was a flag with the same key in CMakeCache.txt: {:?}",
-                            raw_flag
-                        );
-                    }
-                }
+        // Cross-reference the fel4.toml properties with the configuration
+        // seL4's CMake build resolved
+        let cmake_flags =
+            parse_file_to_raw(config.root_dir.join(&artifact_path).join("CMakeCache.txt"))
+                .map_err(CMakeCodegenError::from)?;
+        let report = ConsistencyReport::new(&fel4_flags, &cmake_flags);
+        let consistent = report.is_consistent();
+        if !consistent && subcmd.message_format == MessageFormat::Human {
+            print!("{}", report.render_table());
+            for hint in report.hints() {
+                println!("hint: {}", hint);
             }
-            return Err(Error::ConfigError("Unexpected mismatch between the fel4.toml config values and seL4's CMakeCache.txt config values".to_string()));
         }
+        emit(
+            subcmd.message_format,
+            &BuildMessage::CmakeCacheCheck {
+                consistent,
+                mismatches: report.mismatches(),
+            },
+        )?;
+        report.into_result()?;
     }
 
     if !sysimg_path.exists() {
@@ -176,11 +327,36 @@ pub fn handle_build_cmd(subcmd: &BuildCmd) -> Result<(), Error> {
         )));
     }
 
+    // U-Boot boots the elfloader image of the ARM targets
+    if let Some(ref uboot) = config.settings.uboot {
+        match config.fel4_config.target {
+            SupportedTarget::X8664Sel4Fel4 => {
+                warn!("{}, skipping the [fel4.uboot] table", UBOOT_TARGETS_MESSAGE)
+            }
+            ref target => {
+                let uboot_path = uboot_image_path(&sysimg_path, uboot);
+                write_uboot_image(&sysimg_path, &uboot_path, target, uboot)?;
+                info!("U-Boot image: '{}'", uboot_path.display());
+            }
+        }
+    }
+
+    fingerprint.store(&artifact_path)?;
+
     info!("Output artifact path '{}'", artifact_path.display());
 
     info!("kernel: '{}'", kernel_path.display());
     info!("feL4img: '{}'", sysimg_path.display());
 
+    emit(
+        subcmd.message_format,
+        &BuildMessage::BuildFinished {
+            artifact_path,
+            kernel: &kernel_path,
+            fel4img: &sysimg_path,
+        },
+    )?;
+
     Ok(())
 }
 
@@ -192,17 +368,17 @@ fn is_current_dir_root_dir<P: AsRef<Path>>(root_dir: P) -> Result<bool, ::std::i
 fn construct_libsel4_build_command<P>(
     subcmd: &BuildCmd,
     config: &ResolvedConfig,
+    builder: &Builder,
     locations: &CrossLayerLocations<P>,
 ) -> Command
 where
     P: Borrow<Path>,
 {
-    let mut libsel4_build = Command::new("xargo");
+    let mut libsel4_build = builder.rustc_command();
 
     libsel4_build
-        .arg("rustc")
         .arg("--manifest-path")
-        .arg(&subcmd.cargo_manifest_path)
+        .arg(&config.manifest_path)
         .arg_if(|| subcmd.release, "--release")
         .add_loudness_args(&subcmd.loudness)
         .handle_arm_edge_case(&config.fel4_config.target)
@@ -216,7 +392,8 @@ where
 }
 
 /// Create a Command instance that, when run,
-/// will build the root task binary
+/// will build the root task binary of the generated
+/// root task crate into the package's target directory
 ///
 /// Note: Does NOT include application of Rust/Cargo feature flags
 ///
@@ -226,26 +403,40 @@ where
 fn construct_root_task_build_command<P>(
     subcmd: &BuildCmd,
     config: &ResolvedConfig,
+    builder: &Builder,
+    root_task_crate: &RootTaskCrate,
     cross_layer_locations: &CrossLayerLocations<P>,
 ) -> Command
 where
     P: Borrow<Path>,
 {
-    let mut root_task_build = Command::new("xargo");
+    let mut features: Vec<&str> = Vec::new();
+    if subcmd.tests {
+        features.push("test");
+        features.push("alloc");
+    }
+    let mut root_task_build = builder.rustc_command();
     root_task_build
-        .arg("rustc")
         .arg("--bin")
         .arg("root-task")
         .arg("--manifest-path")
-        .arg(&subcmd.cargo_manifest_path)
+        .arg(&root_task_crate.manifest_path)
+        .arg("--target-dir")
+        .arg(&config.target_dir)
         .arg_if(|| subcmd.release, "--release")
         .add_loudness_args(&subcmd.loudness)
         .handle_arm_edge_case(&config.fel4_config.target)
-        .arg_if(|| subcmd.tests, "--features")
-        .arg_if(|| subcmd.tests, "test alloc")
+        .arg_if(|| !features.is_empty(), "--features")
+        .arg_if(|| !features.is_empty(), features.join(" "))
         .arg("--target")
         .arg(&config.fel4_config.target.full_name())
         .add_locations_as_env_vars(cross_layer_locations);
+    if config.allocator.kind == AllocatorKind::WeeAlloc {
+        root_task_build.env(
+            "WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES",
+            config.allocator.heap_size.to_string(),
+        );
+    }
     root_task_build
 }
 
@@ -257,6 +448,7 @@ pub struct CrossLayerLocations<P: Borrow<Path>> {
     fel4_manifest_path: P,
     fel4_artifact_path: P,
     rust_target_path: P,
+    sel4_config_path: P,
 }
 
 /// Extension methods for `Command` instances to supply common parameters or
@@ -272,6 +464,10 @@ where
         cross_layer_locations: &'l CrossLayerLocations<P>,
     ) -> &'c mut Self;
 
+    /// Set the one rustflags variable the builder reads, unsetting the
+    /// other so that no inherited value takes precedence
+    fn add_rustflags_env_var<'c>(&'c mut self, name: &str, value: &str) -> &'c mut Self;
+
     /// Handle a possible edge case in cross-compiling for arm
     fn handle_arm_edge_case<'c, 'f>(&'c mut self, config: &'f SupportedTarget) -> &'c mut Self;
 }
@@ -283,10 +479,17 @@ impl BuildCommandExt for Command {
     ) -> &'c mut Self {
         self.env("FEL4_MANIFEST_PATH", locations.fel4_manifest_path.borrow())
             .env("FEL4_ARTIFACT_PATH", locations.fel4_artifact_path.borrow())
-            .env("RUST_TARGET_PATH", locations.rust_target_path.borrow());
+            .env("RUST_TARGET_PATH", locations.rust_target_path.borrow())
+            .env("FEL4_SEL4_CONFIG_PATH", locations.sel4_config_path.borrow());
         self
     }
 
+    fn add_rustflags_env_var<'c>(&'c mut self, name: &str, value: &str) -> &'c mut Self {
+        self.env_remove("CARGO_ENCODED_RUSTFLAGS")
+            .env_remove("RUSTFLAGS")
+            .env(name, value)
+    }
+
     fn handle_arm_edge_case<'c, 'f>(&'c mut self, target: &'f SupportedTarget) -> &mut Self {
         // There seems to be an issue with `compiler_builtins` imposing
         // a default compiler used by the `c` feature/dependency; where
@@ -314,19 +517,3 @@ impl BuildCommandExt for Command {
         }
     }
 }
-
-fn merge_feature_flags_with_rustflags_env_var(feature_flags: &[String]) -> String {
-    let mut output: String = match env::var("RUSTFLAGS") {
-        Ok(s) => s,
-        Err(env::VarError::NotUnicode(_)) => String::new(),
-        Err(env::VarError::NotPresent) => String::new(),
-    };
-    if !output.is_empty() {
-        output.push(' ');
-    }
-    for feature in feature_flags {
-        output.push_str("--cfg ");
-        output.push_str(&format!("feature=\"{}\" ", feature));
-    }
-    output
-}
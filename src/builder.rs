@@ -0,0 +1,212 @@
+use fel4_config::SupportedTarget;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use toml;
+
+use super::Error;
+use config::BuilderBackend;
+
+/// A builder backend that has been resolved against the project, ready to
+/// produce `rustc` invocations
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Builder {
+    Xargo,
+    BuildStd(BuildStdCrates),
+}
+
+/// The sysroot crates (and their features) to be built from source by
+/// `cargo -Z build-std`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BuildStdCrates {
+    pub crates: Vec<String>,
+    pub features: Vec<String>,
+}
+
+impl Builder {
+    pub fn resolve<P: AsRef<Path>>(
+        backend: BuilderBackend,
+        root_dir: P,
+        target: &SupportedTarget,
+    ) -> Result<Builder, Error> {
+        match backend {
+            BuilderBackend::Xargo => Ok(Builder::Xargo),
+            BuilderBackend::BuildStd => {
+                let xargo_toml_path = root_dir.as_ref().join("Xargo.toml");
+                if !xargo_toml_path.exists() {
+                    return Ok(Builder::BuildStd(BuildStdCrates::default()));
+                }
+                let mut contents = String::new();
+                File::open(&xargo_toml_path)?.read_to_string(&mut contents)?;
+                Ok(Builder::BuildStd(parse_xargo_toml(
+                    &contents,
+                    target.full_name(),
+                )?))
+            }
+        }
+    }
+
+    /// Xargo can only find the project's Xargo.toml when run from the
+    /// directory containing it
+    pub fn requires_root_dir_as_current_dir(&self) -> bool {
+        match *self {
+            Builder::Xargo => true,
+            Builder::BuildStd(_) => false,
+        }
+    }
+
+    /// Create a Command instance for a `rustc` invocation through this
+    /// builder, to which the usual cargo arguments can be appended
+    pub fn rustc_command(&self) -> Command {
+        match *self {
+            Builder::Xargo => {
+                let mut cmd = Command::new("xargo");
+                cmd.arg("rustc");
+                cmd
+            }
+            Builder::BuildStd(ref crates) => {
+                let mut cmd = Command::new("cargo");
+                cmd.arg("rustc").args(crates.as_args());
+                cmd
+            }
+        }
+    }
+}
+
+impl Default for BuildStdCrates {
+    fn default() -> Self {
+        BuildStdCrates {
+            crates: vec!["core".to_string()],
+            features: Vec::new(),
+        }
+    }
+}
+
+impl BuildStdCrates {
+    pub fn as_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-Z".to_string(),
+            format!("build-std={}", self.crates.join(",")),
+        ];
+        if !self.features.is_empty() {
+            args.push("-Z".to_string());
+            args.push(format!("build-std-features={}", self.features.join(",")));
+        }
+        args
+    }
+}
+
+/// Translate the sysroot dependencies of an Xargo.toml into the equivalent
+/// `build-std` crates, considering both the target-agnostic `[dependencies]`
+/// table and the `[target.<triple>.dependencies]` table of the given target.
+///
+/// `core` is always built, as Xargo does, and with it `compiler_builtins`,
+/// whose features are selected by their build-std names.
+pub fn parse_xargo_toml(contents: &str, target_name: &str) -> Result<BuildStdCrates, Error> {
+    let xargo_toml: toml::Value = contents
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("Invalid Xargo.toml: {}", e)))?;
+    let mut build_std = BuildStdCrates::default();
+    let tables = [
+        xargo_toml.get("dependencies"),
+        xargo_toml
+            .get("target")
+            .and_then(|t| t.get(target_name))
+            .and_then(|t| t.get("dependencies")),
+    ];
+    for deps in tables.iter().filter_map(|t| t.and_then(|t| t.as_table())) {
+        for (name, dep) in deps {
+            if name != "compiler_builtins" && !build_std.crates.contains(name) {
+                build_std.crates.push(name.clone());
+            }
+            let features = dep
+                .get("features")
+                .and_then(|f| f.as_array())
+                .map(|f| f.as_slice())
+                .unwrap_or(&[]);
+            for feature in features.iter().filter_map(|f| f.as_str()) {
+                let feature = build_std_feature(name, feature)?;
+                if !build_std.features.iter().any(|f| f == feature) {
+                    build_std.features.push(feature.to_string());
+                }
+            }
+        }
+    }
+    Ok(build_std)
+}
+
+/// The `build-std-features` name of a feature of an Xargo.toml sysroot
+/// dependency. Xargo enables the features of the sysroot crates directly,
+/// while build-std only offers a fixed set through the `std` crate.
+fn build_std_feature(dependency: &str, feature: &str) -> Result<&'static str, Error> {
+    match feature {
+        "mem" | "compiler-builtins-mem" => Ok("compiler-builtins-mem"),
+        "c" | "compiler-builtins-c" => Ok("compiler-builtins-c"),
+        "no-asm" | "compiler-builtins-no-asm" => Ok("compiler-builtins-no-asm"),
+        "panic_immediate_abort" => Ok("panic_immediate_abort"),
+        "panic-unwind" => Ok("panic-unwind"),
+        "backtrace" => Ok("backtrace"),
+        _ => Err(Error::ConfigError(format!(
+            "The '{}' feature of the '{}' dependency in Xargo.toml has no build-std \
+             equivalent",
+            feature, dependency
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_xargo_toml_to_build_std() {
+        let xargo_toml = include_str!("../templates/Xargo.toml");
+        let expected = BuildStdCrates {
+            crates: vec!["core".to_string(), "alloc".to_string()],
+            features: Vec::new(),
+        };
+        assert_eq!(
+            expected,
+            parse_xargo_toml(xargo_toml, "x86_64-sel4-fel4").unwrap()
+        );
+        assert_eq!(
+            vec!["-Z".to_string(), "build-std=core,alloc".to_string()],
+            expected.as_args()
+        );
+    }
+
+    #[test]
+    fn xargo_toml_other_target_and_features() {
+        let xargo_toml = r#"
+[dependencies.core]
+[dependencies.compiler_builtins]
+features = ["mem"]
+[target.armv7-sel4-fel4.dependencies]
+alloc = {}
+"#;
+        let expected = BuildStdCrates {
+            crates: vec!["core".to_string()],
+            features: vec!["compiler-builtins-mem".to_string()],
+        };
+        assert_eq!(
+            expected,
+            parse_xargo_toml(xargo_toml, "x86_64-sel4-fel4").unwrap()
+        );
+        assert_eq!(
+            vec![
+                "-Z".to_string(),
+                "build-std=core".to_string(),
+                "-Z".to_string(),
+                "build-std-features=compiler-builtins-mem".to_string(),
+            ],
+            expected.as_args()
+        );
+    }
+
+    #[test]
+    fn xargo_toml_unknown_feature() {
+        let xargo_toml = "[dependencies.core]\nfeatures = [\"unknown\"]\n";
+        assert!(parse_xargo_toml(xargo_toml, "x86_64-sel4-fel4").is_err());
+    }
+}
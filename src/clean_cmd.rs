@@ -1,19 +1,28 @@
 use std::fs;
-use std::path::Path;
 use std::process::Command;
 
 use super::Error;
 use command_ext::CommandExt;
-use config::{get_fel4_manifest_with_root_dir, CleanCmd, ManifestWithRootDir};
+use config::{get_fel4_manifest, get_fel4_package, CleanCmd, Fel4Package};
+use root_task_crate::RootTaskCrate;
 
 pub fn handle_clean_cmd(clean_cmd: &CleanCmd) -> Result<(), Error> {
-    let ManifestWithRootDir {
-        fel4_manifest,
-        root_dir,
-    } = get_fel4_manifest_with_root_dir(&clean_cmd.cargo_manifest_path)?;
-    let artifact_path = Path::new(&root_dir).join(fel4_manifest.artifact_path);
+    let package = get_fel4_package(
+        &clean_cmd.cargo_manifest_path,
+        clean_cmd.package.as_ref().map(String::as_str),
+    )?;
+    let fel4_manifest = get_fel4_manifest(package.root_dir.join("Cargo.toml"))?;
+    let artifact_path = package.root_dir.join(fel4_manifest.artifact_path);
 
-    clean_cargo_build_cache(clean_cmd)?;
+    clean_cargo_build_cache(clean_cmd, &package)?;
+
+    // `cargo clean -p` leaves the rest of the target directory alone,
+    // including the helper crate of the selected package
+    let root_task_crate = RootTaskCrate::new(&package.target_dir, &package.name);
+    if root_task_crate.dir.exists() {
+        info!("Removing {}", root_task_crate.dir.display());
+        fs::remove_dir_all(&root_task_crate.dir)?;
+    }
 
     if artifact_path.exists() {
         info!("Removing {}", artifact_path.display());
@@ -23,10 +32,14 @@ pub fn handle_clean_cmd(clean_cmd: &CleanCmd) -> Result<(), Error> {
     Ok(())
 }
 
-fn clean_cargo_build_cache(clean_cmd: &CleanCmd) -> Result<(), Error> {
+fn clean_cargo_build_cache(clean_cmd: &CleanCmd, package: &Fel4Package) -> Result<(), Error> {
     let mut cmd = Command::new("cargo");
     cmd.add_loudness_args(&clean_cmd.loudness)
         .arg("clean")
+        .arg("--manifest-path")
+        .arg(&clean_cmd.cargo_manifest_path)
+        .arg_if(|| clean_cmd.package.is_some(), "--package")
+        .arg_if(|| clean_cmd.package.is_some(), &package.name)
         .run_cmd()?;
 
     Ok(())
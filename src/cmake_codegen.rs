@@ -1,17 +1,20 @@
 use super::Error;
 use cmake_config::*;
-use std::collections::HashSet;
+use fel4_config::FlatTomlValue;
+use std::collections::BTreeMap;
 use std::io::Write;
-use std::path::Path;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum CMakeCodegenError {
     ParseError(ParseError),
     GenerationError(RustCodeGenerationError),
-    DuplicateIdentifiers(String),
     WriteIoError,
+    InvalidCfgPrefix(String),
 }
 
+/// The prefix of the key/value cfg names when fel4.toml sets none
+pub const DEFAULT_CFG_PREFIX: &str = "sel4_";
+
 impl From<ParseError> for CMakeCodegenError {
     fn from(p: ParseError) -> Self {
         CMakeCodegenError::ParseError(p)
@@ -24,6 +27,8 @@ impl From<RustCodeGenerationError> for CMakeCodegenError {
     }
 }
 
+/// The flags that might correspond to fel4.toml properties: those of the
+/// `STRING` and `BOOL` types, besides CMake's own `CMAKE_` ones
 pub fn filter_to_interesting_flags<I>(i: I) -> Vec<RawFlag>
 where
     I: IntoIterator<Item = RawFlag>,
@@ -37,11 +42,52 @@ where
     v
 }
 
-pub fn cache_to_interesting_flags<P: AsRef<Path>>(
-    cmake_cache_path: P,
-) -> Result<Vec<RawFlag>, CMakeCodegenError> {
-    let all_flags = parse_file_to_raw(cmake_cache_path)?;
-    Ok(filter_to_interesting_flags(all_flags))
+/// The fel4.toml properties as flags, interpreted the way the
+/// CMakeCache.txt values are so that the two can be compared
+pub fn properties_to_simple_flags(properties: &BTreeMap<String, FlatTomlValue>) -> Vec<SimpleFlag> {
+    properties
+        .iter()
+        .map(|(k, v)| {
+            let key = Key(k.to_string());
+            match v {
+                FlatTomlValue::Boolean(b) => SimpleFlag::Boolish(key, *b),
+                FlatTomlValue::Integer(i) if *i >= 0 => SimpleFlag::Integerish(key, *i as u64),
+                FlatTomlValue::String(s) => SimpleFlag::from_string_value(key, s),
+                FlatTomlValue::Integer(s) => SimpleFlag::Stringish(key, s.to_string()),
+                FlatTomlValue::Float(s) => SimpleFlag::Stringish(key, s.to_string()),
+                FlatTomlValue::Datetime(s) => SimpleFlag::Stringish(key, s.to_string()),
+            }
+        })
+        .collect()
+}
+
+/// The CMake cache entries matching fel4.toml properties, for recording
+/// them as an initial-cache script
+pub fn simple_flags_to_cache_entries<'a, I>(flags: I) -> Vec<RawFlag>
+where
+    I: IntoIterator<Item = &'a SimpleFlag>,
+{
+    flags
+        .into_iter()
+        .map(|flag| {
+            let (cmake_type, value) = match flag {
+                SimpleFlag::Boolish(_, v) => {
+                    (CMakeType::Bool, if *v { "ON" } else { "OFF" }.to_string())
+                }
+                SimpleFlag::Pathish(_, v) => (CMakeType::Path, v.clone()),
+                SimpleFlag::Stringish(_, _)
+                | SimpleFlag::Integerish(_, _)
+                | SimpleFlag::Listish(_, _) => (CMakeType::String, flag.value_string()),
+            };
+            RawFlag {
+                key: flag.key().0.clone(),
+                cmake_type,
+                value,
+                help: Some("Set by cargo-fel4 from fel4.toml".to_string()),
+                advanced: false,
+            }
+        })
+        .collect()
 }
 
 pub fn simple_flags_to_rust_writer<'a, I, W: Write>(
@@ -52,45 +98,94 @@ pub fn simple_flags_to_rust_writer<'a, I, W: Write>(
 where
     I: IntoIterator<Item = &'a SimpleFlag>,
 {
-    let mut identifiers: HashSet<String> = HashSet::new();
+    let flags: Vec<&SimpleFlag> = flags.into_iter().collect();
+    check_identifier_collisions(flags.iter().map(|f| f.key()))?;
     for flag in flags {
-        let RustConstItem { code, identifier } = flag.generate_rust_const_item()?;
-        if identifiers.contains(&identifier) {
-            return Err(CMakeCodegenError::DuplicateIdentifiers(identifier));
-        } else {
-            identifiers.insert(identifier);
-        }
+        let RustConstItem { code, .. } = flag.generate_rust_const_item();
         writeln!(writer, "{:indent$}{}", "", code, indent = indent_spaces)
             .map_err(|_| CMakeCodegenError::WriteIoError)?;
     }
     Ok(())
 }
 
+/// The consts of the flags as a standalone Rust source file, for the user
+/// library to `include!` into a module of its own
+pub fn simple_flags_to_rust_source<'a, I>(flags: I) -> Result<Vec<u8>, CMakeCodegenError>
+where
+    I: IntoIterator<Item = &'a SimpleFlag>,
+{
+    let mut source: Vec<u8> = b"// NOTE: this file is generated by fel4 from fel4.toml
+// NOTE: Don't edit it here; your changes will be lost at the next build!
+"
+        .to_vec();
+    simple_flags_to_rust_writer(flags, &mut source, 0)?;
+    Ok(source)
+}
+
+/// The identifiers of the truthy boolean flags, as used by the
+/// `--cfg feature="..."` flags of the build
 pub fn truthy_boolean_flags_as_rust_identifiers<'a, I>(
     flags: I,
 ) -> Result<Vec<String>, CMakeCodegenError>
 where
     I: IntoIterator<Item = &'a SimpleFlag>,
 {
-    let mut out = Vec::new();
-    for active_cmake_bool_prop in flags.into_iter().filter_map(|f| match f {
-        SimpleFlag::Stringish(_, _) => None,
-        SimpleFlag::Boolish(Key(_), false) => None,
-        SimpleFlag::Boolish(Key(k), true) => Some(k),
-    }) {
-        if !is_valid_rust_identifier(active_cmake_bool_prop) {
-            return Err(CMakeCodegenError::GenerationError(
-                RustCodeGenerationError::InvalidIdentifier(active_cmake_bool_prop.to_string()),
-            ));
-        }
-        out.push(active_cmake_bool_prop.to_string())
-    }
+    let flags: Vec<&SimpleFlag> = flags.into_iter().collect();
+    // The same identifiers are used by the sel4_config consts, so collisions
+    // among any of the flags are reported
+    check_identifier_collisions(flags.iter().map(|f| f.key()))?;
+    let mut out: Vec<String> = flags
+        .into_iter()
+        .filter_map(|f| match f {
+            SimpleFlag::Boolish(Key(k), true) => Some(mangle_rust_identifier(k)),
+            _ => None,
+        })
+        .collect();
 
     // sort the result so that we get a deterministic order
     out.sort();
 
     Ok(out)
 }
+
+/// The names and values of the key/value cfgs of the non-boolean flags, e.g.
+/// `("sel4_KernelArch", "x86_64")` for use as `--cfg sel4_KernelArch="x86_64"`.
+/// Without an allowlist, the string-valued flags are selected.
+pub fn key_value_flags_as_rust_cfgs<'a, I>(
+    flags: I,
+    prefix: &str,
+    allowlist: Option<&[String]>,
+) -> Result<Vec<(String, String)>, CMakeCodegenError>
+where
+    I: IntoIterator<Item = &'a SimpleFlag>,
+{
+    // The prefix only has to start an identifier, so e.g. `_` is accepted
+    if !is_valid_rust_identifier(format!("{}X", prefix)) {
+        return Err(CMakeCodegenError::InvalidCfgPrefix(prefix.to_string()));
+    }
+    let flags: Vec<&SimpleFlag> = flags.into_iter().collect();
+    check_identifier_collisions(flags.iter().map(|f| f.key()))?;
+    let mut out: Vec<(String, String)> = flags
+        .into_iter()
+        .filter(|f| match allowlist {
+            Some(names) => names.contains(&f.key().0),
+            None => match f {
+                SimpleFlag::Stringish(_, _) => true,
+                _ => false,
+            },
+        })
+        .filter_map(|f| match f {
+            SimpleFlag::Boolish(_, _) => None,
+            _ => Some((
+                format!("{}{}", prefix, mangle_rust_identifier(&f.key().0)),
+                f.value_string(),
+            )),
+        })
+        .collect();
+    out.sort();
+    Ok(out)
+}
+
 impl From<CMakeCodegenError> for Error {
     fn from(c: CMakeCodegenError) -> Self {
         match c {
@@ -98,32 +193,34 @@ impl From<CMakeCodegenError> for Error {
                 ParseError::IoFailure => {
                     Error::ExitStatusError("Failed to read CMakeCache.txt file".into())
                 }
-                ParseError::InvalidTypeHint => {
-                    Error::ExitStatusError("Invalid type hint in CMakeCache.txt file".into())
-                }
-                ParseError::PropertyMissingKeyTypeValueTriple => Error::ExitStatusError(
-                    "Invalid property definition in CMakeCache.txt file".into(),
-                ),
-            },
-            CMakeCodegenError::GenerationError(r) => match r {
-                RustCodeGenerationError::InvalidIdentifier(s) => Error::ExitStatusError(format!(
-                    "Invalid identifier interpreted from CMakeCache.txt: {}",
-                    s
+                ParseError::InvalidTypeHint(line) => Error::ExitStatusError(format!(
+                    "Invalid type hint on line {} of CMakeCache.txt file",
+                    line
                 )),
-                RustCodeGenerationError::InvalidStringLiteral(s) => {
+                ParseError::PropertyMissingKeyTypeValueTriple(line) => {
                     Error::ExitStatusError(format!(
-                        "Invalid Rust string literal generated from a value in CMakeCache.txt: {}",
-                        s
+                        "Invalid property definition on line {} of CMakeCache.txt file",
+                        line
                     ))
                 }
             },
-            CMakeCodegenError::DuplicateIdentifiers(i) => Error::ExitStatusError(format!(
-                "Duplicate identifiers generated in rust config from CMakeCache.txt: {}",
-                i
-            )),
+            CMakeCodegenError::GenerationError(r) => match r {
+                RustCodeGenerationError::IdentifierCollision {
+                    identifier,
+                    first_key,
+                    second_key,
+                } => Error::ExitStatusError(format!(
+                    "The config keys '{}' and '{}' both map to the Rust identifier '{}'",
+                    first_key, second_key, identifier
+                )),
+            },
             CMakeCodegenError::WriteIoError => {
                 Error::ExitStatusError("Failure to write out generated rust config.".into())
             }
+            CMakeCodegenError::InvalidCfgPrefix(prefix) => Error::ConfigError(format!(
+                "The cfg prefix '{}' in fel4.toml is not the start of a Rust identifier",
+                prefix
+            )),
         }
     }
 }
@@ -131,8 +228,6 @@ impl From<CMakeCodegenError> for Error {
 #[cfg(test)]
 mod tests {
     // TODO - test invalid cmake flag parsing case
-    // TODO - test duplicate identifiers case
-    // TODO - test invalid identifiers case
     // TODO - test happy path
     // TODO - test CMAKE_ filtration
     // TODO - test uninteresting CMakeType filtration
@@ -153,4 +248,90 @@ mod tests {
             str::from_utf8(&b).unwrap()
         );
     }
+
+    #[test]
+    fn mangled_feature_flags() {
+        let flags = vec![
+            SimpleFlag::Boolish(Key("Kernel-Printing".to_string()), true),
+            SimpleFlag::Boolish(Key("KernelDebugBuild".to_string()), false),
+            SimpleFlag::Boolish(Key("type".to_string()), true),
+        ];
+        assert_eq!(
+            Ok(vec!["Kernel_Printing".to_string(), "type_".to_string()]),
+            truthy_boolean_flags_as_rust_identifiers(&flags)
+        );
+
+        let colliding = vec![
+            SimpleFlag::Boolish(Key("Kernel-Printing".to_string()), true),
+            SimpleFlag::Stringish(Key("Kernel.Printing".to_string()), "a".to_string()),
+        ];
+        let expected = CMakeCodegenError::GenerationError(
+            RustCodeGenerationError::IdentifierCollision {
+                identifier: "Kernel_Printing".to_string(),
+                first_key: "Kernel-Printing".to_string(),
+                second_key: "Kernel.Printing".to_string(),
+            },
+        );
+        assert_eq!(
+            Err(expected.clone()),
+            truthy_boolean_flags_as_rust_identifiers(&colliding)
+        );
+        assert_eq!(
+            Err(expected),
+            simple_flags_to_rust_writer(&colliding, &mut Vec::new(), 0)
+        );
+    }
+
+    #[test]
+    fn standalone_rust_source() {
+        let flags = vec![
+            SimpleFlag::Boolish(Key("KernelPrinting".to_string()), true),
+            SimpleFlag::Integerish(Key("KernelMaxNumNodes".to_string()), 2),
+            SimpleFlag::Stringish(Key("KernelArch".to_string()), "x86".to_string()),
+        ];
+        let source = simple_flags_to_rust_source(&flags).unwrap();
+        let source = str::from_utf8(&source).unwrap();
+        assert!(source.starts_with("// NOTE: this file is generated by fel4"));
+        assert!(source.contains("\npub const KernelPrinting:bool = true;\n"));
+        assert!(source.contains("\npub const KernelMaxNumNodes:usize = 2;\n"));
+        assert!(source.contains("\npub const KernelArch:&'static str = \"x86\";\n"));
+    }
+
+    #[test]
+    fn key_value_cfgs() {
+        let flags = vec![
+            SimpleFlag::Stringish(Key("KernelPlatform".to_string()), "tx1".to_string()),
+            SimpleFlag::Stringish(Key("KernelArch".to_string()), "arm".to_string()),
+            SimpleFlag::Integerish(Key("KernelMaxNumNodes".to_string()), 2),
+            SimpleFlag::Boolish(Key("KernelPrinting".to_string()), true),
+        ];
+        assert_eq!(
+            Ok(vec![
+                ("sel4_KernelArch".to_string(), "arm".to_string()),
+                ("sel4_KernelPlatform".to_string(), "tx1".to_string()),
+            ]),
+            key_value_flags_as_rust_cfgs(&flags, DEFAULT_CFG_PREFIX, None)
+        );
+
+        let allowlist = vec![
+            "KernelMaxNumNodes".to_string(),
+            "KernelPlatform".to_string(),
+            "KernelPrinting".to_string(),
+        ];
+        assert_eq!(
+            Ok(vec![
+                ("KernelMaxNumNodes".to_string(), "2".to_string()),
+                ("KernelPlatform".to_string(), "tx1".to_string()),
+            ]),
+            key_value_flags_as_rust_cfgs(&flags, "", Some(&allowlist))
+        );
+        assert_eq!(
+            Err(CMakeCodegenError::InvalidCfgPrefix("sel4-".to_string())),
+            key_value_flags_as_rust_cfgs(&flags, "sel4-", None)
+        );
+        assert_eq!(
+            Ok(vec![("_KernelMaxNumNodes".to_string(), "2".to_string())]),
+            key_value_flags_as_rust_cfgs(&flags, "_", Some(&allowlist[..1]))
+        );
+    }
 }
@@ -1,7 +1,7 @@
 use super::Error;
 use config::LoudnessOpts;
 use std::ffi::OsStr;
-use std::process::Command;
+use std::process::{Command, ExitStatus};
 /// Extension methods for `Command` instances to supply common parameters or
 /// metadata
 pub trait CommandExt
@@ -18,6 +18,10 @@ where
     /// Execute a command with logging and status-code checking, discarding
     /// most output
     fn run_cmd(&mut self) -> Result<(), Error>;
+
+    /// Execute a command with logging, handing back the exit status without
+    /// judging it
+    fn status_cmd(&mut self) -> Result<ExitStatus, Error>;
 }
 
 impl CommandExt for Command {
@@ -37,16 +41,7 @@ impl CommandExt for Command {
     }
 
     fn run_cmd(&mut self) -> Result<(), Error> {
-        info!("running: {:?}", self);
-        let status = match self.status() {
-            Ok(status) => status,
-            Err(e) => {
-                return Err(Error::ExitStatusError(format!(
-                    "failed to execute the command: {}",
-                    e
-                )));
-            }
-        };
+        let status = self.status_cmd()?;
 
         if !status.success() {
             return Err(Error::ExitStatusError(format!(
@@ -57,4 +52,11 @@ impl CommandExt for Command {
 
         Ok(())
     }
+
+    fn status_cmd(&mut self) -> Result<ExitStatus, Error> {
+        info!("running: {:?}", self);
+        self.status().map_err(|e| {
+            Error::ExitStatusError(format!("failed to execute the command: {}", e))
+        })
+    }
 }
@@ -1,4 +1,5 @@
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use cargo_metadata;
 use fel4_config::{
@@ -8,6 +9,8 @@ use fel4_config::{
 use structopt::StructOpt;
 
 use super::Error;
+use generator::{resolve_allocator, resolve_thread_config, AllocatorConfig, ThreadConfig};
+use settings::{get_fel4_settings, Fel4Settings};
 
 #[derive(Debug, Clone, StructOpt)]
 #[structopt(bin_name = "cargo")]
@@ -32,6 +35,14 @@ pub enum Fel4SubCmd {
     TestCmd(TestCmd),
     #[structopt(name = "clean", about = "Remove generated artifacts")]
     CleanCmd(CleanCmd),
+    #[structopt(name = "debug", about = "Attach gdb to a feL4 simulation started with --gdb")]
+    DebugCmd(DebugCmd),
+    #[structopt(name = "config", about = "Inspect and edit the fel4.toml configuration")]
+    ConfigCmd(ConfigCmd),
+    #[structopt(
+        name = "verify", about = "Check the fel4.toml configuration against seL4's CMakeCache.txt"
+    )]
+    VerifyCmd(VerifyCmd),
 }
 #[derive(Debug, Clone, StructOpt)]
 pub struct LoudnessOpts {
@@ -49,6 +60,26 @@ pub struct BuildCmd {
     pub release: bool,
     #[structopt(name = "tests", long = "tests", help = "Build with feL4 test features enabled")]
     pub tests: bool,
+    #[structopt(
+        name = "message-format",
+        long = "message-format",
+        default_value = "human",
+        help = "Error format [values: human, json]"
+    )]
+    pub message_format: MessageFormat,
+    #[structopt(
+        name = "backend",
+        long = "backend",
+        help = "Override the builder backend selected in fel4.toml [values: xargo, build-std]"
+    )]
+    pub backend: Option<BuilderBackend>,
+    #[structopt(
+        name = "package",
+        long = "package",
+        short = "p",
+        help = "Package with the fel4.toml manifest to use, when in a workspace"
+    )]
+    pub package: Option<String>,
     #[structopt(
         name = "cargo-manifest-path",
         long = "manifest-path",
@@ -59,6 +90,65 @@ pub struct BuildCmd {
     pub cargo_manifest_path: PathBuf,
 }
 
+/// How build progress and results are reported
+///
+/// `Human` is the default log-based output, `Json` additionally emits one
+/// JSON object per line on stdout, in the spirit of cargo's
+/// `--message-format=json`, and moves the log and the output of the invoked
+/// tools to stderr.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for MessageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(MessageFormat::Human),
+            "json" => Ok(MessageFormat::Json),
+            _ => Err(format!(
+                "invalid message format '{}', expected one of: human, json",
+                s
+            )),
+        }
+    }
+}
+
+/// The tool used to cross-compile the sysroot and the root task
+///
+/// `Xargo` is the default, `BuildStd` uses the nightly-only
+/// `cargo -Z build-std` with the sysroot crates listed in Xargo.toml.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuilderBackend {
+    Xargo,
+    BuildStd,
+}
+
+impl Default for BuilderBackend {
+    fn default() -> Self {
+        BuilderBackend::Xargo
+    }
+}
+
+impl FromStr for BuilderBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xargo" => Ok(BuilderBackend::Xargo),
+            "build-std" => Ok(BuilderBackend::BuildStd),
+            _ => Err(format!(
+                "invalid builder backend '{}', expected one of: xargo, build-std",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, StructOpt)]
 pub struct SimulateCmd {
     #[structopt(flatten)]
@@ -67,6 +157,25 @@ pub struct SimulateCmd {
     pub release: bool,
     #[structopt(name = "tests", long = "tests", help = "Simulate test artifacts")]
     pub tests: bool,
+    #[structopt(
+        name = "timeout",
+        long = "timeout",
+        help = "Stop the simulation after this many seconds, failing the command"
+    )]
+    pub timeout: Option<u64>,
+    #[structopt(
+        name = "gdb",
+        long = "gdb",
+        help = "Start halted, waiting for gdb to attach on the given port (default 1234)"
+    )]
+    pub gdb: Option<Option<u16>>,
+    #[structopt(
+        name = "package",
+        long = "package",
+        short = "p",
+        help = "Package with the fel4.toml manifest to use, when in a workspace"
+    )]
+    pub package: Option<String>,
     #[structopt(
         name = "cargo-manifest-path",
         long = "manifest-path",
@@ -85,6 +194,19 @@ pub struct DeployCmd {
     pub release: bool,
     #[structopt(name = "tests", long = "tests", help = "Deploy test artifacts")]
     pub tests: bool,
+    #[structopt(
+        name = "dry-run",
+        long = "dry-run",
+        help = "Print the deployment steps instead of running them"
+    )]
+    pub dry_run: bool,
+    #[structopt(
+        name = "package",
+        long = "package",
+        short = "p",
+        help = "Package with the fel4.toml manifest to use, when in a workspace"
+    )]
+    pub package: Option<String>,
     #[structopt(
         name = "cargo-manifest-path",
         long = "manifest-path",
@@ -117,6 +239,20 @@ pub struct TestCmd {
     pub release: bool,
     #[structopt(subcommand)]
     pub subcmd: Option<TestSubCmd>,
+    #[structopt(
+        name = "timeout",
+        long = "timeout",
+        default_value = "300",
+        help = "Stop the test simulation after this many seconds, failing the command"
+    )]
+    pub timeout: u64,
+    #[structopt(
+        name = "package",
+        long = "package",
+        short = "p",
+        help = "Package with the fel4.toml manifest to use, when in a workspace"
+    )]
+    pub package: Option<String>,
     #[structopt(
         name = "cargo-manifest-path",
         long = "manifest-path",
@@ -137,10 +273,110 @@ pub enum TestSubCmd {
     Deploy,
 }
 
+#[derive(Debug, Clone, StructOpt)]
+pub struct DebugCmd {
+    #[structopt(flatten)]
+    pub loudness: LoudnessOpts,
+    #[structopt(name = "release", long = "release", help = "Debug release artifacts")]
+    pub release: bool,
+    #[structopt(name = "tests", long = "tests", help = "Debug test artifacts")]
+    pub tests: bool,
+    #[structopt(
+        name = "port",
+        long = "port",
+        default_value = "1234",
+        help = "Port of the QEMU gdbstub"
+    )]
+    pub port: u16,
+    #[structopt(
+        name = "gdb",
+        long = "gdb",
+        help = "The gdb executable, instead of the one matching the target"
+    )]
+    pub gdb: Option<String>,
+    #[structopt(
+        name = "package",
+        long = "package",
+        short = "p",
+        help = "Package with the fel4.toml manifest to use, when in a workspace"
+    )]
+    pub package: Option<String>,
+    #[structopt(
+        name = "cargo-manifest-path",
+        long = "manifest-path",
+        parse(from_os_str),
+        default_value = "./Cargo.toml",
+        help = "Path to the Cargo.toml manifest of the fel4 project"
+    )]
+    pub cargo_manifest_path: PathBuf,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct VerifyCmd {
+    #[structopt(flatten)]
+    pub loudness: LoudnessOpts,
+    #[structopt(name = "release", long = "release", help = "Verify the release configuration")]
+    pub release: bool,
+    #[structopt(name = "tests", long = "tests", help = "Verify the test configuration")]
+    pub tests: bool,
+    #[structopt(
+        name = "cmake-cache",
+        long = "cmake-cache",
+        parse(from_os_str),
+        help = "The CMakeCache.txt to check against, instead of the one of the last build"
+    )]
+    pub cmake_cache: Option<PathBuf>,
+    #[structopt(
+        name = "package",
+        long = "package",
+        short = "p",
+        help = "Package with the fel4.toml manifest to use, when in a workspace"
+    )]
+    pub package: Option<String>,
+    #[structopt(
+        name = "cargo-manifest-path",
+        long = "manifest-path",
+        parse(from_os_str),
+        default_value = "./Cargo.toml",
+        help = "Path to the Cargo.toml manifest of the fel4 project"
+    )]
+    pub cargo_manifest_path: PathBuf,
+}
+
 #[derive(Debug, Clone, StructOpt)]
 pub struct CleanCmd {
     #[structopt(flatten)]
     pub loudness: LoudnessOpts,
+    #[structopt(
+        name = "package",
+        long = "package",
+        short = "p",
+        help = "Package with the fel4.toml manifest to use, when in a workspace"
+    )]
+    pub package: Option<String>,
+    #[structopt(
+        name = "cargo-manifest-path",
+        long = "manifest-path",
+        parse(from_os_str),
+        default_value = "./Cargo.toml",
+        help = "Path to the Cargo.toml manifest of the fel4 project"
+    )]
+    pub cargo_manifest_path: PathBuf,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct ConfigCmd {
+    #[structopt(flatten)]
+    pub loudness: LoudnessOpts,
+    #[structopt(subcommand)]
+    pub subcmd: ConfigSubCmd,
+    #[structopt(
+        name = "package",
+        long = "package",
+        short = "p",
+        help = "Package with the fel4.toml manifest to use, when in a workspace"
+    )]
+    pub package: Option<String>,
     #[structopt(
         name = "cargo-manifest-path",
         long = "manifest-path",
@@ -151,6 +387,84 @@ pub struct CleanCmd {
     pub cargo_manifest_path: PathBuf,
 }
 
+#[derive(Debug, Clone, StructOpt)]
+pub enum ConfigSubCmd {
+    #[structopt(name = "show", about = "Print the configuration resolved for a build profile")]
+    Show {
+        #[structopt(name = "release", long = "release", help = "Resolve the release profile")]
+        release: bool,
+        #[structopt(name = "tests", long = "tests", help = "Resolve the test profile")]
+        tests: bool,
+        #[structopt(
+            name = "format",
+            long = "format",
+            default_value = "toml",
+            help = "Output format [values: toml, json]"
+        )]
+        format: ConfigFormat,
+    },
+    #[structopt(name = "get", about = "Print a value of fel4.toml")]
+    Get {
+        #[structopt(help = "Dotted path of the value, e.g. x86_64-sel4-fel4.debug.KernelPrinting")]
+        key: String,
+    },
+    #[structopt(name = "set", about = "Change a value of fel4.toml, preserving its comments")]
+    Set {
+        #[structopt(help = "Dotted path of the value, e.g. fel4.platform")]
+        key: String,
+        #[structopt(help = "A TOML value, bare words are taken as strings")]
+        value: String,
+    },
+    #[structopt(
+        name = "diff", about = "Show the properties that differ between two build profiles"
+    )]
+    Diff {
+        #[structopt(help = "Build profile [values: debug, release, test-debug, test-release]")]
+        from: Fel4BuildProfile,
+        #[structopt(help = "Build profile [values: debug, release, test-debug, test-release]")]
+        to: Fel4BuildProfile,
+    },
+    #[structopt(
+        name = "import",
+        about = "Import the seL4 options of a CMakeCache.txt into a build profile of fel4.toml"
+    )]
+    Import {
+        #[structopt(
+            name = "cmake-cache", parse(from_os_str), help = "The CMakeCache.txt to import"
+        )]
+        cmake_cache: PathBuf,
+        #[structopt(
+            name = "profile",
+            long = "profile",
+            default_value = "debug",
+            help = "The build profile to import into [values: debug, release]"
+        )]
+        profile: Fel4BuildProfile,
+    },
+}
+
+/// The output format of `cargo fel4 config show`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(format!(
+                "invalid config format '{}', expected one of: toml, json",
+                s
+            )),
+        }
+    }
+}
+
 impl<'a> From<&'a BuildCmd> for Fel4BuildProfile {
     fn from(c: &'a BuildCmd) -> Self {
         build_flags_to_profile(c.release, c.tests)
@@ -169,13 +483,25 @@ impl<'a> From<&'a DeployCmd> for Fel4BuildProfile {
     }
 }
 
+impl<'a> From<&'a DebugCmd> for Fel4BuildProfile {
+    fn from(c: &'a DebugCmd) -> Self {
+        build_flags_to_profile(c.release, c.tests)
+    }
+}
+
+impl<'a> From<&'a VerifyCmd> for Fel4BuildProfile {
+    fn from(c: &'a VerifyCmd) -> Self {
+        build_flags_to_profile(c.release, c.tests)
+    }
+}
+
 impl<'a> From<&'a TestCmd> for Fel4BuildProfile {
     fn from(c: &'a TestCmd) -> Self {
         build_flags_to_profile(c.release, true)
     }
 }
 
-fn build_flags_to_profile(is_release: bool, is_test: bool) -> Fel4BuildProfile {
+pub fn build_flags_to_profile(is_release: bool, is_test: bool) -> Fel4BuildProfile {
     match (is_release, is_test) {
         (true, true) => Fel4BuildProfile::TestRelease,
         (true, false) => Fel4BuildProfile::Release,
@@ -189,12 +515,22 @@ fn build_flags_to_profile(is_release: bool, is_test: bool) -> Fel4BuildProfile {
 #[derive(Debug, Clone)]
 pub struct ResolvedConfig {
     pub root_dir: PathBuf,
+    /// The Cargo.toml manifest of the feL4 package itself, even when
+    /// cargo-fel4 was pointed at a workspace manifest
+    pub manifest_path: PathBuf,
+    /// The cargo target directory, which may be shared by a workspace
+    pub target_dir: PathBuf,
     /// The end user application's package name
     pub pkg_name: String,
     /// The module name of the user application's package
     pub pkg_module_name: String,
     pub arch: Arch,
     pub fel4_config: Fel4Config,
+    /// The cargo-fel4 specific settings from fel4.toml
+    pub settings: Fel4Settings,
+    /// The root task thread defaults for the selected build profile
+    pub root_task_thread: ThreadConfig,
+    pub allocator: AllocatorConfig,
 }
 
 #[allow(non_camel_case_types)]
@@ -239,6 +575,15 @@ impl Fel4BuildProfile {
         }
     }
 
+    pub fn full_name(&self) -> &'static str {
+        match *self {
+            Fel4BuildProfile::Debug => "debug",
+            Fel4BuildProfile::Release => "release",
+            Fel4BuildProfile::TestDebug => "test-debug",
+            Fel4BuildProfile::TestRelease => "test-release",
+        }
+    }
+
     pub fn as_fel4_config_build_profile(&self) -> ConfigBuildProfile {
         match *self {
             Fel4BuildProfile::Debug => ConfigBuildProfile::Debug,
@@ -249,6 +594,23 @@ impl Fel4BuildProfile {
     }
 }
 
+impl FromStr for Fel4BuildProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Fel4BuildProfile::Debug),
+            "release" => Ok(Fel4BuildProfile::Release),
+            "test-debug" => Ok(Fel4BuildProfile::TestDebug),
+            "test-release" => Ok(Fel4BuildProfile::TestRelease),
+            _ => Err(format!(
+                "invalid build profile '{}', expected one of: debug, release, test-debug, test-release",
+                s
+            )),
+        }
+    }
+}
+
 pub struct ManifestWithRootDir {
     pub fel4_manifest: FullFel4Manifest,
     pub root_dir: PathBuf,
@@ -256,13 +618,10 @@ pub struct ManifestWithRootDir {
 
 pub fn get_fel4_manifest_with_root_dir<P: AsRef<Path>>(
     cargo_manifest_file_path: P,
+    package: Option<&str>,
 ) -> Result<ManifestWithRootDir, Error> {
-    let fel4_manifest = get_fel4_manifest(cargo_manifest_file_path.as_ref())?;
-    let root_dir = {
-        let mut p = cargo_manifest_file_path.as_ref().to_path_buf();
-        p.pop();
-        p
-    };
+    let Fel4Package { root_dir, .. } = get_fel4_package(cargo_manifest_file_path, package)?;
+    let fel4_manifest = get_fel4_manifest(root_dir.join("Cargo.toml"))?;
     Ok(ManifestWithRootDir {
         fel4_manifest,
         root_dir,
@@ -285,41 +644,111 @@ fn fel4_manifest_path_from_cargo_manifest_path<P: AsRef<Path>>(
     p.join("fel4.toml")
 }
 
-pub fn get_resolved_config<P: AsRef<Path>>(
+/// The location details of the feL4 package, which may be either a
+/// standalone package or one member of a cargo workspace
+#[derive(Debug, Clone)]
+pub struct Fel4Package {
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub root_dir: PathBuf,
+    /// The cargo target directory, which is shared by all workspace members
+    pub target_dir: PathBuf,
+}
+
+/// Select the feL4 package from the cargo metadata of the given manifest.
+///
+/// When no package name is supplied, a standalone package is used as-is,
+/// while in a workspace the single member with a fel4.toml manifest is
+/// selected.
+pub fn get_fel4_package<P: AsRef<Path>>(
     cargo_manifest_path: P,
-    build_profile: &Fel4BuildProfile,
-) -> Result<ResolvedConfig, Error> {
-    let (pkg_name, pkg_module_name, root_dir) = {
-        let metadata = cargo_metadata::metadata(Some(cargo_manifest_path.as_ref()))?;
-        if metadata.packages.len() > 1 {
-            return Err(Error::ConfigError(String::from(
-                "a fel4 build currently requires a singular top-level package",
-            )));
-        };
-
-        if let Some(pkg) = metadata.packages.first() {
-            let root_dir = {
-                let mut p = PathBuf::from(&pkg.manifest_path);
-                p.pop();
-                p
-            };
-            (pkg.name.clone(), pkg.name.replace("-", "_"), root_dir)
-        } else {
+    package: Option<&str>,
+) -> Result<Fel4Package, Error> {
+    let metadata = cargo_metadata::metadata(Some(cargo_manifest_path.as_ref()))?;
+    let candidates: Vec<&cargo_metadata::Package> = match package {
+        Some(name) => metadata
+            .packages
+            .iter()
+            .filter(|pkg| pkg.name == name)
+            .collect(),
+        None if metadata.packages.len() == 1 => metadata.packages.iter().collect(),
+        None => metadata
+            .packages
+            .iter()
+            .filter(|pkg| {
+                fel4_manifest_path_from_cargo_manifest_path(&pkg.manifest_path).exists()
+            })
+            .collect(),
+    };
+    let pkg = match (candidates.len(), package) {
+        (1, _) => candidates[0],
+        (0, Some(name)) => {
+            return Err(Error::ConfigError(format!(
+                "package '{}' is not a member of the workspace",
+                name
+            )))
+        }
+        (0, None) => {
             return Err(Error::ConfigError(String::from(
-                "a fel4 build currently requires a singular top-level package",
+                "could not find a workspace member with a fel4.toml manifest",
+            )))
+        }
+        _ => {
+            let names: Vec<&str> = candidates.iter().map(|pkg| pkg.name.as_str()).collect();
+            return Err(Error::ConfigError(format!(
+                "multiple workspace members have a fel4.toml manifest, select one with --package: {}",
+                names.join(", ")
             )));
         }
     };
+    let manifest_path = PathBuf::from(&pkg.manifest_path);
+    let root_dir = {
+        let mut p = manifest_path.clone();
+        p.pop();
+        p
+    };
+    Ok(Fel4Package {
+        name: pkg.name.clone(),
+        manifest_path,
+        root_dir,
+        target_dir: PathBuf::from(&metadata.target_directory),
+    })
+}
+
+pub fn get_resolved_config<P: AsRef<Path>>(
+    cargo_manifest_path: P,
+    package: Option<&str>,
+    build_profile: &Fel4BuildProfile,
+) -> Result<ResolvedConfig, Error> {
+    let Fel4Package {
+        name: pkg_name,
+        manifest_path,
+        root_dir,
+        target_dir,
+    } = get_fel4_package(cargo_manifest_path, package)?;
+    let pkg_module_name = pkg_name.replace("-", "_");
     let fel4_config: Fel4Config = get_fel4_config(
         root_dir.join("fel4.toml"),
         &build_profile.as_fel4_config_build_profile(),
     ).map_err(|e| Error::ConfigError(format!("{}", e)))?;
+    let settings = get_fel4_settings(root_dir.join("fel4.toml"))?;
+    let root_task_thread = resolve_thread_config(
+        &settings
+            .root_task
+            .for_profile(&build_profile.as_fel4_config_build_profile()),
+    )?;
+    let allocator = resolve_allocator(&settings.allocator)?;
     let arch = Arch::from(&fel4_config.target);
     Ok(ResolvedConfig {
         root_dir,
+        manifest_path,
+        target_dir,
         pkg_name,
         pkg_module_name,
         arch,
         fel4_config,
+        settings,
+        root_task_thread,
+        allocator,
     })
 }
@@ -0,0 +1,724 @@
+use cmake_config::{parse_file_to_raw, RawFlag, SimpleFlag};
+use fel4_config::{get_fel4_config, parse_full_manifest, Fel4Config, FlatTomlValue};
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use toml::{self, Value};
+
+use super::Error;
+use cmake_codegen::{filter_to_interesting_flags, CMakeCodegenError};
+use config::{
+    build_flags_to_profile, get_fel4_package, ConfigCmd, ConfigFormat, ConfigSubCmd,
+    Fel4BuildProfile, Fel4Package,
+};
+
+pub fn handle_config_cmd(cmd: &ConfigCmd) -> Result<(), Error> {
+    let Fel4Package { root_dir, .. } = get_fel4_package(
+        &cmd.cargo_manifest_path,
+        cmd.package.as_ref().map(String::as_str),
+    )?;
+    let fel4_manifest_path = root_dir.join("fel4.toml");
+    match cmd.subcmd {
+        ConfigSubCmd::Show {
+            release,
+            tests,
+            format,
+        } => {
+            let profile = build_flags_to_profile(release, tests);
+            let config = resolve_config(&fel4_manifest_path, &profile)?;
+            print!("{}", render_config(&config, &profile, format)?);
+        }
+        ConfigSubCmd::Get { ref key } => {
+            let contents = read_fel4_manifest(&fel4_manifest_path)?;
+            println!("{}", get_value(&contents, key)?);
+        }
+        ConfigSubCmd::Set { ref key, ref value } => {
+            let contents = read_fel4_manifest(&fel4_manifest_path)?;
+            let edited = set_value(&contents, key, value)?;
+            parse_full_manifest(&edited).map_err(|e| {
+                Error::ConfigError(format!(
+                    "Refusing to set '{}', fel4.toml would no longer be valid: {}",
+                    key, e
+                ))
+            })?;
+            fs::write(&fel4_manifest_path, edited).map_err(|e| {
+                Error::IO(format!(
+                    "Could not write {}: {}",
+                    fel4_manifest_path.display(),
+                    e
+                ))
+            })?;
+        }
+        ConfigSubCmd::Diff { from, to } => {
+            let from_config = resolve_config(&fel4_manifest_path, &from)?;
+            let to_config = resolve_config(&fel4_manifest_path, &to)?;
+            let diff = diff_properties(&from, &from_config, &to, &to_config);
+            if diff.is_empty() {
+                println!(
+                    "The {} and {} profiles resolve to the same properties",
+                    from.full_name(),
+                    to.full_name()
+                );
+            } else {
+                print!("{}", diff);
+            }
+        }
+        ConfigSubCmd::Import {
+            ref cmake_cache,
+            profile,
+        } => {
+            let contents = read_fel4_manifest(&fel4_manifest_path)?;
+            let cmake_flags = parse_file_to_raw(cmake_cache).map_err(CMakeCodegenError::from)?;
+            let import = plan_import(&contents, &profile, &cmake_flags)?;
+            let mut edited = contents.clone();
+            for (key, value) in &import.values {
+                let dotted_key = format!("{}.{}", import.table, key);
+                edited = set_value(&edited, &dotted_key, &value.to_string())?;
+            }
+            parse_full_manifest(&edited).map_err(|e| {
+                Error::ConfigError(format!(
+                    "Refusing to import '{}', fel4.toml would no longer be valid: {}",
+                    cmake_cache.display(),
+                    e
+                ))
+            })?;
+            if edited != contents {
+                fs::write(&fel4_manifest_path, edited).map_err(|e| {
+                    Error::IO(format!(
+                        "Could not write {}: {}",
+                        fel4_manifest_path.display(),
+                        e
+                    ))
+                })?;
+            }
+            print!("{}", import.report());
+        }
+    }
+    Ok(())
+}
+
+fn resolve_config(
+    fel4_manifest_path: &Path,
+    profile: &Fel4BuildProfile,
+) -> Result<Fel4Config, Error> {
+    get_fel4_config(fel4_manifest_path, &profile.as_fel4_config_build_profile())
+        .map_err(|e| Error::ConfigError(format!("{}", e)))
+}
+
+fn read_fel4_manifest(fel4_manifest_path: &Path) -> Result<String, Error> {
+    let mut contents = String::new();
+    File::open(fel4_manifest_path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| {
+            Error::IO(format!(
+                "Could not read {}: {}",
+                fel4_manifest_path.display(),
+                e
+            ))
+        })?;
+    Ok(contents)
+}
+
+/// The resolved configuration as printed by `cargo fel4 config show`
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct ConfigView<'a> {
+    profile: &'a str,
+    target: &'a str,
+    platform: &'a str,
+    artifact_path: String,
+    target_specs_path: String,
+    properties: BTreeMap<&'a str, Value>,
+}
+
+fn render_config(
+    config: &Fel4Config,
+    profile: &Fel4BuildProfile,
+    format: ConfigFormat,
+) -> Result<String, Error> {
+    let view = ConfigView {
+        profile: profile.full_name(),
+        target: config.target.full_name(),
+        platform: config.platform.full_name(),
+        artifact_path: config
+            .artifact_path
+            .join(profile.artifact_subdir_path())
+            .display()
+            .to_string(),
+        target_specs_path: config.target_specs_path.display().to_string(),
+        properties: config
+            .properties
+            .iter()
+            .map(|(k, v)| (k.as_str(), flat_value_to_toml(v)))
+            .collect(),
+    };
+    let rendered = match format {
+        ConfigFormat::Toml => toml::to_string(&view).map_err(|e| format!("{}", e)),
+        ConfigFormat::Json => serde_json::to_string_pretty(&view)
+            .map(|json| format!("{}\n", json))
+            .map_err(|e| format!("{}", e)),
+    };
+    rendered.map_err(|e| Error::ConfigError(format!("Could not render the configuration: {}", e)))
+}
+
+fn flat_value_to_toml(value: &FlatTomlValue) -> Value {
+    match value {
+        FlatTomlValue::Boolean(b) => Value::Boolean(*b),
+        FlatTomlValue::String(s) => Value::String(s.to_string()),
+        FlatTomlValue::Integer(i) => Value::Integer(*i),
+        FlatTomlValue::Float(f) => Value::Float(*f),
+        FlatTomlValue::Datetime(d) => Value::String(d.to_string()),
+    }
+}
+
+/// The properties that differ between two resolved configurations, in the
+/// style of a unified diff
+pub fn diff_properties(
+    from_profile: &Fel4BuildProfile,
+    from: &Fel4Config,
+    to_profile: &Fel4BuildProfile,
+    to: &Fel4Config,
+) -> String {
+    let mut lines = Vec::new();
+    let mut keys: Vec<&String> = from.properties.keys().chain(to.properties.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let from_value = from.properties.get(key).map(flat_value_to_toml);
+        let to_value = to.properties.get(key).map(flat_value_to_toml);
+        if from_value == to_value {
+            continue;
+        }
+        if let Some(value) = from_value {
+            lines.push(format!("-{} = {}", key, value));
+        }
+        if let Some(value) = to_value {
+            lines.push(format!("+{} = {}", key, value));
+        }
+    }
+    if lines.is_empty() {
+        return String::new();
+    }
+    format!(
+        "--- {}\n+++ {}\n{}\n",
+        from_profile.full_name(),
+        to_profile.full_name(),
+        lines.join("\n")
+    )
+}
+
+/// Split a dotted key into its table and the key within it, e.g.
+/// `x86_64-sel4-fel4.debug.KernelPrinting`
+fn split_key(key: &str) -> (&str, &str) {
+    match key.rfind('.') {
+        Some(index) => (&key[..index], &key[index + 1..]),
+        None => ("", key),
+    }
+}
+
+/// Look up a dotted key in fel4.toml. Strings are returned as-is, every
+/// other value as TOML.
+pub fn get_value(contents: &str, key: &str) -> Result<String, Error> {
+    let manifest: Value = contents
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("{}", e)))?;
+    let value = key
+        .split('.')
+        .fold(Some(&manifest), |value, part| value.and_then(|v| v.get(part)))
+        .ok_or_else(|| Error::ConfigError(format!("fel4.toml has no value for '{}'", key)))?;
+    match *value {
+        Value::String(ref s) => Ok(s.clone()),
+        Value::Table(_) => toml::to_string(value)
+            .map(|table| table.trim_matches('\n').to_string())
+            .map_err(|e| Error::ConfigError(format!("{}", e))),
+        ref other => Ok(other.to_string()),
+    }
+}
+
+/// Render a command line value as TOML: anything that is not already a
+/// valid TOML value is taken as a string. Values spanning several lines are
+/// refused, as they could add further keys to the table.
+fn value_literal(raw: &str) -> Result<String, Error> {
+    if raw.contains('\n') || raw.contains('\r') {
+        return Err(Error::ConfigError(format!(
+            "The value {:?} spans several lines",
+            raw
+        )));
+    }
+    match format!("value = {}", raw).parse::<Value>() {
+        Ok(Value::Table(ref table)) if table.len() == 1 => Ok(raw.trim().to_string()),
+        Ok(_) => Err(Error::ConfigError(format!(
+            "The value {:?} is not a single TOML value",
+            raw
+        ))),
+        Err(_) => Ok(Value::String(raw.to_string()).to_string()),
+    }
+}
+
+/// The name of the table a header line opens, or None for an array of
+/// tables
+fn table_header(line: &str) -> Option<String> {
+    if line.starts_with("[[") {
+        return None;
+    }
+    let end = line.find(']')?;
+    Some(
+        line[1..end]
+            .split('.')
+            .map(|part| part.trim().trim_matches('"'))
+            .collect::<Vec<_>>()
+            .join("."),
+    )
+}
+
+/// The key of a `key = value` line and the byte range of its value, when
+/// the value fits on the line
+fn key_value(line: &str) -> Option<(&str, Option<(usize, usize)>)> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with('[') {
+        return None;
+    }
+    let equals = line.find('=')?;
+    let key = line[..equals].trim().trim_matches('"');
+    let start = line[equals + 1..]
+        .find(|c: char| !c.is_whitespace())
+        .map_or(line.len(), |offset| equals + 1 + offset);
+    let value = &line[start..];
+    let length = if value.starts_with("\"\"\"") || value.starts_with("'''") {
+        None
+    } else if value.starts_with('"') {
+        let mut escaped = false;
+        value
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| {
+                let closing = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                closing
+            })
+            .map(|(i, _)| i + 1)
+    } else if value.starts_with('\'') {
+        value[1..].find('\'').map(|i| i + 2)
+    } else if value.starts_with('[') {
+        // Single-line arrays of simple values
+        value.find(']').map(|i| i + 1)
+    } else {
+        let end = value.find('#').unwrap_or_else(|| value.len());
+        Some(value[..end].trim().len())
+    };
+    Some((key, length.map(|length| (start, start + length))))
+}
+
+/// Set a dotted key of fel4.toml to a value, editing the line that holds it
+/// in place so that comments and formatting are preserved. A missing key is
+/// added at the end of its table, and a missing table at the end of the
+/// file.
+pub fn set_value(contents: &str, key: &str, raw_value: &str) -> Result<String, Error> {
+    let (table, name) = split_key(key);
+    let literal = value_literal(raw_value)?;
+    let new_line = format!("{} = {}", name, literal);
+    let mut lines: Vec<String> = contents.lines().map(String::from).collect();
+
+    // The root table is open until the first header
+    let mut in_table = table.is_empty();
+    let mut insert_at = if in_table { Some(0) } else { None };
+    let mut replaced = false;
+    for index in 0..lines.len() {
+        let trimmed = lines[index].trim().to_string();
+        if trimmed.starts_with('[') {
+            in_table = table_header(&trimmed).map_or(false, |header| header == table);
+            if in_table {
+                insert_at = Some(index + 1);
+            }
+            continue;
+        }
+        if !in_table {
+            continue;
+        }
+        let edit = match key_value(&lines[index]) {
+            Some((line_key, range)) if line_key == name => Some(range),
+            Some(_) => {
+                insert_at = Some(index + 1);
+                None
+            }
+            None => None,
+        };
+        if let Some(range) = edit {
+            let (start, end) = range.ok_or_else(|| {
+                Error::ConfigError(format!(
+                    "'{}' spans several lines, which cannot be edited in place",
+                    key
+                ))
+            })?;
+            let line = format!("{}{}{}", &lines[index][..start], literal, &lines[index][end..]);
+            lines[index] = line;
+            replaced = true;
+            break;
+        }
+    }
+    if !replaced {
+        match insert_at {
+            Some(index) => lines.insert(index, new_line),
+            None => {
+                if lines.last().map_or(false, |l| !l.trim().is_empty()) {
+                    lines.push(String::new());
+                }
+                lines.push(format!("[{}]", table));
+                lines.push(new_line);
+            }
+        }
+    }
+
+    let newline = if contents.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut edited = lines.join(newline);
+    edited.push_str(newline);
+    edited
+        .parse::<Value>()
+        .map_err(|e| Error::ConfigError(format!("Could not set '{}': {}", key, e)))?;
+    Ok(edited)
+}
+
+/// The changes `cargo fel4 config import` makes to fel4.toml
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheImport {
+    /// The profile table the options are written to
+    pub table: String,
+    /// The options to write, with their new values
+    pub values: Vec<(String, Value)>,
+    /// The options fel4.toml already sets to the same value
+    pub unchanged: Vec<String>,
+    /// The options fel4.toml sets in a table shared with other profiles,
+    /// with the name of that table
+    pub conflicts: Vec<(String, String)>,
+    /// The options that are not seL4 `Kernel*` or `Lib*` options
+    pub skipped: Vec<String>,
+}
+
+impl CacheImport {
+    /// The summary printed once the options are written
+    fn report(&self) -> String {
+        let mut lines = vec![format!(
+            "Imported {} options into [{}]",
+            self.values.len(),
+            self.table
+        )];
+        for (key, value) in &self.values {
+            lines.push(format!("  {} = {}", key, value));
+        }
+        if !self.unchanged.is_empty() {
+            lines.push(format!(
+                "{} options already had the same value",
+                self.unchanged.len()
+            ));
+        }
+        for (key, table) in &self.conflicts {
+            lines.push(format!(
+                "Skipped {}, which [{}] sets for every profile, edit it there instead",
+                key, table
+            ));
+        }
+        if !self.skipped.is_empty() {
+            lines.push(format!(
+                "Skipped {} options that are not Kernel* or Lib* options of type BOOL or \
+                 STRING: {}",
+                self.skipped.len(),
+                self.skipped.join(", ")
+            ));
+        }
+        format!("{}\n", lines.join("\n"))
+    }
+}
+
+/// Work out how to write the seL4 options of a CMakeCache.txt into the
+/// given profile table of the target fel4.toml selects. Options set in the
+/// target or platform tables, which apply to every profile, are left alone.
+pub fn plan_import(
+    contents: &str,
+    profile: &Fel4BuildProfile,
+    cmake_flags: &[RawFlag],
+) -> Result<CacheImport, Error> {
+    let manifest: Value = contents
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("{}", e)))?;
+    let selected = |key: &str| {
+        manifest
+            .get("fel4")
+            .and_then(|fel4| fel4.get(key))
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::ConfigError(format!("fel4.toml has no value for 'fel4.{}'", key)))
+    };
+    let target = selected("target")?;
+    let platform = selected("platform")?;
+    let profile_name = profile.as_fel4_config_build_profile().full_name();
+    let target_table = manifest.get(target);
+    let table_value = |table: Option<&str>, key: &str| {
+        let table = match table {
+            Some(name) => target_table.and_then(|t| t.get(name)),
+            None => target_table,
+        };
+        table.and_then(|t| t.get(key)).filter(|v| !v.is_table())
+    };
+
+    let interesting = filter_to_interesting_flags(cmake_flags.iter().cloned());
+    let mut import = CacheImport {
+        table: format!("{}.{}", target, profile_name),
+        values: Vec::new(),
+        unchanged: Vec::new(),
+        conflicts: Vec::new(),
+        skipped: Vec::new(),
+    };
+    for flag in &interesting {
+        if !flag.key.starts_with("Kernel") && !flag.key.starts_with("Lib") {
+            continue;
+        }
+        let value = cmake_flag_to_toml(flag);
+        if let Some(existing) = table_value(Some(profile_name), &flag.key) {
+            if existing == &value {
+                import.unchanged.push(flag.key.clone());
+            } else {
+                import.values.push((flag.key.clone(), value));
+            }
+            continue;
+        }
+        let shared = table_value(None, &flag.key)
+            .map(|v| (v, target.to_string()))
+            .or_else(|| {
+                table_value(Some(platform), &flag.key)
+                    .map(|v| (v, format!("{}.{}", target, platform)))
+            });
+        match shared {
+            Some((existing, _)) if existing == &value => import.unchanged.push(flag.key.clone()),
+            Some((_, table)) => import.conflicts.push((flag.key.clone(), table)),
+            None => import.values.push((flag.key.clone(), value)),
+        }
+    }
+    let imported = |key: &String| {
+        import.values.iter().any(|(k, _)| k == key)
+            || import.unchanged.contains(key)
+            || import.conflicts.iter().any(|(k, _)| k == key)
+    };
+    let mut skipped: Vec<String> = cmake_flags
+        .iter()
+        .map(|f| f.key.clone())
+        .filter(|key| !imported(key))
+        .collect();
+    skipped.sort();
+    skipped.dedup();
+    import.skipped = skipped;
+    Ok(import)
+}
+
+/// The TOML value of a CMake option: BOOL options become booleans, plain
+/// numbers integers and everything else strings
+fn cmake_flag_to_toml(flag: &RawFlag) -> Value {
+    match SimpleFlag::from(flag) {
+        SimpleFlag::Boolish(_, b) => Value::Boolean(b),
+        SimpleFlag::Integerish(_, n) if n <= i64::max_value() as u64 => Value::Integer(n as i64),
+        _ => Value::String(flag.value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cmake_config::CMakeType;
+    use fel4_config::{BuildProfile, SupportedPlatform, SupportedTarget};
+    use std::path::PathBuf;
+
+    const MANIFEST: &str = r#"[fel4]
+artifact-path = "artifacts"
+target = "x86_64-sel4-fel4"  # the default
+platform = "pc99"
+
+[x86_64-sel4-fel4]
+# Build the kernel for the simulator
+BuildWithCommonSimulationSettings = true
+
+[x86_64-sel4-fel4.debug]
+KernelPrinting = true
+"#;
+
+    #[test]
+    fn set_preserves_comments() {
+        let edited = set_value(MANIFEST, "fel4.target", "armv7-sel4-fel4").unwrap();
+        assert!(edited.contains("target = \"armv7-sel4-fel4\"  # the default\n"));
+        assert_eq!(MANIFEST.replace("x86_64-sel4-fel4\"  #", "armv7-sel4-fel4\"  #"), edited);
+
+        let edited = set_value(MANIFEST, "x86_64-sel4-fel4.debug.KernelPrinting", "false").unwrap();
+        assert_eq!(
+            "false",
+            get_value(&edited, "x86_64-sel4-fel4.debug.KernelPrinting").unwrap()
+        );
+        assert!(edited.contains("# Build the kernel for the simulator\n"));
+    }
+
+    #[test]
+    fn set_adds_missing_keys_and_tables() {
+        let edited = set_value(MANIFEST, "x86_64-sel4-fel4.KernelMaxNumNodes", "2").unwrap();
+        assert!(edited.contains(
+            "BuildWithCommonSimulationSettings = true\nKernelMaxNumNodes = 2\n\n[x86_64-sel4-fel4.debug]"
+        ));
+        let edited =
+            set_value(MANIFEST, "x86_64-sel4-fel4.release.KernelPrinting", "false").unwrap();
+        assert!(edited.ends_with(
+            "KernelPrinting = true\n\n[x86_64-sel4-fel4.release]\nKernelPrinting = false\n"
+        ));
+        assert_eq!(
+            "x86_64-sel4-fel4",
+            get_value(MANIFEST, "fel4.target").unwrap()
+        );
+        assert!(get_value(MANIFEST, "fel4.no-such-key").is_err());
+    }
+
+    #[test]
+    fn set_refuses_several_lines() {
+        assert!(set_value(MANIFEST, "fel4.target", "true\nfoo = 1").is_err());
+        assert!(set_value(MANIFEST, "fel4.target", "true\r\nfoo = 1").is_err());
+        let edited = set_value(MANIFEST, "fel4.platform", "pc 99 # comment").unwrap();
+        assert_eq!("pc 99 # comment", get_value(&edited, "fel4.platform").unwrap());
+    }
+
+    fn resolved_config(properties: &[(&str, FlatTomlValue)]) -> Fel4Config {
+        Fel4Config {
+            artifact_path: PathBuf::from("artifacts"),
+            target_specs_path: PathBuf::from("target_specs"),
+            target: SupportedTarget::X8664Sel4Fel4,
+            platform: SupportedPlatform::PC99,
+            build_profile: BuildProfile::Debug,
+            properties: properties
+                .iter()
+                .map(|&(ref key, ref value)| (key.to_string(), value.clone()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn render_resolved_config() {
+        let config = resolved_config(&[
+            ("KernelPrinting", FlatTomlValue::Boolean(true)),
+            ("KernelMaxNumNodes", FlatTomlValue::Integer(1)),
+        ]);
+        assert_eq!(
+            "profile = \"test-debug\"\n\
+             target = \"x86_64-sel4-fel4\"\n\
+             platform = \"pc99\"\n\
+             artifact-path = \"artifacts/test/debug\"\n\
+             target-specs-path = \"target_specs\"\n\
+             \n\
+             [properties]\n\
+             KernelMaxNumNodes = 1\n\
+             KernelPrinting = true\n",
+            render_config(&config, &Fel4BuildProfile::TestDebug, ConfigFormat::Toml).unwrap()
+        );
+        let json: serde_json::Value = serde_json::from_str(
+            &render_config(&config, &Fel4BuildProfile::Debug, ConfigFormat::Json).unwrap(),
+        ).unwrap();
+        assert_eq!("artifacts/debug", json["artifact-path"]);
+        assert_eq!(true, json["properties"]["KernelPrinting"]);
+    }
+
+    #[test]
+    fn diff_resolved_configs() {
+        let debug = resolved_config(&[
+            ("KernelDebugBuild", FlatTomlValue::Boolean(true)),
+            ("KernelPrinting", FlatTomlValue::Boolean(true)),
+            ("KernelArch", FlatTomlValue::String("x86".to_string())),
+        ]);
+        let release = resolved_config(&[
+            ("KernelDebugBuild", FlatTomlValue::Boolean(false)),
+            ("KernelArch", FlatTomlValue::String("x86".to_string())),
+            ("KernelOptimisation", FlatTomlValue::String("-O2".to_string())),
+        ]);
+        assert_eq!(
+            "--- debug\n+++ release\n\
+             -KernelDebugBuild = true\n\
+             +KernelDebugBuild = false\n\
+             +KernelOptimisation = \"-O2\"\n\
+             -KernelPrinting = true\n",
+            diff_properties(
+                &Fel4BuildProfile::Debug,
+                &debug,
+                &Fel4BuildProfile::Release,
+                &release
+            )
+        );
+        assert_eq!(
+            "",
+            diff_properties(
+                &Fel4BuildProfile::Debug,
+                &debug,
+                &Fel4BuildProfile::Debug,
+                &debug
+            )
+        );
+    }
+
+    #[test]
+    fn import_cmake_cache_options() {
+        let manifest = format!(
+            "{}\n[x86_64-sel4-fel4.pc99]\nKernelX86MicroArch = \"nehalem\"\n",
+            MANIFEST
+        );
+        let flag = |key: &str, cmake_type: CMakeType, value: &str| RawFlag {
+            key: key.to_string(),
+            cmake_type,
+            value: value.to_string(),
+            help: None,
+            advanced: false,
+        };
+        let cmake_flags = vec![
+            flag("CMAKE_BUILD_TYPE", CMakeType::String, "Debug"),
+            flag("HardwareDebugAPI", CMakeType::Bool, "OFF"),
+            flag("KernelPrinting", CMakeType::Bool, "ON"),
+            flag("KernelMaxNumNodes", CMakeType::String, "4"),
+            flag("KernelX86MicroArch", CMakeType::String, "haswell"),
+            flag("KernelX86Sel4Arch", CMakeType::Internal, "x86_64"),
+            flag("LibSel4FunctionAttributes", CMakeType::String, "public"),
+        ];
+        let import = plan_import(&manifest, &Fel4BuildProfile::Debug, &cmake_flags).unwrap();
+        assert_eq!(
+            CacheImport {
+                table: "x86_64-sel4-fel4.debug".to_string(),
+                values: vec![
+                    ("KernelMaxNumNodes".to_string(), Value::Integer(4)),
+                    (
+                        "LibSel4FunctionAttributes".to_string(),
+                        Value::String("public".to_string()),
+                    ),
+                ],
+                unchanged: vec!["KernelPrinting".to_string()],
+                conflicts: vec![(
+                    "KernelX86MicroArch".to_string(),
+                    "x86_64-sel4-fel4.pc99".to_string(),
+                )],
+                skipped: vec![
+                    "CMAKE_BUILD_TYPE".to_string(),
+                    "HardwareDebugAPI".to_string(),
+                    "KernelX86Sel4Arch".to_string(),
+                ],
+            },
+            import
+        );
+        assert_eq!(
+            "Imported 2 options into [x86_64-sel4-fel4.debug]\n\
+             \x20 KernelMaxNumNodes = 4\n\
+             \x20 LibSel4FunctionAttributes = \"public\"\n\
+             1 options already had the same value\n\
+             Skipped KernelX86MicroArch, which [x86_64-sel4-fel4.pc99] sets for every profile, \
+             edit it there instead\n\
+             Skipped 3 options that are not Kernel* or Lib* options of type BOOL or STRING: \
+             CMAKE_BUILD_TYPE, HardwareDebugAPI, KernelX86Sel4Arch\n",
+            import.report()
+        );
+
+        let release = plan_import(&manifest, &Fel4BuildProfile::Release, &cmake_flags).unwrap();
+        assert_eq!("x86_64-sel4-fel4.release", release.table);
+        assert!(
+            release
+                .values
+                .contains(&("KernelPrinting".to_string(), Value::Boolean(true)))
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use fel4_config::SupportedTarget;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::Error;
+use command_ext::CommandExt;
+use config::{
+    get_fel4_manifest_with_root_dir, get_fel4_package, DebugCmd, Fel4BuildProfile,
+    ManifestWithRootDir,
+};
+use elf::section_address;
+
+pub fn handle_debug_cmd(cmd: &DebugCmd) -> Result<(), Error> {
+    let ManifestWithRootDir {
+        fel4_manifest,
+        root_dir,
+    } = get_fel4_manifest_with_root_dir(
+        &cmd.cargo_manifest_path,
+        cmd.package.as_ref().map(String::as_str),
+    )?;
+    let target_dir = get_fel4_package(
+        &cmd.cargo_manifest_path,
+        cmd.package.as_ref().map(String::as_str),
+    )?.target_dir;
+    let build_profile = Fel4BuildProfile::from(cmd);
+    let artifact_path = Path::new(&root_dir)
+        .join(&fel4_manifest.artifact_path)
+        .join(build_profile.artifact_subdir_path());
+
+    let target_build_cache_path = target_dir
+        .join(fel4_manifest.selected_target.full_name())
+        .join(build_profile.as_fel4_config_build_profile().full_name());
+    let root_task_path = target_build_cache_path.join("root-task");
+    if !root_task_path.exists() {
+        return Err(Error::ConfigError(format!(
+            "Something went wrong with the build, cannot find the root task '{}'",
+            root_task_path.display()
+        )));
+    }
+
+    let gdb = cmd
+        .gdb
+        .clone()
+        .unwrap_or_else(|| default_gdb(&fel4_manifest.selected_target).to_string());
+    let mut gdb_cmd = Command::new(gdb);
+    gdb_cmd.arg(&root_task_path).arg("-ex").arg("set confirm off");
+
+    let kernel_path = kernel_symbols_path(
+        &fel4_manifest.selected_target,
+        &artifact_path,
+        &target_build_cache_path,
+    )?;
+    let text_address = fs::read(&kernel_path)
+        .ok()
+        .and_then(|contents| section_address(&contents, ".text"))
+        .ok_or_else(|| {
+            Error::ConfigError(format!(
+                "Could not load the kernel symbols from '{}'",
+                kernel_path.display()
+            ))
+        })?;
+    gdb_cmd.arg("-ex").arg(format!(
+        "add-symbol-file {} {:#x}",
+        kernel_path.display(),
+        text_address
+    ));
+
+    gdb_cmd
+        .arg("-ex")
+        .arg(format!("target remote localhost:{}", cmd.port))
+        .run_cmd()
+}
+
+/// The kernel ELF gdb loads the kernel symbols from. For x86_64 the build
+/// leaves it at `<artifact path>/kernel`. On the ARM targets that file is the
+/// elfloader image, which carries the kernel as a payload gdb cannot see
+/// into, so the kernel is taken from the seL4 CMake build instead.
+fn kernel_symbols_path(
+    target: &SupportedTarget,
+    artifact_path: &Path,
+    target_build_cache_path: &Path,
+) -> Result<PathBuf, Error> {
+    let kernel_path = match *target {
+        SupportedTarget::X8664Sel4Fel4 => Some(artifact_path.join("kernel")),
+        SupportedTarget::Armv7Sel4Fel4 | SupportedTarget::Aarch64Sel4Fel4 => {
+            cmake_kernel_elf_path(target_build_cache_path)
+        }
+    };
+    match kernel_path {
+        Some(ref kernel_path) if kernel_path.is_file() => Ok(kernel_path.clone()),
+        Some(kernel_path) => Err(Error::ConfigError(format!(
+            "Something went wrong with the build, cannot find the kernel '{}'",
+            kernel_path.display()
+        ))),
+        None => Err(Error::ConfigError(format!(
+            "Something went wrong with the build, cannot find the kernel ELF of the seL4 build \
+             in '{}'",
+            target_build_cache_path.join("build").display()
+        ))),
+    }
+}
+
+/// libsel4-sys runs the seL4 CMake build in
+/// `<build cache>/build/libsel4-sys-<hash>/out/build`, which leaves the
+/// kernel at `kernel/kernel.elf`. The most recently built one is picked when
+/// there are several.
+fn cmake_kernel_elf_path(target_build_cache_path: &Path) -> Option<PathBuf> {
+    fs::read_dir(target_build_cache_path.join("build"))
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("libsel4-sys-")
+        })
+        .map(|entry| {
+            entry
+                .path()
+                .join("out")
+                .join("build")
+                .join("kernel")
+                .join("kernel.elf")
+        })
+        .filter(|path| path.is_file())
+        .max_by_key(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+}
+
+/// The gdb matching the cross-compilers cargo-fel4 expects to be installed
+fn default_gdb(target: &SupportedTarget) -> &'static str {
+    match *target {
+        SupportedTarget::X8664Sel4Fel4 => "gdb",
+        SupportedTarget::Armv7Sel4Fel4 => "arm-none-eabi-gdb",
+        SupportedTarget::Aarch64Sel4Fel4 => "aarch64-linux-gnu-gdb",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    #[test]
+    fn x86_64_kernel_symbols_come_from_the_kernel_artifact() {
+        let artifacts = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let target = SupportedTarget::X8664Sel4Fel4;
+        assert!(kernel_symbols_path(&target, artifacts.path(), cache.path()).is_err());
+        fs::create_dir(artifacts.path().join("kernel")).unwrap();
+        assert!(kernel_symbols_path(&target, artifacts.path(), cache.path()).is_err());
+        fs::remove_dir(artifacts.path().join("kernel")).unwrap();
+        fs::write(artifacts.path().join("kernel"), b"").unwrap();
+        assert_eq!(
+            artifacts.path().join("kernel"),
+            kernel_symbols_path(&target, artifacts.path(), cache.path()).unwrap()
+        );
+    }
+
+    #[test]
+    fn arm_kernel_symbols_come_from_the_cmake_build() {
+        let artifacts = tempfile::tempdir().unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let target = SupportedTarget::Armv7Sel4Fel4;
+        // The elfloader image does not count
+        fs::write(artifacts.path().join("kernel"), b"").unwrap();
+        assert!(kernel_symbols_path(&target, artifacts.path(), cache.path()).is_err());
+
+        let kernel_dir = |crate_dir: &str| {
+            cache
+                .path()
+                .join("build")
+                .join(crate_dir)
+                .join("out")
+                .join("build")
+                .join("kernel")
+        };
+        fs::create_dir_all(kernel_dir("libsel4-sys-0123abcd")).unwrap();
+        assert!(kernel_symbols_path(&target, artifacts.path(), cache.path()).is_err());
+        fs::create_dir_all(kernel_dir("other-0123abcd")).unwrap();
+        fs::write(kernel_dir("other-0123abcd").join("kernel.elf"), b"").unwrap();
+        assert!(kernel_symbols_path(&target, artifacts.path(), cache.path()).is_err());
+        fs::write(kernel_dir("libsel4-sys-0123abcd").join("kernel.elf"), b"").unwrap();
+        assert_eq!(
+            kernel_dir("libsel4-sys-0123abcd").join("kernel.elf"),
+            kernel_symbols_path(&target, artifacts.path(), cache.path()).unwrap()
+        );
+    }
+}
@@ -1,29 +1,45 @@
-use std::path::Path;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use super::Error;
 use command_ext::CommandExt;
 use config::{get_fel4_manifest_with_root_dir, DeployCmd, Fel4BuildProfile, ManifestWithRootDir};
-use fel4_config::SupportedPlatform;
+use fel4_config::{SupportedPlatform, SupportedTarget};
+use settings::{get_fel4_settings, DeployMethod, DfuSettings, UbootSettings};
+use uboot::{uboot_file_name, write_uboot_image, UBOOT_TARGETS_MESSAGE};
+
+/// The TX1 recovery mode DFU device, used when deploying to the TX1 without
+/// a configured deploy method
+const TX1_DFU_DEVICE: &str = "0955:701a";
 
 pub fn handle_deploy_cmd(cmd: &DeployCmd) -> Result<(), Error> {
     let ManifestWithRootDir {
         fel4_manifest,
         root_dir,
-    } = get_fel4_manifest_with_root_dir(&cmd.cargo_manifest_path)?;
+    } = get_fel4_manifest_with_root_dir(
+        &cmd.cargo_manifest_path,
+        cmd.package.as_ref().map(String::as_str),
+    )?;
     let artifact_path = Path::new(&root_dir)
         .join(&fel4_manifest.artifact_path)
         .join(Fel4BuildProfile::from(cmd).artifact_subdir_path());
 
-    if fel4_manifest.selected_platform != SupportedPlatform::Tx1 {
-        return Err(Error::ConfigError(format!(
-            "The selected {} platform does not support deployment",
-            fel4_manifest.selected_platform.full_name()
-        )));
-    }
-
     let fel4img_path = artifact_path.join("feL4img");
 
+    // Settle on the deploy method before looking for the image
+    let settings = get_fel4_settings(root_dir.join("fel4.toml"))?;
+    let steps = deploy_plan(
+        &fel4_manifest.selected_target,
+        &fel4_manifest.selected_platform,
+        settings
+            .deploy
+            .get(fel4_manifest.selected_platform.full_name()),
+        &root_dir,
+        &fel4img_path,
+    )?;
+
     if !fel4img_path.exists() {
         return Err(Error::ConfigError(format!(
             "Something went wrong with the build, cannot find the deploy image '{}'",
@@ -31,15 +47,259 @@ pub fn handle_deploy_cmd(cmd: &DeployCmd) -> Result<(), Error> {
         )));
     }
 
-    // We currently only support deploying to the TX1 platform,
-    // so the deploy-mode is hard-coded to DFU and
-    // the DFU device is hard-coded to the TX1
-    Command::new("dfu-util")
-        .arg("--device")
-        .arg("0955:701a")
-        .arg("-a")
-        .arg("kernel")
-        .arg("-D")
-        .arg(fel4img_path)
-        .run_cmd()
+    for mut step in steps {
+        if cmd.dry_run {
+            println!("{}", step);
+        } else {
+            step.execute()?;
+        }
+    }
+    Ok(())
+}
+
+/// A single action of a deployment
+#[derive(Debug)]
+pub enum DeployStep {
+    CreateDir(PathBuf),
+    Copy { from: PathBuf, to: PathBuf },
+    /// Wrap an ELF image for U-Boot
+    Package {
+        from: PathBuf,
+        to: PathBuf,
+        target: SupportedTarget,
+        settings: UbootSettings,
+    },
+    Run(Command),
+}
+
+impl DeployStep {
+    pub fn execute(&mut self) -> Result<(), Error> {
+        match *self {
+            DeployStep::CreateDir(ref dir) => fs::create_dir_all(dir).map_err(|e| {
+                Error::IO(format!("Could not create '{}': {}", dir.display(), e))
+            }),
+            DeployStep::Copy { ref from, ref to } => fs::copy(from, to).map(|_| ()).map_err(|e| {
+                Error::IO(format!(
+                    "Could not copy '{}' to '{}': {}",
+                    from.display(),
+                    to.display(),
+                    e
+                ))
+            }),
+            DeployStep::Package {
+                ref from,
+                ref to,
+                ref target,
+                ref settings,
+            } => write_uboot_image(from, to, target, settings),
+            DeployStep::Run(ref mut cmd) => cmd.run_cmd(),
+        }
+    }
+}
+
+impl fmt::Display for DeployStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeployStep::CreateDir(ref dir) => write!(f, "mkdir -p \"{}\"", dir.display()),
+            DeployStep::Copy { ref from, ref to } => {
+                write!(f, "cp \"{}\" \"{}\"", from.display(), to.display())
+            }
+            DeployStep::Package {
+                ref from, ref to, ..
+            } => write!(
+                f,
+                "package \"{}\" as the U-Boot image \"{}\"",
+                from.display(),
+                to.display()
+            ),
+            DeployStep::Run(ref cmd) => write!(f, "{:?}", cmd),
+        }
+    }
+}
+
+/// Work out the steps that deliver the image to the platform with the
+/// configured deploy method. The TX1 is deployed to by DFU unless configured
+/// otherwise, every other platform needs a `[fel4.deploy.<platform>]` table.
+pub fn deploy_plan(
+    target: &SupportedTarget,
+    platform: &SupportedPlatform,
+    method: Option<&DeployMethod>,
+    root_dir: &Path,
+    image_path: &Path,
+) -> Result<Vec<DeployStep>, Error> {
+    let default_method = DeployMethod::Dfu(DfuSettings::default());
+    let method = match method {
+        Some(method) => method,
+        None if *platform == SupportedPlatform::Tx1 => &default_method,
+        None => {
+            return Err(Error::ConfigError(format!(
+                "The selected {} platform has no deploy method, configure one in a [fel4.deploy.{}] table",
+                platform.full_name(),
+                platform.full_name()
+            )))
+        }
+    };
+    if let DeployMethod::UbootImage(_) = *method {
+        if *target == SupportedTarget::X8664Sel4Fel4 {
+            return Err(Error::ConfigError(format!(
+                "{}, the uboot-image deploy method of the {} platform cannot be used",
+                UBOOT_TARGETS_MESSAGE,
+                platform.full_name()
+            )));
+        }
+    }
+    let image_name = image_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "feL4img".to_string());
+    let mut steps = Vec::new();
+    match *method {
+        DeployMethod::Dfu(ref dfu) => {
+            let device = match dfu.device {
+                Some(ref device) => device.as_str(),
+                None if *platform == SupportedPlatform::Tx1 => TX1_DFU_DEVICE,
+                None => {
+                    return Err(Error::ConfigError(format!(
+                        "The dfu deploy method of the {} platform requires a device",
+                        platform.full_name()
+                    )))
+                }
+            };
+            let mut dfu_util = Command::new("dfu-util");
+            dfu_util
+                .arg("--device")
+                .arg(device)
+                .arg("-a")
+                .arg(dfu.alt.as_ref().map(String::as_str).unwrap_or("kernel"))
+                .arg("-D")
+                .arg(image_path);
+            steps.push(DeployStep::Run(dfu_util));
+        }
+        DeployMethod::Tftp(ref copy) => {
+            let directory = root_dir.join(&copy.directory);
+            steps.push(DeployStep::CreateDir(directory.clone()));
+            steps.push(DeployStep::Copy {
+                from: image_path.to_path_buf(),
+                to: directory.join(copy.file_name.as_ref().unwrap_or(&image_name)),
+            });
+        }
+        DeployMethod::SdCard(ref copy) => {
+            let directory = root_dir.join(&copy.directory);
+            if !directory.is_dir() {
+                return Err(Error::ConfigError(format!(
+                    "The SD card boot partition is not mounted at '{}'",
+                    directory.display()
+                )));
+            }
+            steps.push(DeployStep::Copy {
+                from: image_path.to_path_buf(),
+                to: directory.join(copy.file_name.as_ref().unwrap_or(&image_name)),
+            });
+        }
+        DeployMethod::UbootImage(ref uboot) => {
+            let image = uboot.image();
+            let uimage_name = uboot_file_name(&image_name, &image);
+            let uimage_path = image_path.with_file_name(&uimage_name);
+            steps.push(DeployStep::Package {
+                from: image_path.to_path_buf(),
+                to: uimage_path.clone(),
+                target: target.clone(),
+                settings: image,
+            });
+            if let Some(ref directory) = uboot.directory {
+                let directory = root_dir.join(directory);
+                steps.push(DeployStep::CreateDir(directory.clone()));
+                steps.push(DeployStep::Copy {
+                    from: uimage_path,
+                    to: directory.join(uboot.file_name.as_ref().unwrap_or(&uimage_name)),
+                });
+            }
+        }
+        DeployMethod::Script(ref script) => {
+            let mut run_script = Command::new(root_dir.join(&script.path));
+            run_script
+                .current_dir(root_dir)
+                .args(&script.args)
+                .arg(image_path);
+            steps.push(DeployStep::Run(run_script));
+        }
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use settings::{CopySettings, UbootImageSettings};
+
+    #[test]
+    fn tx1_defaults_to_dfu() {
+        let steps = deploy_plan(
+            &SupportedTarget::Aarch64Sel4Fel4,
+            &SupportedPlatform::Tx1,
+            None,
+            Path::new("/project"),
+            Path::new("/project/artifacts/debug/feL4img"),
+        ).unwrap();
+        assert_eq!(1, steps.len());
+        let step = steps[0].to_string();
+        assert!(step.contains("\"dfu-util\" \"--device\" \"0955:701a\" \"-a\" \"kernel\""));
+    }
+
+    #[test]
+    fn configured_tftp_and_unconfigured_platforms() {
+        let tftp = DeployMethod::Tftp(CopySettings {
+            directory: PathBuf::from("/srv/tftp"),
+            file_name: Some("sabre.img".to_string()),
+        });
+        let steps = deploy_plan(
+            &SupportedTarget::Armv7Sel4Fel4,
+            &SupportedPlatform::Sabre,
+            Some(&tftp),
+            Path::new("/project"),
+            Path::new("/project/artifacts/debug/feL4img"),
+        ).unwrap();
+        assert_eq!(
+            vec![
+                "mkdir -p \"/srv/tftp\"",
+                "cp \"/project/artifacts/debug/feL4img\" \"/srv/tftp/sabre.img\"",
+            ],
+            steps.iter().map(|s| s.to_string()).collect::<Vec<_>>()
+        );
+        assert!(
+            deploy_plan(
+                &SupportedTarget::Armv7Sel4Fel4,
+                &SupportedPlatform::Sabre,
+                None,
+                Path::new("/project"),
+                Path::new("/project/artifacts/debug/feL4img"),
+            ).is_err()
+        );
+    }
+
+    #[test]
+    fn uboot_image_is_rejected_for_x86_64() {
+        let uboot = DeployMethod::UbootImage(UbootImageSettings {
+            format: None,
+            load_address: None,
+            entry_address: None,
+            name: None,
+            directory: None,
+            file_name: None,
+        });
+        let plan = |target| {
+            deploy_plan(
+                &target,
+                &SupportedPlatform::PC99,
+                Some(&uboot),
+                Path::new("/project"),
+                Path::new("/project/artifacts/debug/feL4img"),
+            )
+        };
+        match plan(SupportedTarget::X8664Sel4Fel4) {
+            Err(Error::ConfigError(ref msg)) => assert!(msg.starts_with(UBOOT_TARGETS_MESSAGE)),
+            other => panic!("Unexpected plan {:?}", other),
+        }
+        assert_eq!(1, plan(SupportedTarget::Armv7Sel4Fel4).unwrap().len());
+    }
 }
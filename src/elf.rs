@@ -0,0 +1,250 @@
+//! Just enough little-endian ELF parsing to find section addresses and to
+//! flatten loadable segments into a raw image
+
+/// The contents of the loadable segments of an ELF file, laid out from the
+/// lowest physical load address
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoadImage {
+    pub load_address: u64,
+    /// The physical address of the entry point
+    pub entry_address: u64,
+    pub data: Vec<u8>,
+}
+
+/// Segments further apart than this are not flattened into a single image
+const MAX_LOAD_IMAGE_SIZE: u64 = 256 * 1024 * 1024;
+
+const PT_LOAD: u64 = 1;
+
+struct Layout {
+    is_64_bit: bool,
+}
+
+impl Layout {
+    fn parse(contents: &[u8]) -> Option<Layout> {
+        if contents.get(0..4)? != b"\x7fELF" || *contents.get(5)? != 1 {
+            return None;
+        }
+        match *contents.get(4)? {
+            1 => Some(Layout { is_64_bit: false }),
+            2 => Some(Layout { is_64_bit: true }),
+            _ => None,
+        }
+    }
+
+    /// The width of addresses and offsets
+    fn word(&self) -> usize {
+        if self.is_64_bit {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// Offsets of the (e_entry, e_phoff, e_shoff) ELF header fields
+    fn header_offsets(&self) -> (usize, usize, usize) {
+        if self.is_64_bit {
+            (0x18, 0x20, 0x28)
+        } else {
+            (0x18, 0x1c, 0x20)
+        }
+    }
+
+    /// Offset of e_phentsize, followed by e_phnum, e_shentsize, e_shnum
+    /// and e_shstrndx
+    fn table_sizes_offset(&self) -> usize {
+        if self.is_64_bit {
+            0x36
+        } else {
+            0x2a
+        }
+    }
+}
+
+/// The address of a section, e.g. `.text`
+pub fn section_address(contents: &[u8], section_name: &str) -> Option<u64> {
+    let layout = Layout::parse(contents)?;
+    let (_, _, shoff_field) = layout.header_offsets();
+    let sizes = layout.table_sizes_offset();
+    let section_headers = read_le(contents, shoff_field, layout.word())?;
+    let entry_size = read_le(contents, sizes + 4, 2)?;
+    let count = read_le(contents, sizes + 6, 2)?;
+    let names_index = read_le(contents, sizes + 8, 2)?;
+    // (offset of sh_addr, offset of sh_offset)
+    let (addr_field, offset_field) = if layout.is_64_bit {
+        (0x10, 0x18)
+    } else {
+        (0x0c, 0x10)
+    };
+    let header = |index: u64| table_entry(section_headers, index, entry_size);
+    let names_offset = read_le(
+        contents,
+        header(names_index)?.checked_add(offset_field)?,
+        layout.word(),
+    )?;
+    for index in 0..count {
+        let name_offset = names_offset.checked_add(read_le(contents, header(index)?, 4)?)?;
+        let name = contents.get(name_offset as usize..)?;
+        if name.starts_with(section_name.as_bytes()) && name.get(section_name.len()) == Some(&0) {
+            return read_le(
+                contents,
+                header(index)?.checked_add(addr_field)?,
+                layout.word(),
+            );
+        }
+    }
+    None
+}
+
+/// Flatten the loadable segments into a single image, as `objcopy -O binary`
+/// would. The image is laid out by physical address, so the virtual entry
+/// point is translated through the segment containing it.
+pub fn load_image(contents: &[u8]) -> Option<LoadImage> {
+    let layout = Layout::parse(contents)?;
+    let word = layout.word();
+    let (entry_field, phoff_field, _) = layout.header_offsets();
+    let sizes = layout.table_sizes_offset();
+    let virtual_entry_address = read_le(contents, entry_field, word)?;
+    let program_headers = read_le(contents, phoff_field, word)?;
+    let entry_size = read_le(contents, sizes, 2)?;
+    let count = read_le(contents, sizes + 2, 2)?;
+    // (offset of p_offset, p_vaddr, p_paddr, p_filesz, p_memsz)
+    let (offset_field, vaddr_field, paddr_field, filesz_field, memsz_field) = if layout.is_64_bit {
+        (0x08, 0x10, 0x18, 0x20, 0x28)
+    } else {
+        (0x04, 0x08, 0x0c, 0x10, 0x14)
+    };
+
+    // (physical address, file offset, size) of each loadable segment
+    let mut segments = Vec::new();
+    let mut entry_address = None;
+    for index in 0..count {
+        let header = table_entry(program_headers, index, entry_size)?;
+        let field = |field: usize| read_le(contents, header.checked_add(field)?, word);
+        if read_le(contents, header, 4)? != PT_LOAD {
+            continue;
+        }
+        let physical_address = field(paddr_field)?;
+        let virtual_address = field(vaddr_field)?;
+        let memory_size = field(memsz_field)?;
+        if virtual_entry_address >= virtual_address
+            && virtual_entry_address - virtual_address < memory_size
+        {
+            entry_address = physical_address.checked_add(virtual_entry_address - virtual_address);
+        }
+        let file_size = field(filesz_field)?;
+        if file_size != 0 {
+            segments.push((physical_address, field(offset_field)?, file_size));
+        }
+    }
+    let entry_address = entry_address?;
+    let load_address = segments.iter().map(|s| s.0).min()?;
+    let mut end_address = load_address;
+    for &(address, _, size) in &segments {
+        end_address = end_address.max(address.checked_add(size)?);
+    }
+    if end_address - load_address > MAX_LOAD_IMAGE_SIZE {
+        return None;
+    }
+    let mut data = vec![0u8; (end_address - load_address) as usize];
+    for &(address, offset, size) in &segments {
+        let source = contents.get(offset as usize..offset.checked_add(size)? as usize)?;
+        let start = (address - load_address) as usize;
+        data[start..start + source.len()].copy_from_slice(source);
+    }
+    Some(LoadImage {
+        load_address,
+        entry_address,
+        data,
+    })
+}
+
+/// The offset of an entry of a program or section header table, or `None`
+/// when it overflows
+fn table_entry(table_offset: u64, index: u64, entry_size: u64) -> Option<usize> {
+    Some(table_offset.checked_add(index.checked_mul(entry_size)?)? as usize)
+}
+
+fn read_le(contents: &[u8], offset: usize, width: usize) -> Option<u64> {
+    let bytes = contents.get(offset..offset.checked_add(width)?)?;
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0u64, |value, byte| (value << 8) | u64::from(*byte)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// An ELF32 file with two loadable segments, 8 bytes apart, whose
+    /// virtual addresses are offset from their physical ones
+    fn two_segment_elf32(virtual_offset: u32) -> Vec<u8> {
+        fn push_le(elf: &mut Vec<u8>, value: u32, width: usize) {
+            for i in 0..width {
+                elf.push((value >> (8 * i)) as u8);
+            }
+        }
+        let mut elf = b"\x7fELF\x01\x01\x01".to_vec();
+        elf.resize(0x18, 0);
+        push_le(&mut elf, 0x8000_0008 + virtual_offset, 4); // e_entry
+        push_le(&mut elf, 52, 4); // e_phoff
+        push_le(&mut elf, 0, 4); // e_shoff
+        push_le(&mut elf, 0, 4); // e_flags
+        push_le(&mut elf, 52, 2); // e_ehsize
+        push_le(&mut elf, 32, 2); // e_phentsize
+        push_le(&mut elf, 2, 2); // e_phnum
+        elf.resize(52, 0);
+        for &(paddr, offset) in &[(0x8000_0008u32, 120u32), (0x8000_0000, 116)] {
+            push_le(&mut elf, 1, 4); // p_type
+            push_le(&mut elf, offset, 4);
+            push_le(&mut elf, paddr + virtual_offset, 4); // p_vaddr
+            push_le(&mut elf, paddr, 4);
+            push_le(&mut elf, 4, 4); // p_filesz
+            push_le(&mut elf, 4, 4); // p_memsz
+            push_le(&mut elf, 0, 4); // p_flags
+            push_le(&mut elf, 0, 4); // p_align
+        }
+        elf.extend_from_slice(b"abcdwxyz");
+        elf
+    }
+
+    #[test]
+    fn flatten_segments() {
+        assert_eq!(
+            Some(LoadImage {
+                load_address: 0x8000_0000,
+                entry_address: 0x8000_0008,
+                data: b"abcd\0\0\0\0wxyz".to_vec(),
+            }),
+            load_image(&two_segment_elf32(0))
+        );
+        assert_eq!(
+            Some(0x8000_0008),
+            load_image(&two_segment_elf32(0x4000_0000)).map(|image| image.entry_address)
+        );
+        assert_eq!(None, load_image(b"\x7fELF but not really"));
+    }
+
+    #[test]
+    fn overflowing_header_table() {
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(0x20, 0);
+        elf.extend_from_slice(&[0xff; 8]); // e_phoff
+        elf.resize(0x36, 0);
+        elf.extend_from_slice(&[56, 0, 2, 0]); // e_phentsize, e_phnum
+        elf.resize(0x40, 0);
+        assert_eq!(None, load_image(&elf));
+    }
+
+    #[test]
+    fn text_address_of_this_executable() {
+        let test_executable = fs::read(env::current_exe().unwrap()).unwrap();
+        assert!(section_address(&test_executable, ".text").is_some());
+        assert_eq!(None, section_address(&test_executable, ".no-such-section"));
+    }
+}
@@ -0,0 +1,201 @@
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+use super::Error;
+
+/// The name of the fingerprint file kept in the artifact directory
+pub const FINGERPRINT_FILE_NAME: &str = ".fel4-fingerprint.json";
+
+/// The inputs of the ARM libsel4-sys rebuild, which bundles the root task
+/// into the elfloader image. When they match those of the previous build of
+/// an artifact directory, the rebuild is skipped.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BuildFingerprint {
+    /// The fel4.toml properties of the selected target, platform and profile
+    pub properties: BTreeMap<String, String>,
+    /// Hash of the target specification JSON
+    pub target_spec: String,
+    pub rustflags: String,
+    /// The builder backend, with the sysroot crates it builds
+    pub builder: String,
+    /// The locked libsel4-sys package, which holds the seL4 sources
+    pub libsel4_sys: String,
+    /// Hash of the root task binary
+    pub root_task: String,
+}
+
+impl BuildFingerprint {
+    /// The fingerprint stored by the previous build, if any
+    pub fn load(artifact_path: &Path) -> Option<BuildFingerprint> {
+        let contents = fs::read(artifact_path.join(FINGERPRINT_FILE_NAME)).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    /// Whether the elfloader image in the artifact directory was built from
+    /// these inputs
+    pub fn is_fresh(&self, artifact_path: &Path) -> bool {
+        artifact_path.join("kernel").exists()
+            && BuildFingerprint::load(artifact_path).as_ref() == Some(self)
+    }
+
+    pub fn store(&self, artifact_path: &Path) -> Result<(), Error> {
+        let path = artifact_path.join(FINGERPRINT_FILE_NAME);
+        let contents = serde_json::to_vec_pretty(self)
+            .map_err(|e| Error::IO(format!("Could not serialize the build fingerprint: {}", e)))?;
+        fs::write(&path, contents)
+            .map_err(|e| Error::IO(format!("Could not write '{}': {}", path.display(), e)))
+    }
+}
+
+/// Hash of a file's contents, or of nothing when it cannot be read
+pub fn hash_file(path: &Path) -> String {
+    hash_bytes(&fs::read(path).unwrap_or_default())
+}
+
+/// 64 bit FNV-1a, as a hexadecimal string. This is not a cryptographic hash,
+/// but unlike the std hashers it is stable across Rust releases.
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// The Cargo.lock cargo resolves a package through, which is that of its
+/// workspace when it is a workspace member
+pub fn find_lockfile(root_dir: &Path) -> Option<PathBuf> {
+    root_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())
+}
+
+/// The version and source of a package in a Cargo.lock, which for git
+/// dependencies includes the revision, or nothing when it is not locked
+pub fn locked_package(lockfile_path: &Path, name: &str) -> String {
+    let lockfile: Option<Value> = fs::read_to_string(lockfile_path)
+        .ok()
+        .and_then(|contents| contents.parse().ok());
+    lockfile
+        .as_ref()
+        .and_then(|lockfile| lockfile.get("package"))
+        .and_then(Value::as_array)
+        .and_then(|packages| {
+            packages
+                .iter()
+                .find(|package| package.get("name").and_then(Value::as_str) == Some(name))
+        })
+        .map(|package| {
+            format!(
+                "{} {}",
+                package.get("version").and_then(Value::as_str).unwrap_or_default(),
+                package.get("source").and_then(Value::as_str).unwrap_or("path")
+            )
+        })
+        .unwrap_or_default()
+}
+
+/// Write the contents to the file unless it already holds them, leaving its
+/// modification time untouched so that cargo does not rebuild needlessly.
+/// Returns whether the file was written.
+pub fn write_if_changed(path: &Path, contents: &[u8]) -> Result<bool, Error> {
+    if let Ok(existing) = fs::read(path) {
+        if existing == contents {
+            return Ok(false);
+        }
+    }
+    fs::write(path, contents)
+        .map_err(|e| Error::IO(format!("Could not write '{}': {}", path.display(), e)))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    #[test]
+    fn unchanged_files_are_not_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("root-task.rs");
+        assert!(write_if_changed(&path, b"fn main() {}").unwrap());
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        assert!(!write_if_changed(&path, b"fn main() {}").unwrap());
+        assert_eq!(modified, fs::metadata(&path).unwrap().modified().unwrap());
+        assert!(write_if_changed(&path, b"fn main() { loop {} }").unwrap());
+    }
+
+    #[test]
+    fn fingerprint_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(None, BuildFingerprint::load(dir.path()));
+        let mut fingerprint = BuildFingerprint {
+            target_spec: hash_bytes(b"{}"),
+            rustflags: "--cfg KernelPrinting".to_string(),
+            root_task: hash_file(&dir.path().join("missing")),
+            ..BuildFingerprint::default()
+        };
+        fingerprint
+            .properties
+            .insert("KernelArch".to_string(), "arm".to_string());
+        fingerprint.store(dir.path()).unwrap();
+        assert_eq!(Some(fingerprint), BuildFingerprint::load(dir.path()));
+        assert_eq!("cbf29ce484222325", hash_bytes(b""));
+    }
+
+    #[test]
+    fn locked_package_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Cargo.lock");
+        assert_eq!("", locked_package(&path, "libsel4-sys"));
+        fs::write(
+            &path,
+            "[[package]]\nname = \"hello\"\nversion = \"0.1.0\"\n\n\
+             [[package]]\nname = \"libsel4-sys\"\nversion = \"0.5.0\"\n\
+             source = \"git+https://github.com/PolySync/libsel4-sys.git?branch=master#0123abc\"\n",
+        ).unwrap();
+        assert_eq!(
+            "0.5.0 git+https://github.com/PolySync/libsel4-sys.git?branch=master#0123abc",
+            locked_package(&path, "libsel4-sys")
+        );
+        assert_eq!("0.1.0 path", locked_package(&path, "hello"));
+    }
+
+    #[test]
+    fn libsel4_sys_update_forces_a_rebuild() {
+        let workspace = tempfile::tempdir().unwrap();
+        let member_dir = workspace.path().join("member");
+        fs::create_dir(&member_dir).unwrap();
+        assert_eq!(None, find_lockfile(&member_dir));
+        let lockfile_path = workspace.path().join("Cargo.lock");
+        let lockfile = |version: &str| {
+            format!(
+                "[[package]]\nname = \"libsel4-sys\"\nversion = \"{}\"\n\
+                 source = \"registry+https://github.com/rust-lang/crates.io-index\"\n",
+                version
+            )
+        };
+        fs::write(&lockfile_path, lockfile("0.5.0")).unwrap();
+        assert_eq!(Some(lockfile_path.clone()), find_lockfile(&member_dir));
+
+        let artifacts = tempfile::tempdir().unwrap();
+        let fingerprint = |lockfile_path: &Path| BuildFingerprint {
+            libsel4_sys: locked_package(lockfile_path, "libsel4-sys"),
+            ..BuildFingerprint::default()
+        };
+        let built = fingerprint(&find_lockfile(&member_dir).unwrap());
+        assert!(!built.is_fresh(artifacts.path()));
+        built.store(artifacts.path()).unwrap();
+        assert!(!built.is_fresh(artifacts.path()));
+        fs::write(artifacts.path().join("kernel"), b"").unwrap();
+        assert!(built.is_fresh(artifacts.path()));
+
+        fs::write(&lockfile_path, lockfile("0.5.1")).unwrap();
+        assert!(!fingerprint(&find_lockfile(&member_dir).unwrap()).is_fresh(artifacts.path()));
+    }
+}
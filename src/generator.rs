@@ -1,20 +1,185 @@
+use std::collections::HashSet;
 use std::io::Write;
 
 use super::Error;
 use cmake_codegen::simple_flags_to_rust_writer;
-use cmake_config::SimpleFlag;
+use cmake_config::{is_valid_rust_identifier, SimpleFlag};
 use config::Arch;
+use settings::{AllocatorKind, AllocatorSettings, ComponentSettings, HeapBacking, ThreadSettings};
 
 const ARMV7_ASM: &str = include_str!("asm/arm.s");
 const AARCH64_ASM: &str = include_str!("asm/aarch64.s");
 const X86_ASM: &str = include_str!("asm/x86.s");
 const X86_64_ASM: &str = include_str!("asm/x86_64.s");
 
+/// The default stack size of a root task spawned thread, in bytes
+pub const DEFAULT_STACK_SIZE: usize = 32768;
+/// The default priority of a root task spawned thread
+pub const DEFAULT_PRIORITY: u8 = 255;
+/// The default maximum controlled priority of a root task spawned thread,
+/// which matches the seL4 default for a new TCB
+pub const DEFAULT_MAX_CONTROLLED_PRIORITY: u8 = 0;
+
+/// The default heap size in bytes, which matches the default of the
+/// wee_alloc static array backend
+pub const DEFAULT_HEAP_SIZE: usize = 32 * 1024 * 1024;
+/// Heap memory retyped from untyped memory is mapped at this address, which
+/// lies in the first GiB of the root task's address space so that only page
+/// tables (and no higher level paging structures) have to be created. The
+/// root task image is linked below it on every architecture, which the
+/// generated `init_heap` checks at boot.
+const UNTYPED_HEAP_VADDR: usize = 0x1000_0000;
+/// The end of the first GiB, which an untyped-backed heap must fit below
+const UNTYPED_HEAP_LIMIT: usize = 0x4000_0000;
+const PAGE_SIZE: usize = 4096;
+
+/// The global allocator installed by the root task when the `alloc` feature
+/// is enabled
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AllocatorConfig {
+    pub kind: AllocatorKind,
+    /// In bytes
+    pub heap_size: usize,
+    pub backing: HeapBacking,
+}
+
+impl AllocatorConfig {
+    /// The optional dependency of the user crate that provides the
+    /// allocator, enabled as a feature of the same name
+    pub fn dependency_feature(&self) -> Option<&'static str> {
+        match self.kind {
+            AllocatorKind::WeeAlloc => Some("wee_alloc"),
+            AllocatorKind::LinkedList => Some("linked_list_allocator"),
+            AllocatorKind::Bump | AllocatorKind::None => None,
+        }
+    }
+}
+
+/// Resolve the allocator settings, applying the built-in defaults for
+/// anything fel4.toml leaves unspecified
+pub fn resolve_allocator(settings: &AllocatorSettings) -> Result<AllocatorConfig, Error> {
+    let kind = settings.kind.unwrap_or(AllocatorKind::WeeAlloc);
+    let backing = settings.backing.unwrap_or(HeapBacking::Static);
+    let heap_size = settings.heap_size.unwrap_or(DEFAULT_HEAP_SIZE);
+    if heap_size == 0 {
+        return Err(Error::ConfigError(
+            "The heap size must be non-zero".to_string(),
+        ));
+    }
+    if kind == AllocatorKind::WeeAlloc && backing == HeapBacking::Untyped {
+        return Err(Error::ConfigError(
+            "The wee-alloc allocator only supports a static heap backing".to_string(),
+        ));
+    }
+    let heap_size = match backing {
+        HeapBacking::Static => heap_size,
+        // Untyped memory is handed out a page at a time
+        HeapBacking::Untyped => (heap_size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE,
+    };
+    if backing == HeapBacking::Untyped && heap_size > UNTYPED_HEAP_LIMIT - UNTYPED_HEAP_VADDR {
+        return Err(Error::ConfigError(format!(
+            "An untyped-backed heap can be at most {} bytes",
+            UNTYPED_HEAP_LIMIT - UNTYPED_HEAP_VADDR
+        )));
+    }
+    Ok(AllocatorConfig {
+        kind,
+        heap_size,
+        backing,
+    })
+}
+
+/// The scheduling and stack parameters of a thread started by the root task
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ThreadConfig {
+    /// In bytes
+    pub stack_size: usize,
+    pub priority: u8,
+    pub max_controlled_priority: u8,
+}
+
+/// A user application thread that the root task will start
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Component {
+    pub name: String,
+    /// Path to the entry function, relative to the user crate root
+    pub entry: String,
+    pub thread: ThreadConfig,
+}
+
+/// Resolve the root task thread defaults, applying the built-in defaults for
+/// anything fel4.toml leaves unspecified
+pub fn resolve_thread_config(settings: &ThreadSettings) -> Result<ThreadConfig, Error> {
+    let stack_size = settings.stack_size.unwrap_or(DEFAULT_STACK_SIZE);
+    if stack_size == 0 || stack_size % 16 != 0 {
+        return Err(Error::ConfigError(format!(
+            "A thread stack size must be a non-zero multiple of 16 bytes, found {}",
+            stack_size
+        )));
+    }
+    Ok(ThreadConfig {
+        stack_size,
+        priority: settings.priority.unwrap_or(DEFAULT_PRIORITY),
+        max_controlled_priority: settings
+            .max_controlled_priority
+            .unwrap_or(DEFAULT_MAX_CONTROLLED_PRIORITY),
+    })
+}
+
+/// Validate the components declared in fel4.toml, falling back to a single
+/// component that runs the user crate's `run` function when none are
+/// declared. Unspecified component values are taken from the root task
+/// thread defaults.
+pub fn resolve_components(
+    settings: &[ComponentSettings],
+    defaults: &ThreadConfig,
+) -> Result<Vec<Component>, Error> {
+    if settings.is_empty() {
+        return Ok(vec![Component {
+            name: "main".to_string(),
+            entry: "run".to_string(),
+            thread: defaults.clone(),
+        }]);
+    }
+    let mut names: HashSet<&str> = HashSet::new();
+    let mut components = Vec::new();
+    for c in settings {
+        if !names.insert(&c.name) {
+            return Err(Error::ConfigError(format!(
+                "Duplicate component name '{}'",
+                c.name
+            )));
+        }
+        if !c.entry.split("::").all(is_valid_rust_identifier) {
+            return Err(Error::ConfigError(format!(
+                "The entry of component '{}' is not a valid Rust path: '{}'",
+                c.name, c.entry
+            )));
+        }
+        let thread = resolve_thread_config(&ThreadSettings {
+            stack_size: c.stack_size.or(Some(defaults.stack_size)),
+            priority: c.priority.or(Some(defaults.priority)),
+            max_controlled_priority: c
+                .max_controlled_priority
+                .or(Some(defaults.max_controlled_priority)),
+        }).map_err(|e| Error::ConfigError(format!("Component '{}': {}", c.name, e)))?;
+        components.push(Component {
+            name: c.name.clone(),
+            entry: c.entry.clone(),
+            thread,
+        });
+    }
+    Ok(components)
+}
+
 pub struct Generator<'a, 'b, 'c, W: Write + 'a> {
     writer: &'a mut W,
     package_module_name: &'b str,
     arch: &'b Arch,
     flags: &'c [SimpleFlag],
+    components: &'c [Component],
+    test_thread: &'c ThreadConfig,
+    allocator: &'c AllocatorConfig,
 }
 
 impl<'a, 'b, 'c, W: Write> Generator<'a, 'b, 'c, W> {
@@ -23,6 +188,9 @@ impl<'a, 'b, 'c, W: Write> Generator<'a, 'b, 'c, W> {
         package_module_name: &'b str,
         arch: &'b Arch,
         flags: &'c [SimpleFlag],
+        components: &'c [Component],
+        test_thread: &'c ThreadConfig,
+        allocator: &'c AllocatorConfig,
     ) -> Self
     where
         W: Write,
@@ -32,6 +200,9 @@ impl<'a, 'b, 'c, W: Write> Generator<'a, 'b, 'c, W> {
             package_module_name,
             arch,
             flags,
+            components,
+            test_thread,
+            allocator,
         }
     }
 
@@ -44,13 +215,11 @@ impl<'a, 'b, 'c, W: Write> Generator<'a, 'b, 'c, W> {
 use core::intrinsics;
 use core::panic::PanicInfo;
 use core::mem;
+use core::ptr;
 use sel4_sys::*;\n\n",
         )?;
 
-        self.writer.write_all(b"#[cfg(feature = \"alloc\")]\n")?;
-        self.writer.write_all(b"#[global_allocator]\n")?;
-        self.writer
-            .write_all(b"static ALLOCATOR: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;\n")?;
+        self.generate_global_allocator()?;
 
         self.writer.write_all(
             b"
@@ -77,13 +246,11 @@ fn get_untyped(info: &seL4_BootInfo, size_bytes: usize) -> Option<seL4_CPtr> {
     }
     None
 }
-
-const CHILD_STACK_SIZE: usize = 4096;
-static mut CHILD_STACK: *const [u64; CHILD_STACK_SIZE] =
-    &[0; CHILD_STACK_SIZE];
-
-        ",
+",
         )?;
+        self.generate_heap()?;
+        self.generate_components()?;
+        self.generate_spawn_component()?;
         self.generate_main()?;
         let asm = match *self.arch {
             Arch::X86 => X86_ASM,
@@ -110,8 +277,10 @@ static mut CHILD_STACK: *const [u64; CHILD_STACK_SIZE] =
         )?;
 
         self.writer.write_all(b"extern crate sel4_sys;\n")?;
-        self.writer.write_all(b"#[cfg(feature = \"alloc\")]\n")?;
-        self.writer.write_all(b"extern crate wee_alloc;\n")?;
+        if let Some(dependency) = self.allocator.dependency_feature() {
+            self.writer.write_all(b"#[cfg(feature = \"alloc\")]\n")?;
+            writeln!(self.writer, "extern crate {};", dependency)?;
+        }
         self.writer.write_all(b"#[cfg(feature = \"alloc\")]\n")?;
         self.writer.write_all(b"extern crate alloc;\n")?;
         self.writer
@@ -121,14 +290,327 @@ static mut CHILD_STACK: *const [u64; CHILD_STACK_SIZE] =
         Ok(())
     }
 
-    fn generate_main(&mut self) -> Result<(), Error> {
+    fn generate_global_allocator(&mut self) -> Result<(), Error> {
+        match self.allocator.kind {
+            AllocatorKind::WeeAlloc => self.writer.write_all(
+                b"#[cfg(feature = \"alloc\")]
+#[global_allocator]
+static ALLOCATOR: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;\n",
+            )?,
+            AllocatorKind::LinkedList => self.writer.write_all(
+                b"#[cfg(feature = \"alloc\")]
+#[global_allocator]
+static ALLOCATOR: linked_list_allocator::LockedHeap =
+    linked_list_allocator::LockedHeap::empty();\n",
+            )?,
+            AllocatorKind::Bump => self.writer.write_all(BUMP_ALLOCATOR_CODE.as_bytes())?,
+            AllocatorKind::None => self
+                .writer
+                .write_all(b"// no global allocator was selected in fel4.toml\n")?,
+        }
+        Ok(())
+    }
+
+    /// Whether the allocator has to be handed its heap memory at boot
+    fn allocator_needs_init(&self) -> bool {
+        match self.allocator.kind {
+            AllocatorKind::LinkedList | AllocatorKind::Bump => true,
+            AllocatorKind::WeeAlloc | AllocatorKind::None => false,
+        }
+    }
+
+    /// Emit `init_heap`, which provides the allocator with its heap memory
+    /// and returns the next free slot of the root CNode
+    fn generate_heap(&mut self) -> Result<(), Error> {
+        if !self.allocator_needs_init() {
+            return Ok(());
+        }
+        let init_allocator = match self.allocator.kind {
+            AllocatorKind::LinkedList => {
+                "unsafe { ALLOCATOR.lock().init(heap_start, HEAP_SIZE) };"
+            }
+            _ => "ALLOCATOR.init(heap_start, HEAP_SIZE);",
+        };
+        writeln!(
+            self.writer,
+            "
+#[cfg(feature = \"alloc\")]
+const HEAP_SIZE: usize = {};",
+            self.allocator.heap_size
+        )?;
+        match self.allocator.backing {
+            HeapBacking::Static => {
+                writeln!(
+                    self.writer,
+                    "#[cfg(feature = \"alloc\")]
+static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+
+#[cfg(feature = \"alloc\")]
+fn init_heap(_bootinfo: &seL4_BootInfo, first_free_slot: seL4_CPtr) -> seL4_CPtr {{
+    let heap_start = unsafe {{ HEAP.as_mut_ptr() as usize }};
+    {}
+    first_free_slot
+}}",
+                    init_allocator
+                )?;
+            }
+            HeapBacking::Untyped => {
+                self.generate_page_mapping()?;
+                writeln!(
+                    self.writer,
+                    "#[cfg(feature = \"alloc\")]
+const HEAP_VADDR: usize = {:#x};
+
+/// Retype a single object from the first non-device untyped with room for it
+#[cfg(feature = \"alloc\")]
+fn retype_any_untyped(
+    bootinfo: &seL4_BootInfo,
+    object_type: seL4_Word,
+    size_bits: seL4_Word,
+    slot: seL4_CPtr,
+) {{
+    if slot >= bootinfo.empty.end {{
+        panic!(\"Ran out of CSpace slots while creating the heap\");
+    }}
+    let cspace_cap = seL4_CapInitThreadCNode;
+    let mut idx = 0;
+    for untyped in bootinfo.untyped.start..bootinfo.untyped.end {{
+        let desc = &bootinfo.untypedList[idx];
+        idx += 1;
+        if desc.isDevice != 0 || (desc.sizeBits as seL4_Word) < size_bits {{
+            continue;
+        }}
+        let retype_err: seL4_Error = unsafe {{
+            seL4_Untyped_Retype(
+                untyped,
+                object_type,
+                size_bits,
+                cspace_cap.into(),
+                cspace_cap.into(),
+                seL4_WordBits.into(),
+                slot,
+                1,
+            )
+        }};
+        if retype_err == 0 {{
+            return;
+        }}
+    }}
+    panic!(\"Ran out of untyped memory while creating the heap\");
+}}
+
+#[cfg(feature = \"alloc\")]
+fn init_heap(bootinfo: &seL4_BootInfo, first_free_slot: seL4_CPtr) -> seL4_CPtr {{
+    let mut slot = first_free_slot;
+    let page_size: usize = 1 << seL4_PageBits;
+    // The kernel maps the IPC buffer, the boot info frame and the extra boot
+    // info right after the root task image
+    let image_end =
+        bootinfo as *const seL4_BootInfo as usize + page_size + bootinfo.extraLen as usize;
+    assert!(
+        image_end <= HEAP_VADDR,
+        \"The root task image ends at {{:#x}}, past the heap at {{:#x}}\",
+        image_end,
+        HEAP_VADDR
+    );
+    for page in 0..HEAP_SIZE / page_size {{
+        let vaddr = (HEAP_VADDR + page * page_size) as seL4_Word;
+        retype_any_untyped(bootinfo, PAGE_OBJECT, seL4_PageBits as seL4_Word, slot);
+        if map_page(slot, vaddr) != 0 {{
+            // The page table covering vaddr does not exist yet
+            retype_any_untyped(
+                bootinfo,
+                PAGE_TABLE_OBJECT,
+                seL4_PageTableBits as seL4_Word,
+                slot + 1,
+            );
+            assert!(
+                map_page_table(slot + 1, vaddr) == 0,
+                \"Failed to map a heap page table\"
+            );
+            assert!(map_page(slot, vaddr) == 0, \"Failed to map a heap page\");
+            slot += 1;
+        }}
+        slot += 1;
+    }}
+    let heap_start = HEAP_VADDR;
+    {}
+    slot
+}}",
+                    UNTYPED_HEAP_VADDR, init_allocator
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Emit the architecture specific object types and invocations needed to
+    /// map heap pages into the root task's address space
+    fn generate_page_mapping(&mut self) -> Result<(), Error> {
+        let (page_object, page_table_object, page_map, page_table_map, attributes) =
+            match *self.arch {
+                Arch::X86 | Arch::X86_64 => (
+                    "_object_seL4_X86_4K",
+                    "_object_seL4_X86_PageTableObject",
+                    "seL4_X86_Page_Map",
+                    "seL4_X86_PageTable_Map",
+                    "seL4_X86_VMAttributes_seL4_X86_Default_VMAttributes",
+                ),
+                Arch::Armv7 | Arch::Aarch64 => (
+                    "_object_seL4_ARM_SmallPageObject",
+                    "_object_seL4_ARM_PageTableObject",
+                    "seL4_ARM_Page_Map",
+                    "seL4_ARM_PageTable_Map",
+                    "seL4_ARM_VMAttributes_seL4_ARM_Default_VMAttributes",
+                ),
+            };
+        writeln!(
+            self.writer,
+            "#[cfg(feature = \"alloc\")]
+const PAGE_OBJECT: seL4_Word = {page_object} as seL4_Word;
+#[cfg(feature = \"alloc\")]
+const PAGE_TABLE_OBJECT: seL4_Word = {page_table_object} as seL4_Word;
+
+#[cfg(feature = \"alloc\")]
+fn map_page(page_cap: seL4_CPtr, vaddr: seL4_Word) -> seL4_Error {{
+    unsafe {{
+        {page_map}(
+            page_cap,
+            seL4_CapInitThreadVSpace.into(),
+            vaddr,
+            seL4_CapRights_new(0, 1, 1),
+            {attributes},
+        )
+    }}
+}}
+
+#[cfg(feature = \"alloc\")]
+fn map_page_table(page_table_cap: seL4_CPtr, vaddr: seL4_Word) -> seL4_Error {{
+    unsafe {{
+        {page_table_map}(
+            page_table_cap,
+            seL4_CapInitThreadVSpace.into(),
+            vaddr,
+            {attributes},
+        )
+    }}
+}}
+",
+            page_object = page_object,
+            page_table_object = page_table_object,
+            page_map = page_map,
+            page_table_map = page_table_map,
+            attributes = attributes,
+        )?;
+        Ok(())
+    }
+
+    /// Emit the stack of each component, along with a typed reference to its
+    /// entry function so that a missing or mistyped function is reported at
+    /// compile time
+    fn generate_components(&mut self) -> Result<(), Error> {
+        writeln!(
+            self.writer,
+            "
+// fel4_test thread
+#[cfg(feature = \"test\")]
+const TEST_STACK_SIZE: usize = {};
+#[cfg(feature = \"test\")]
+static mut TEST_STACK: [u64; TEST_STACK_SIZE / 8] = [0; TEST_STACK_SIZE / 8];",
+            self.test_thread.stack_size
+        )?;
+        for (i, c) in self.components.iter().enumerate() {
+            writeln!(
+                self.writer,
+                "
+// component '{name}'
+#[allow(dead_code)]
+const COMPONENT_{i}_ENTRY: fn() = {pkg}::{entry};
+#[cfg(not(feature = \"test\"))]
+const COMPONENT_{i}_STACK_SIZE: usize = {stack_size};
+#[cfg(not(feature = \"test\"))]
+static mut COMPONENT_{i}_STACK: [u64; COMPONENT_{i}_STACK_SIZE / 8] =
+    [0; COMPONENT_{i}_STACK_SIZE / 8];",
+                name = c.name,
+                i = i,
+                pkg = self.package_module_name,
+                entry = c.entry,
+                stack_size = c.thread.stack_size,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn generate_spawn_component(&mut self) -> Result<(), Error> {
         self.writer.write_all(
             b"
-fn main() {
-    let bootinfo = unsafe { &*BOOTINFO };
+/// Written to the lowest word of each thread stack, and checked by the root
+/// task for signs of a stack overflow whenever a thread faults
+const STACK_CANARY: u64 = 0xfe14_57ac_ca4a_12ee;
+
+#[allow(dead_code)]
+struct ComponentThread {
+    name: &'static str,
+    tcb_cap: seL4_CPtr,
+    canary: *const u64,
+    overflowed: bool,
+}
+
+impl ComponentThread {
+    /// Suspend the thread and report it, when its stack canary was clobbered.
+    /// Returns whether the overflow was newly detected.
+    fn check_stack_canary(&mut self) -> bool {
+        if self.overflowed || unsafe { ptr::read_volatile(self.canary) } == STACK_CANARY {
+            return false;
+        }
+        self.overflowed = true;
+        let _: u32 = unsafe { seL4_TCB_Suspend(self.tcb_cap) };
+        #[cfg(feature = \"KernelPrinting\")]
+        {
+            use core::fmt::Write;
+            let _ = write!(
+                sel4_sys::DebugOutHandle,
+                \"----- stack overflow detected in '{}', thread suspended -----\\n\",
+                self.name
+            );
+        }
+        true
+    }
+}
+
+/// Create the endpoint the faults of the component threads are delivered to
+fn create_fault_endpoint(bootinfo: &seL4_BootInfo, ep_cap: seL4_CPtr) -> seL4_CPtr {
+    let cspace_cap = seL4_CapInitThreadCNode;
+    let untyped = get_untyped(bootinfo, 1 << seL4_EndpointBits).unwrap();
+    let retype_err: seL4_Error = unsafe {
+        seL4_Untyped_Retype(
+            untyped,
+            api_object_seL4_EndpointObject.into(),
+            seL4_EndpointBits.into(),
+            cspace_cap.into(),
+            cspace_cap.into(),
+            seL4_WordBits.into(),
+            ep_cap,
+            1,
+        )
+    };
+
+    assert!(retype_err == 0, \"Failed to retype untyped memory\");
+    ep_cap
+}
+
+fn spawn_component(
+    bootinfo: &seL4_BootInfo,
+    name: &'static str,
+    tcb_cap: seL4_CPtr,
+    fault_ep: seL4_CPtr,
+    entry: seL4_Word,
+    stack: &'static mut [u64],
+    priority: seL4_Word,
+    max_controlled_priority: seL4_Word,
+) -> ComponentThread {
     let cspace_cap = seL4_CapInitThreadCNode;
     let pd_cap = seL4_CapInitThreadVSpace;
-    let tcb_cap = bootinfo.empty.start;
     let untyped = get_untyped(bootinfo, 1 << seL4_TCBBits).unwrap();
     let retype_err: seL4_Error = unsafe {
         seL4_Untyped_Retype(
@@ -148,7 +630,7 @@ fn main() {
     let tcb_err: seL4_Error = unsafe {
         seL4_TCB_Configure(
             tcb_cap,
-            seL4_CapNull.into(),
+            fault_ep.into(),
             cspace_cap.into(),
             seL4_NilData.into(),
             pd_cap.into(),
@@ -160,43 +642,20 @@ fn main() {
 
     assert!(tcb_err == 0, \"Failed to configure TCB\");
 
-    let stack_base = unsafe { CHILD_STACK as usize };
-    let stack_top = stack_base + CHILD_STACK_SIZE;
+    stack[0] = STACK_CANARY;
+    let canary = &stack[0] as *const u64;
+    let stack_base = stack.as_ptr() as usize;
+    let stack_top = (stack_base + stack.len() * mem::size_of::<u64>()) & !0xf;
     let mut regs: seL4_UserContext = unsafe { mem::zeroed() };\n",
         )?;
 
         match *self.arch {
             Arch::X86 | Arch::X86_64 => {
-                writeln!(
-                    self.writer,
-                    "    #[cfg(feature = \"test\")]
-    {{ regs.rip = {}::fel4_test::run as seL4_Word; }}
-    #[cfg(not(feature = \"test\"))]
-    {{ regs.rip = {}::run as seL4_Word; }}",
-                    self.package_module_name, self.package_module_name,
-                )?;
+                writeln!(self.writer, "    regs.rip = entry;")?;
                 writeln!(self.writer, "    regs.rsp = stack_top as seL4_Word;")?;
             }
-            Arch::Armv7 => {
-                writeln!(
-                    self.writer,
-                    "    #[cfg(feature = \"test\")]
-    {{ regs.pc = {}::fel4_test::run as seL4_Word; }}
-    #[cfg(not(feature = \"test\"))]
-    {{ regs.pc = {}::run as seL4_Word; }}",
-                    self.package_module_name, self.package_module_name
-                )?;
-                writeln!(self.writer, "    regs.sp = stack_top as seL4_Word;")?;
-            }
-            Arch::Aarch64 => {
-                writeln!(
-                    self.writer,
-                    "    #[cfg(feature = \"test\")]
-    {{ regs.pc = {}::fel4_test::run as seL4_Word; }}
-    #[cfg(not(feature = \"test\"))]
-    {{ regs.pc = {}::run as seL4_Word; }}",
-                    self.package_module_name, self.package_module_name
-                )?;
+            Arch::Armv7 | Arch::Aarch64 => {
+                writeln!(self.writer, "    regs.pc = entry;")?;
                 writeln!(self.writer, "    regs.sp = stack_top as seL4_Word;")?;
             }
         }
@@ -205,21 +664,196 @@ fn main() {
     let _: u32 =
         unsafe { seL4_TCB_WriteRegisters(tcb_cap, 0, 0, 2, &mut regs) };
     let _: u32 = unsafe {
-        seL4_TCB_SetPriority(tcb_cap, seL4_CapInitThreadTCB.into(), 255)
+        seL4_TCB_SetMCPriority(
+            tcb_cap,
+            seL4_CapInitThreadTCB.into(),
+            max_controlled_priority,
+        )
+    };
+    let _: u32 = unsafe {
+        seL4_TCB_SetPriority(tcb_cap, seL4_CapInitThreadTCB.into(), priority)
     };
     let _: u32 = unsafe { seL4_TCB_Resume(tcb_cap) };
+    ComponentThread {
+        name,
+        tcb_cap,
+        canary,
+        overflowed: false,
+    }
+}
+",
+        )?;
+        Ok(())
+    }
+
+    fn generate_main(&mut self) -> Result<(), Error> {
+        self.writer.write_all(
+            b"
+fn main() {
+    let bootinfo = unsafe { &*BOOTINFO };
+",
+        )?;
+        if self.allocator_needs_init() {
+            self.writer.write_all(
+                b"    #[cfg(feature = \"alloc\")]
+    let first_free_slot = init_heap(bootinfo, bootinfo.empty.start);
+    #[cfg(not(feature = \"alloc\"))]
+    let first_free_slot = bootinfo.empty.start;\n",
+            )?;
+        } else {
+            self.writer
+                .write_all(b"    let first_free_slot = bootinfo.empty.start;\n")?;
+        }
+        self.writer.write_all(
+            b"    let fault_ep = create_fault_endpoint(bootinfo, first_free_slot);\n",
+        )?;
+        writeln!(
+            self.writer,
+            "    #[cfg(feature = \"test\")]
+    let mut threads = [spawn_component(
+        bootinfo,
+        \"fel4_test\",
+        first_free_slot + 1,
+        fault_ep,
+        {pkg}::fel4_test::run as seL4_Word,
+        unsafe {{ &mut TEST_STACK }},
+        {priority},
+        {mcp},
+    )];
+    #[cfg(feature = \"test\")]
+    let highest_priority: seL4_Word = {priority};",
+            pkg = self.package_module_name,
+            priority = self.test_thread.priority,
+            mcp = self.test_thread.max_controlled_priority,
+        )?;
+        writeln!(
+            self.writer,
+            "    #[cfg(not(feature = \"test\"))]
+    let mut threads = ["
+        )?;
+        for (i, c) in self.components.iter().enumerate() {
+            writeln!(
+                self.writer,
+                "        spawn_component(
+            bootinfo,
+            \"{name}\",
+            first_free_slot + {slot},
+            fault_ep,
+            COMPONENT_{i}_ENTRY as seL4_Word,
+            unsafe {{ &mut COMPONENT_{i}_STACK }},
+            {priority},
+            {mcp},
+        ),",
+                name = c.name,
+                i = i,
+                slot = i + 1,
+                priority = c.thread.priority,
+                mcp = c.thread.max_controlled_priority,
+            )?;
+        }
+        let highest_priority = self
+            .components
+            .iter()
+            .map(|c| c.thread.priority)
+            .max()
+            .unwrap_or(DEFAULT_PRIORITY);
+        writeln!(
+            self.writer,
+            "    ];
+    #[cfg(not(feature = \"test\"))]
+    let highest_priority: seL4_Word = {};",
+            highest_priority
+        )?;
+        self.writer.write_all(
+            b"
+    // Wait for faults at the highest component priority, so that no busy
+    // component can keep the root task from checking the stacks
+    let _: u32 = unsafe {
+        seL4_TCB_SetPriority(
+            seL4_CapInitThreadTCB.into(),
+            seL4_CapInitThreadTCB.into(),
+            highest_priority,
+        )
+    };
     loop {
-        unsafe {
-            seL4_Yield();
+        let mut badge: seL4_Word = 0;
+        let _ = unsafe { seL4_Recv(fault_ep, &mut badge) };
+        let mut overflowed = false;
+        for thread in threads.iter_mut() {
+            overflowed |= thread.check_stack_canary();
+        }
+        // The faulting thread stays blocked, as the fault is never replied to
+        if !overflowed {
+            #[cfg(feature = \"KernelPrinting\")]
+            {
+                use core::fmt::Write;
+                let _ = write!(
+                    sel4_sys::DebugOutHandle,
+                    \"----- a component thread faulted -----\\n\"
+                );
+            }
         }
     }
 }
-        ",
+",
         )?;
         Ok(())
     }
 }
 
+const BUMP_ALLOCATOR_CODE: &str = r##"
+/// Hands out memory from the heap in increasing addresses, and never
+/// reclaims it
+#[cfg(feature = "alloc")]
+struct BumpAllocator {
+    next: core::sync::atomic::AtomicUsize,
+    end: core::sync::atomic::AtomicUsize,
+}
+
+#[cfg(feature = "alloc")]
+impl BumpAllocator {
+    fn init(&self, start: usize, size: usize) {
+        use core::sync::atomic::Ordering;
+        self.next.store(start, Ordering::SeqCst);
+        self.end.store(start + size, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "alloc")]
+unsafe impl core::alloc::GlobalAlloc for BumpAllocator {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        use core::sync::atomic::Ordering;
+        loop {
+            let current = self.next.load(Ordering::SeqCst);
+            let start = match current.checked_add(layout.align() - 1) {
+                Some(padded) => padded & !(layout.align() - 1),
+                None => return ptr::null_mut(),
+            };
+            let end = match start.checked_add(layout.size()) {
+                Some(end) if end <= self.end.load(Ordering::SeqCst) => end,
+                _ => return ptr::null_mut(),
+            };
+            if self
+                .next
+                .compare_exchange(current, end, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
+                return start as *mut u8;
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {}
+}
+
+#[cfg(feature = "alloc")]
+#[global_allocator]
+static ALLOCATOR: BumpAllocator = BumpAllocator {
+    next: core::sync::atomic::AtomicUsize::new(0),
+    end: core::sync::atomic::AtomicUsize::new(0),
+};
+"##;
+
 const BOOT_INFO_AND_LANG_ITEM_CODE: &str = r##"
 pub static mut BOOTINFO: *mut seL4_BootInfo = (0 as *mut seL4_BootInfo);
 static mut RUN_ONCE: bool = false;
@@ -323,3 +957,153 @@ pub extern "C" fn oom() -> ! {
     }
 }
 "##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(name: &str, entry: &str) -> ComponentSettings {
+        ComponentSettings {
+            name: name.to_string(),
+            entry: entry.to_string(),
+            stack_size: None,
+            priority: None,
+            max_controlled_priority: None,
+        }
+    }
+
+    #[test]
+    fn default_component_runs_run() {
+        let defaults = resolve_thread_config(&ThreadSettings::default()).unwrap();
+        let components = resolve_components(&[], &defaults).unwrap();
+        assert_eq!(1, components.len());
+        assert_eq!("run", components[0].entry);
+    }
+
+    #[test]
+    fn component_validation() {
+        let defaults = resolve_thread_config(&ThreadSettings::default()).unwrap();
+        assert!(resolve_components(&[component("net", "net::run")], &defaults).is_ok());
+        assert!(resolve_components(&[component("net", "net::")], &defaults).is_err());
+        assert!(resolve_components(&[component("net", "0run")], &defaults).is_err());
+        let duplicates = [component("a", "run"), component("a", "run")];
+        assert!(resolve_components(&duplicates, &defaults).is_err());
+        let mut odd_stack = component("a", "run");
+        odd_stack.stack_size = Some(100);
+        assert!(resolve_components(&[odd_stack], &defaults).is_err());
+    }
+
+    #[test]
+    fn allocator_validation() {
+        let defaults = resolve_allocator(&AllocatorSettings::default()).unwrap();
+        assert_eq!(AllocatorKind::WeeAlloc, defaults.kind);
+        assert_eq!(DEFAULT_HEAP_SIZE, defaults.heap_size);
+        assert_eq!(Some("wee_alloc"), defaults.dependency_feature());
+
+        let untyped = AllocatorSettings {
+            kind: Some(AllocatorKind::LinkedList),
+            heap_size: Some(5000),
+            backing: Some(HeapBacking::Untyped),
+        };
+        assert_eq!(8192, resolve_allocator(&untyped).unwrap().heap_size);
+
+        let wee_untyped = AllocatorSettings {
+            kind: Some(AllocatorKind::WeeAlloc),
+            heap_size: None,
+            backing: Some(HeapBacking::Untyped),
+        };
+        assert!(resolve_allocator(&wee_untyped).is_err());
+        let empty = AllocatorSettings {
+            kind: Some(AllocatorKind::Bump),
+            heap_size: Some(0),
+            backing: None,
+        };
+        assert!(resolve_allocator(&empty).is_err());
+        let oversized = AllocatorSettings {
+            kind: Some(AllocatorKind::Bump),
+            heap_size: Some(UNTYPED_HEAP_LIMIT),
+            backing: Some(HeapBacking::Untyped),
+        };
+        assert!(resolve_allocator(&oversized).is_err());
+    }
+
+    fn generate(arch: &Arch, settings: &AllocatorSettings) -> String {
+        let defaults = resolve_thread_config(&ThreadSettings::default()).unwrap();
+        let components = resolve_components(&[], &defaults).unwrap();
+        let allocator = resolve_allocator(settings).unwrap();
+        let mut out = Vec::new();
+        Generator::new(
+            &mut out,
+            "foo",
+            arch,
+            &[],
+            &components,
+            &defaults,
+            &allocator,
+        ).generate()
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn linked_list_static_heap() {
+        let source = generate(
+            &Arch::X86_64,
+            &AllocatorSettings {
+                kind: Some(AllocatorKind::LinkedList),
+                heap_size: Some(65536),
+                backing: None,
+            },
+        );
+        assert!(source.contains("extern crate linked_list_allocator;"));
+        assert!(source.contains("static ALLOCATOR: linked_list_allocator::LockedHeap"));
+        assert!(source.contains("const HEAP_SIZE: usize = 65536;"));
+        assert!(source.contains("static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];"));
+        assert!(source.contains("unsafe { ALLOCATOR.lock().init(heap_start, HEAP_SIZE) };"));
+        assert!(!source.contains("fn retype_any_untyped"));
+        assert!(source.contains("init_heap(bootinfo, bootinfo.empty.start)"));
+    }
+
+    #[test]
+    fn untyped_heap() {
+        let settings = AllocatorSettings {
+            kind: Some(AllocatorKind::LinkedList),
+            heap_size: Some(5000),
+            backing: Some(HeapBacking::Untyped),
+        };
+        for &(ref arch, page_object, page_map) in &[
+            (Arch::X86_64, "_object_seL4_X86_4K", "seL4_X86_Page_Map("),
+            (
+                Arch::Armv7,
+                "_object_seL4_ARM_SmallPageObject",
+                "seL4_ARM_Page_Map(",
+            ),
+        ] {
+            let source = generate(arch, &settings);
+            assert!(source.contains("const HEAP_SIZE: usize = 8192;"));
+            assert!(source.contains("const HEAP_VADDR: usize = 0x10000000;"));
+            assert!(source.contains(&format!("const PAGE_OBJECT: seL4_Word = {}", page_object)));
+            assert!(source.contains(page_map));
+            assert!(!source.contains("static mut HEAP"));
+            // The slots are checked before anything is retyped into them
+            let slot_check = source
+                .find("Ran out of CSpace slots while creating the heap")
+                .unwrap();
+            assert!(slot_check < source.find("seL4_Untyped_Retype(").unwrap());
+            // and the image layout before any page is mapped
+            let layout_check = source.find("past the heap at").unwrap();
+            let init_heap = source.find("fn init_heap").unwrap();
+            assert!(init_heap < layout_check);
+            assert!(layout_check < source[init_heap..].find("map_page(").unwrap() + init_heap);
+            assert!(source.contains("unsafe { ALLOCATOR.lock().init(heap_start, HEAP_SIZE) };"));
+        }
+    }
+
+    #[test]
+    fn wee_alloc_has_no_heap_init() {
+        let source = generate(&Arch::Armv7, &AllocatorSettings::default());
+        assert!(source.contains("extern crate wee_alloc;"));
+        assert!(!source.contains("const HEAP_SIZE"));
+        assert!(!source.contains("init_heap("));
+    }
+}
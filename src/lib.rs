@@ -2,42 +2,78 @@ extern crate cargo_metadata;
 extern crate cmake_config;
 extern crate colored;
 extern crate fel4_config;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate structopt;
+extern crate toml;
 
 use colored::Colorize;
 use std::fmt;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 mod build_cmd;
+mod builder;
 mod clean_cmd;
 mod cmake_codegen;
 mod command_ext;
 mod config;
+mod config_cmd;
+mod debug_cmd;
 mod deploy_cmd;
+mod elf;
+mod fingerprint;
 mod generator;
+mod machine_message;
 mod new_cmd;
+mod qemu;
+mod root_task_crate;
+mod rustflags;
+mod settings;
 mod simulate_cmd;
 mod test_cmd;
+mod test_report;
+mod uboot;
+mod verify_cmd;
 
 pub use build_cmd::handle_build_cmd;
 pub use clean_cmd::handle_clean_cmd;
 pub use config::{
-    BuildCmd, CargoFel4Cli, CleanCmd, Fel4SubCmd, LoudnessOpts, NewCmd, ResolvedConfig,
-    SimulateCmd, TestCmd, TestSubCmd,
+    BuildCmd, BuilderBackend, CargoFel4Cli, CleanCmd, ConfigCmd, ConfigFormat, ConfigSubCmd,
+    DebugCmd, Fel4BuildProfile, Fel4SubCmd, LoudnessOpts, MessageFormat, NewCmd, ResolvedConfig,
+    SimulateCmd, TestCmd, TestSubCmd, VerifyCmd,
 };
+pub use config_cmd::handle_config_cmd;
+pub use debug_cmd::handle_debug_cmd;
 pub use deploy_cmd::handle_deploy_cmd;
 pub use new_cmd::handle_new_cmd;
 pub use simulate_cmd::handle_simulate_cmd;
 pub use test_cmd::handle_test_cmd;
+pub use verify_cmd::handle_verify_cmd;
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Error {
     ConfigError(String),
     IO(String),
     ExitStatusError(String),
+    /// The simulated application asked to exit with a non-zero code
+    GuestExit(i32),
+}
+
+impl Error {
+    /// The exit status cargo-fel4 reports for this error
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::GuestExit(code) => code,
+            _ => 1,
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -57,6 +93,7 @@ impl fmt::Display for Error {
         match self {
             Error::IO(msg) => write!(f, "[IO error] {}", msg),
             Error::ExitStatusError(msg) => write!(f, "[command error] {}", msg),
+            Error::GuestExit(code) => write!(f, "[guest exit] the application exited with {}", code),
             Error::ConfigError(msg) => write!(
                 f,
                 "[config error] {}\n\nCheck your project's toml files for invalid syntax",
@@ -68,6 +105,16 @@ impl fmt::Display for Error {
 
 pub struct Logger;
 
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+impl Logger {
+    /// Send the log output to stderr rather than stdout, keeping stdout free
+    /// for machine-readable messages
+    pub fn log_to_stderr(enabled: bool) {
+        LOG_TO_STDERR.store(enabled, Ordering::SeqCst);
+    }
+}
+
 impl log::Log for Logger {
     fn enabled(&self, metadata: &log::Metadata) -> bool {
         metadata.level() <= log::Level::Info
@@ -77,16 +124,17 @@ impl log::Log for Logger {
     /// Info is colored bright-green
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            println!(
-                "{}: {}",
-                match record.level() {
-                    log::Level::Error => "error".red(),
-                    log::Level::Warn => "warn".bright_yellow(),
-                    log::Level::Info => "info".bright_green(),
-                    l => l.to_string().to_lowercase().normal(),
-                },
-                record.args()
-            );
+            let level = match record.level() {
+                log::Level::Error => "error".red(),
+                log::Level::Warn => "warn".bright_yellow(),
+                log::Level::Info => "info".bright_green(),
+                l => l.to_string().to_lowercase().normal(),
+            };
+            if LOG_TO_STDERR.load(Ordering::SeqCst) {
+                eprintln!("{}: {}", level, record.args());
+            } else {
+                println!("{}: {}", level, record.args());
+            }
         }
     }
 
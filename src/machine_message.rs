@@ -0,0 +1,178 @@
+use libc;
+use serde_json;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use super::Error;
+use command_ext::CommandExt;
+use config::MessageFormat;
+
+/// Structured build events, emitted as one JSON object per line when
+/// `--message-format=json` is requested.
+///
+/// Modeled on cargo's own JSON messages: every object carries a `reason`
+/// field naming the event, followed by the event-specific fields.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum BuildMessage<'a> {
+    ConfigResolved {
+        package: &'a str,
+        target: &'a str,
+        platform: &'a str,
+        profile: &'a str,
+        artifact_path: &'a Path,
+    },
+    RootTaskGenerated {
+        path: &'a Path,
+        /// Whether the existing file already held the generated source
+        fresh: bool,
+    },
+    InvocationStarted {
+        step: BuildStep,
+        command: String,
+    },
+    /// A step skipped because its inputs are unchanged since the last build
+    InvocationFresh {
+        step: BuildStep,
+    },
+    InvocationFinished {
+        step: BuildStep,
+        success: bool,
+        exit_code: Option<i32>,
+    },
+    CmakeCacheCheck {
+        consistent: bool,
+        mismatches: Vec<FlagMismatch>,
+    },
+    BuildFinished {
+        artifact_path: &'a Path,
+        kernel: &'a Path,
+        fel4img: &'a Path,
+    },
+}
+
+/// The external tool invocations made during a build
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildStep {
+    RootTask,
+    Libsel4SysRebuild,
+}
+
+/// A fel4.toml property whose value was not reflected in seL4's
+/// CMakeCache.txt
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FlagMismatch {
+    pub key: String,
+    pub fel4_value: String,
+    /// The value found under the same key in CMakeCache.txt, if any
+    pub cmake_value: Option<String>,
+}
+
+/// Print the message as a single line of JSON, if requested by the format
+pub fn emit(format: MessageFormat, message: &BuildMessage) -> Result<(), Error> {
+    if format == MessageFormat::Json {
+        let line = serde_json::to_string(message)
+            .map_err(|e| Error::IO(format!("Could not serialize a build message: {}", e)))?;
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// A handle on our stderr, for a child process to write its stdout to
+fn stderr_as_stdio() -> Result<Stdio, Error> {
+    let fd = unsafe { libc::dup(libc::STDERR_FILENO) };
+    if fd < 0 {
+        return Err(Error::IO(
+            "Could not duplicate the stderr file descriptor".to_string(),
+        ));
+    }
+    Ok(unsafe { Stdio::from_raw_fd(fd) })
+}
+
+/// Run a build step command, bracketing it with invocation started/finished
+/// messages. In the JSON format the command's stdout goes to stderr, keeping
+/// stdout to the messages alone.
+pub fn run_step_cmd(
+    cmd: &mut Command,
+    step: BuildStep,
+    format: MessageFormat,
+) -> Result<(), Error> {
+    emit(
+        format,
+        &BuildMessage::InvocationStarted {
+            step,
+            command: format!("{:?}", cmd),
+        },
+    )?;
+    if format == MessageFormat::Json {
+        cmd.stdout(stderr_as_stdio()?);
+    }
+    let status = cmd.status_cmd()?;
+    emit(
+        format,
+        &BuildMessage::InvocationFinished {
+            step,
+            success: status.success(),
+            exit_code: status.code(),
+        },
+    )?;
+    if !status.success() {
+        return Err(Error::ExitStatusError(format!(
+            "command status returned: {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_tagged_with_their_reason() {
+        let path = Path::new("artifacts/debug");
+        assert_eq!(
+            r#"{"reason":"config-resolved","package":"app","target":"x86_64-sel4-fel4","platform":"pc99","profile":"debug","artifact_path":"artifacts/debug"}"#,
+            serde_json::to_string(&BuildMessage::ConfigResolved {
+                package: "app",
+                target: "x86_64-sel4-fel4",
+                platform: "pc99",
+                profile: "debug",
+                artifact_path: path,
+            }).unwrap()
+        );
+        assert_eq!(
+            r#"{"reason":"invocation-fresh","step":"libsel4-sys-rebuild"}"#,
+            serde_json::to_string(&BuildMessage::InvocationFresh {
+                step: BuildStep::Libsel4SysRebuild,
+            }).unwrap()
+        );
+        assert_eq!(
+            r#"{"reason":"invocation-finished","step":"root-task","success":false,"exit_code":null}"#,
+            serde_json::to_string(&BuildMessage::InvocationFinished {
+                step: BuildStep::RootTask,
+                success: false,
+                exit_code: None,
+            }).unwrap()
+        );
+    }
+
+    #[test]
+    fn mismatches_are_serialized_in_full() {
+        let message = BuildMessage::CmakeCacheCheck {
+            consistent: false,
+            mismatches: vec![FlagMismatch {
+                key: "KernelPrinting".to_string(),
+                fel4_value: "ON".to_string(),
+                cmake_value: Some("OFF".to_string()),
+            }],
+        };
+        assert_eq!(
+            r#"{"reason":"cmake-cache-check","consistent":false,"mismatches":[{"key":"KernelPrinting","fel4_value":"ON","cmake_value":"OFF"}]}"#,
+            serde_json::to_string(&message).unwrap()
+        );
+    }
+}
@@ -3,8 +3,9 @@ extern crate structopt;
 extern crate log;
 extern crate cargo_fel4;
 
-use cargo_fel4::{CargoFel4Cli, Fel4SubCmd, Logger, LoudnessOpts};
+use cargo_fel4::{CargoFel4Cli, Fel4SubCmd, Logger, LoudnessOpts, MessageFormat};
 use log::LevelFilter;
+use std::process;
 use structopt::StructOpt;
 
 static LOGGER: Logger = Logger;
@@ -16,43 +17,48 @@ fn main() {
     };
     let CargoFel4Cli::Fel4SubCmd(subcmd) = CargoFel4Cli::from_args();
 
-    match subcmd {
+    let result = match subcmd {
         Fel4SubCmd::BuildCmd(c) => {
             set_logging_level(&c.loudness);
-            if let Err(e) = cargo_fel4::handle_build_cmd(&c) {
-                error!("Failed to run the build command\n{}", e)
-            }
+            Logger::log_to_stderr(c.message_format == MessageFormat::Json);
+            cargo_fel4::handle_build_cmd(&c).map_err(|e| ("build", e))
         }
         Fel4SubCmd::SimulateCmd(c) => {
             set_logging_level(&c.loudness);
-            if let Err(e) = cargo_fel4::handle_simulate_cmd(&c) {
-                error!("Failed to run the simulation command\n{}", e)
-            }
+            cargo_fel4::handle_simulate_cmd(&c).map_err(|e| ("simulation", e))
         }
         Fel4SubCmd::DeployCmd(c) => {
             set_logging_level(&c.loudness);
-            if let Err(e) = cargo_fel4::handle_deploy_cmd(&c) {
-                error!("Failed to run the deploy command\n{}", e)
-            }
+            cargo_fel4::handle_deploy_cmd(&c).map_err(|e| ("deploy", e))
         }
         Fel4SubCmd::NewCmd(c) => {
             set_logging_level(&c.loudness);
-            if let Err(e) = cargo_fel4::handle_new_cmd(&c) {
-                error!("Failed to run the new command\n{}", e)
-            }
+            cargo_fel4::handle_new_cmd(&c).map_err(|e| ("new", e))
         }
         Fel4SubCmd::TestCmd(c) => {
             set_logging_level(&c.loudness);
-            if let Err(e) = cargo_fel4::handle_test_cmd(&c) {
-                error!("Failed to run the test command\n{}", e)
-            }
+            cargo_fel4::handle_test_cmd(&c).map_err(|e| ("test", e))
         }
         Fel4SubCmd::CleanCmd(c) => {
             set_logging_level(&c.loudness);
-            if let Err(e) = cargo_fel4::handle_clean_cmd(&c) {
-                error!("Failed to run the clean command\n{}", e)
-            }
+            cargo_fel4::handle_clean_cmd(&c).map_err(|e| ("clean", e))
         }
+        Fel4SubCmd::DebugCmd(c) => {
+            set_logging_level(&c.loudness);
+            cargo_fel4::handle_debug_cmd(&c).map_err(|e| ("debug", e))
+        }
+        Fel4SubCmd::ConfigCmd(c) => {
+            set_logging_level(&c.loudness);
+            cargo_fel4::handle_config_cmd(&c).map_err(|e| ("config", e))
+        }
+        Fel4SubCmd::VerifyCmd(c) => {
+            set_logging_level(&c.loudness);
+            cargo_fel4::handle_verify_cmd(&c).map_err(|e| ("verify", e))
+        }
+    };
+    if let Err((command, e)) = result {
+        error!("Failed to run the {} command\n{}", command, e);
+        process::exit(e.exit_code());
     }
 }
 
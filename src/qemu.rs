@@ -0,0 +1,205 @@
+use fel4_config::{SupportedPlatform, SupportedTarget};
+use std::path::Path;
+use std::process::Command;
+
+use super::Error;
+use settings::SimulationSettings;
+
+/// The port of the QEMU gdbstub when none is given
+pub const DEFAULT_GDB_PORT: u16 = 1234;
+
+/// The QEMU command line that simulates a feL4 application
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QemuInvocation {
+    pub qemu: String,
+    pub args: Vec<String>,
+}
+
+/// The QEMU defaults for a target and platform, matching those of the seL4
+/// simulation scripts
+struct QemuDefaults {
+    qemu: &'static str,
+    machine: Option<&'static str>,
+    cpu: Option<&'static str>,
+    memory: &'static str,
+    /// Arguments selecting the console
+    serial: &'static [&'static str],
+    /// Arguments that let the guest end the simulation with an exit code of
+    /// its choosing
+    guest_exit: &'static [&'static str],
+}
+
+fn qemu_defaults(
+    target: &SupportedTarget,
+    platform: &SupportedPlatform,
+) -> Result<QemuDefaults, Error> {
+    match *target {
+        SupportedTarget::X8664Sel4Fel4 if *platform == SupportedPlatform::PC99 => {
+            Ok(QemuDefaults {
+                qemu: "qemu-system-x86_64",
+                machine: None,
+                cpu: Some("Nehalem,-vme,+pdpe1gb,-xsave,-xsaveopt,-xsavec,-fsgsbase,-invpcid,enforce"),
+                memory: "512M",
+                serial: &["-serial", "mon:stdio"],
+                guest_exit: &["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"],
+            })
+        }
+        SupportedTarget::Armv7Sel4Fel4 if *platform == SupportedPlatform::Sabre => {
+            Ok(QemuDefaults {
+                qemu: "qemu-system-arm",
+                machine: Some("sabrelite"),
+                cpu: None,
+                memory: "1024M",
+                // The console is on the second UART of the i.MX6
+                serial: &["-serial", "null", "-serial", "mon:stdio"],
+                guest_exit: &["-semihosting"],
+            })
+        }
+        _ => Err(Error::ConfigError(format!(
+            "The selected {} platform does not support simulation for the {} target",
+            platform.full_name(),
+            target.full_name()
+        ))),
+    }
+}
+
+impl QemuInvocation {
+    /// Derive the QEMU command line from the target and platform, with the
+    /// images found in the artifact path and the fel4.toml overrides applied
+    pub fn resolve<P: AsRef<Path>>(
+        target: &SupportedTarget,
+        platform: &SupportedPlatform,
+        artifact_path: P,
+        settings: &SimulationSettings,
+    ) -> Result<QemuInvocation, Error> {
+        let defaults = qemu_defaults(target, platform)?;
+        let artifact_path = artifact_path.as_ref();
+        let mut args: Vec<String> = Vec::new();
+        if let Some(machine) = settings.machine.as_ref().map(String::as_str).or(defaults.machine) {
+            args.push("-machine".to_string());
+            args.push(machine.to_string());
+        }
+        if let Some(cpu) = settings.cpu.as_ref().map(String::as_str).or(defaults.cpu) {
+            args.push("-cpu".to_string());
+            args.push(cpu.to_string());
+        }
+        args.push("-nographic".to_string());
+        args.push("-m".to_string());
+        args.push(format!(
+            "size={}",
+            settings
+                .memory
+                .as_ref()
+                .map(String::as_str)
+                .unwrap_or(defaults.memory)
+        ));
+        args.extend(defaults.serial.iter().map(|a| a.to_string()));
+        match *target {
+            // The kernel is booted by multiboot, with the root task as a module
+            SupportedTarget::X8664Sel4Fel4 => {
+                args.push("-kernel".to_string());
+                args.push(image_path(artifact_path, "kernel")?);
+                args.push("-initrd".to_string());
+                args.push(image_path(artifact_path, "feL4img")?);
+            }
+            // The elfloader image contains both the kernel and the root task
+            _ => {
+                args.push("-kernel".to_string());
+                args.push(image_path(artifact_path, "feL4img")?);
+            }
+        }
+        args.extend(defaults.guest_exit.iter().map(|a| a.to_string()));
+        args.extend(settings.extra_args.iter().cloned());
+        Ok(QemuInvocation {
+            qemu: settings
+                .qemu
+                .clone()
+                .unwrap_or_else(|| defaults.qemu.to_string()),
+            args,
+        })
+    }
+
+    /// Start the guest halted, with a gdbstub listening on the given port
+    pub fn with_gdbstub(mut self, port: u16) -> QemuInvocation {
+        self.args.push("-gdb".to_string());
+        self.args.push(format!("tcp::{}", port));
+        self.args.push("-S".to_string());
+        self
+    }
+
+    pub fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.qemu);
+        cmd.args(&self.args);
+        cmd
+    }
+}
+
+fn image_path(artifact_path: &Path, name: &str) -> Result<String, Error> {
+    let path = artifact_path.join(name);
+    if !path.exists() {
+        return Err(Error::ConfigError(format!(
+            "Something went wrong with the build, cannot find the image '{}'",
+            path.display()
+        )));
+    }
+    Ok(path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn x86_64_invocation_with_overrides() {
+        let artifacts = tempfile::tempdir().unwrap();
+        File::create(artifacts.path().join("kernel")).unwrap();
+        File::create(artifacts.path().join("feL4img")).unwrap();
+        let settings = SimulationSettings {
+            memory: Some("1G".to_string()),
+            extra_args: vec!["-smp".to_string(), "2".to_string()],
+            ..SimulationSettings::default()
+        };
+        let invocation = QemuInvocation::resolve(
+            &SupportedTarget::X8664Sel4Fel4,
+            &SupportedPlatform::PC99,
+            artifacts.path(),
+            &settings,
+        ).unwrap();
+        assert_eq!("qemu-system-x86_64", invocation.qemu);
+        assert!(invocation.args.contains(&"size=1G".to_string()));
+        assert!(
+            invocation
+                .args
+                .contains(&artifacts.path().join("feL4img").display().to_string())
+        );
+        assert_eq!(
+            &["-smp".to_string(), "2".to_string()],
+            &invocation.args[invocation.args.len() - 2..]
+        );
+    }
+
+    #[test]
+    fn missing_images_and_unsupported_platforms() {
+        let artifacts = tempfile::tempdir().unwrap();
+        assert!(
+            QemuInvocation::resolve(
+                &SupportedTarget::Armv7Sel4Fel4,
+                &SupportedPlatform::Sabre,
+                artifacts.path(),
+                &SimulationSettings::default(),
+            ).is_err()
+        );
+        File::create(artifacts.path().join("feL4img")).unwrap();
+        assert!(
+            QemuInvocation::resolve(
+                &SupportedTarget::Aarch64Sel4Fel4,
+                &SupportedPlatform::Tx1,
+                artifacts.path(),
+                &SimulationSettings::default(),
+            ).is_err()
+        );
+    }
+}
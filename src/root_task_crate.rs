@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use toml::{self, Value};
+
+use super::Error;
+use fingerprint::{find_lockfile, write_if_changed};
+
+/// The dependencies of the generated root task besides the user library,
+/// taken over from the user's Cargo.toml
+const ROOT_TASK_DEPENDENCIES: &[&str] = &[
+    "libsel4-sys",
+    "wee_alloc",
+    "linked_list_allocator",
+    "proptest",
+];
+
+/// Records the heap size wee_alloc was built with, in the helper crate
+const WEE_ALLOC_HEAP_SIZE_FILE_NAME: &str = ".wee-alloc-heap-size";
+/// The user's lockfile as last copied into the helper crate
+const USER_LOCKFILE_FILE_NAME: &str = ".user-Cargo.lock";
+
+/// The helper crate cargo-fel4 generates to hold the root task binary,
+/// which depends on the user library by path so that the user's source
+/// tree is left alone
+#[derive(Clone, Debug, PartialEq)]
+pub struct RootTaskCrate {
+    pub dir: PathBuf,
+    pub manifest_path: PathBuf,
+    pub source_path: PathBuf,
+    pub lockfile_path: PathBuf,
+}
+
+impl RootTaskCrate {
+    /// The location of the helper crate of a package, under
+    /// `<target dir>/fel4/<package name>`
+    pub fn new(target_dir: &Path, pkg_name: &str) -> RootTaskCrate {
+        let dir = target_dir.join("fel4").join(pkg_name);
+        RootTaskCrate {
+            manifest_path: dir.join("Cargo.toml"),
+            source_path: dir.join("src").join("main.rs"),
+            lockfile_path: dir.join("Cargo.lock"),
+            dir,
+        }
+    }
+
+    /// Write the helper crate, returning whether the root task source
+    /// changed. Unchanged files are not rewritten, so that cargo does not
+    /// rebuild the root task needlessly.
+    pub fn write(
+        &self,
+        pkg_name: &str,
+        user_manifest_path: &Path,
+        allocator_dependency: Option<&str>,
+        root_task_source: &[u8],
+    ) -> Result<bool, Error> {
+        let mut user_manifest = String::new();
+        File::open(user_manifest_path)
+            .and_then(|mut f| f.read_to_string(&mut user_manifest))
+            .map_err(|e| {
+                Error::IO(format!(
+                    "Could not read {}: {}",
+                    user_manifest_path.display(),
+                    e
+                ))
+            })?;
+        let user_root_dir = user_manifest_path.parent().unwrap_or_else(|| Path::new("."));
+        let manifest = root_task_manifest(
+            pkg_name,
+            user_root_dir,
+            allocator_dependency,
+            &user_manifest,
+        )?;
+
+        let source_dir = self.dir.join("src");
+        fs::create_dir_all(&source_dir).map_err(|e| {
+            Error::IO(format!(
+                "Difficulty creating directory, {:?} : {}",
+                &source_dir, e
+            ))
+        })?;
+        write_if_changed(&self.manifest_path, manifest.as_bytes())?;
+        // Take over the user's lockfile whenever it changes, e.g. after a
+        // `cargo update`, so that the root task resolves the same dependency
+        // revisions (libsel4-sys included) as the ARM libsel4-sys rebuild,
+        // which goes through the user's manifest. In between, cargo is left
+        // to add the entries of the helper crate itself.
+        if let Some(user_lockfile_path) = find_lockfile(user_root_dir) {
+            let user_lockfile = fs::read(&user_lockfile_path).map_err(|e| {
+                Error::IO(format!(
+                    "Could not read {}: {}",
+                    user_lockfile_path.display(),
+                    e
+                ))
+            })?;
+            let user_lockfile_changed =
+                write_if_changed(&self.dir.join(USER_LOCKFILE_FILE_NAME), &user_lockfile)?;
+            if user_lockfile_changed || !self.lockfile_path.exists() {
+                fs::write(&self.lockfile_path, &user_lockfile).map_err(|e| {
+                    Error::IO(format!(
+                        "Could not write '{}': {}",
+                        self.lockfile_path.display(),
+                        e
+                    ))
+                })?;
+            }
+        }
+        write_if_changed(&self.source_path, root_task_source)
+    }
+
+    /// Record the heap size wee_alloc is built with, returning the size it
+    /// was previously built with when that differs. wee_alloc reads the size
+    /// in its build script, which cargo does not rerun when only the size
+    /// changes, so the record is kept until the target directory is cleaned.
+    pub fn record_wee_alloc_heap_size(&self, heap_size: usize) -> Result<Option<usize>, Error> {
+        let path = self.dir.join(WEE_ALLOC_HEAP_SIZE_FILE_NAME);
+        let previous = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| contents.trim().parse::<usize>().ok());
+        match previous {
+            Some(previous) if previous != heap_size => Ok(Some(previous)),
+            Some(_) => Ok(None),
+            None => {
+                fs::write(&path, heap_size.to_string()).map_err(|e| {
+                    Error::IO(format!("Could not write '{}': {}", path.display(), e))
+                })?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Render the Cargo.toml of the root task crate for the user package whose
+/// manifest is given, taking over the specifications of the root task
+/// dependencies and forwarding the features the user library declares.
+/// The allocator dependency, if any, is enabled by the `alloc` feature.
+pub fn root_task_manifest(
+    pkg_name: &str,
+    user_root_dir: &Path,
+    allocator_dependency: Option<&str>,
+    user_manifest: &str,
+) -> Result<String, Error> {
+    let user_manifest: Value = user_manifest
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("Invalid Cargo.toml: {}", e)))?;
+    let user_dependencies = user_manifest
+        .get("dependencies")
+        .and_then(Value::as_table)
+        .cloned()
+        .unwrap_or_default();
+    let user_features = user_manifest
+        .get("features")
+        .and_then(Value::as_table)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut dependencies = BTreeMap::new();
+    let mut user_library = BTreeMap::new();
+    user_library.insert(
+        "path".to_string(),
+        Value::String(user_root_dir.display().to_string()),
+    );
+    dependencies.insert(pkg_name.to_string(), Value::Table(user_library));
+    for name in ROOT_TASK_DEPENDENCIES {
+        if let Some(dependency) = user_dependencies.get(*name) {
+            let mut dependency = match *dependency {
+                Value::Table(ref table) => table.clone(),
+                ref version => {
+                    let mut table = BTreeMap::new();
+                    table.insert("version".to_string(), version.clone());
+                    table
+                }
+            };
+            if let Some(path) = dependency.get("path").and_then(Value::as_str).map(PathBuf::from) {
+                dependency.insert(
+                    "path".to_string(),
+                    Value::String(user_root_dir.join(path).display().to_string()),
+                );
+            }
+            // The allocators and proptest are selected through features
+            if *name != "libsel4-sys" {
+                dependency.insert("optional".to_string(), Value::Boolean(true));
+            }
+            dependencies.insert(name.to_string(), Value::Table(dependency));
+        }
+    }
+    if !dependencies.contains_key("libsel4-sys") {
+        return Err(Error::ConfigError(format!(
+            "The {} package does not depend on libsel4-sys",
+            pkg_name
+        )));
+    }
+
+    let forwarded = |feature: &str| {
+        if user_features.contains_key(feature) {
+            vec![Value::String(format!("{}/{}", pkg_name, feature))]
+        } else {
+            Vec::new()
+        }
+    };
+    let mut features = BTreeMap::new();
+    features.insert("default".to_string(), Value::Array(Vec::new()));
+    let mut alloc = forwarded("alloc");
+    if let Some(dependency) = allocator_dependency {
+        if !dependencies.contains_key(dependency) {
+            return Err(Error::ConfigError(format!(
+                "The selected allocator needs an optional {} dependency in the Cargo.toml of {}",
+                dependency, pkg_name
+            )));
+        }
+        alloc.push(Value::String(dependency.to_string()));
+    }
+    features.insert("alloc".to_string(), Value::Array(alloc));
+    let mut test = vec![Value::String("alloc".to_string())];
+    test.extend(forwarded("test"));
+    if dependencies.contains_key("proptest") {
+        test.push(Value::String("proptest".to_string()));
+    }
+    features.insert("test".to_string(), Value::Array(test));
+
+    let mut package = BTreeMap::new();
+    package.insert(
+        "name".to_string(),
+        Value::String(format!("{}-root-task", pkg_name)),
+    );
+    package.insert("version".to_string(), Value::String("0.1.0".to_string()));
+    package.insert("publish".to_string(), Value::Boolean(false));
+
+    let mut bin = BTreeMap::new();
+    bin.insert("name".to_string(), Value::String("root-task".to_string()));
+    bin.insert("path".to_string(), Value::String("src/main.rs".to_string()));
+
+    let mut manifest = BTreeMap::new();
+    manifest.insert("package".to_string(), Value::Table(package));
+    manifest.insert("bin".to_string(), Value::Array(vec![Value::Table(bin)]));
+    manifest.insert("dependencies".to_string(), Value::Table(dependencies));
+    manifest.insert("features".to_string(), Value::Table(features));
+    // Keep the crate out of any workspace the user package belongs to
+    manifest.insert("workspace".to_string(), Value::Table(BTreeMap::new()));
+    let rendered = toml::to_string(&Value::Table(manifest)).map_err(|e| {
+        Error::ConfigError(format!("Could not render the root task manifest: {}", e))
+    })?;
+    Ok(format!(
+        "# Generated by cargo-fel4, changes will be overwritten\n{}",
+        rendered
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    #[test]
+    fn manifest_from_new_project_template() {
+        let user_manifest = format!(
+            "[package]\nname = \"hello\"\nversion = \"0.1.0\"\n\n[dependencies]\n{}",
+            include_str!("../templates/Cargo.toml.part")
+        );
+        let manifest: Value = root_task_manifest(
+            "hello",
+            Path::new("/work/hello"),
+            Some("wee_alloc"),
+            &user_manifest,
+        ).unwrap()
+            .parse()
+            .unwrap();
+        let dependencies = manifest["dependencies"].as_table().unwrap();
+        assert_eq!(
+            Some("/work/hello"),
+            dependencies["hello"].get("path").and_then(Value::as_str)
+        );
+        assert!(dependencies["libsel4-sys"].get("git").is_some());
+        assert_eq!(
+            Some(true),
+            dependencies["wee_alloc"].get("optional").and_then(Value::as_bool)
+        );
+        assert_eq!(
+            &Value::Array(vec![
+                Value::String("hello/alloc".to_string()),
+                Value::String("wee_alloc".to_string()),
+            ]),
+            &manifest["features"]["alloc"]
+        );
+        assert_eq!(
+            &Value::Array(vec![
+                Value::String("alloc".to_string()),
+                Value::String("hello/test".to_string()),
+                Value::String("proptest".to_string()),
+            ]),
+            &manifest["features"]["test"]
+        );
+        assert_eq!("root-task", manifest["bin"][0]["name"].as_str().unwrap());
+    }
+
+    #[test]
+    fn manifest_requires_libsel4_sys() {
+        let user_manifest = "[package]\nname = \"hello\"\n[dependencies]\nlibc = \"0.2\"\n";
+        assert!(
+            root_task_manifest("hello", Path::new("/work/hello"), None, user_manifest).is_err()
+        );
+    }
+
+    #[test]
+    fn manifest_requires_allocator_dependency() {
+        let user_manifest = "[package]\nname = \"hello\"\n[dependencies]\nlibsel4-sys = \"0.1\"\n";
+        let path = Path::new("/work/hello");
+        assert!(root_task_manifest("hello", path, None, user_manifest).is_ok());
+        assert!(
+            root_task_manifest("hello", path, Some("linked_list_allocator"), user_manifest)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn user_lockfile_is_taken_over_when_it_changes() {
+        let user_dir = tempfile::tempdir().unwrap();
+        let user_manifest_path = user_dir.path().join("Cargo.toml");
+        fs::write(
+            &user_manifest_path,
+            "[package]\nname = \"hello\"\nversion = \"0.1.0\"\n\
+             [dependencies]\nlibsel4-sys = \"0.1\"\n",
+        ).unwrap();
+        let user_lockfile_path = user_dir.path().join("Cargo.lock");
+        let target_dir = tempfile::tempdir().unwrap();
+        let root_task_crate = RootTaskCrate::new(target_dir.path(), "hello");
+        let write = || {
+            root_task_crate
+                .write("hello", &user_manifest_path, None, b"fn main() {}")
+                .unwrap()
+        };
+        let helper_lockfile = || fs::read_to_string(&root_task_crate.lockfile_path).unwrap();
+
+        fs::write(&user_lockfile_path, "# libsel4-sys 0.1.0\n").unwrap();
+        write();
+        assert_eq!("# libsel4-sys 0.1.0\n", helper_lockfile());
+        // The entries cargo adds for the helper crate are kept
+        fs::write(&root_task_crate.lockfile_path, "# hello-root-task\n").unwrap();
+        write();
+        assert_eq!("# hello-root-task\n", helper_lockfile());
+        fs::write(&user_lockfile_path, "# libsel4-sys 0.1.1\n").unwrap();
+        write();
+        assert_eq!("# libsel4-sys 0.1.1\n", helper_lockfile());
+    }
+
+    #[test]
+    fn changed_wee_alloc_heap_size_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let root_task_crate = RootTaskCrate::new(dir.path(), "hello");
+        fs::create_dir_all(&root_task_crate.dir).unwrap();
+        assert_eq!(None, root_task_crate.record_wee_alloc_heap_size(4096).unwrap());
+        assert_eq!(None, root_task_crate.record_wee_alloc_heap_size(4096).unwrap());
+        assert_eq!(
+            Some(4096),
+            root_task_crate.record_wee_alloc_heap_size(8192).unwrap()
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+use super::Error;
+use builder::Builder;
+use cmake_config::rust_string_literal;
+
+/// The separator of the flags in `CARGO_ENCODED_RUSTFLAGS`
+const ENCODED_SEPARATOR: char = '\x1f';
+
+/// The places cargo reads the rustflags of an invocation from, in the order
+/// of precedence it gives them: the first that sets any flags wins
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RustflagsSources {
+    pub cargo_encoded_rustflags: Option<String>,
+    pub rustflags: Option<String>,
+    /// The directory cargo runs in, whose ancestors' `.cargo/config` files
+    /// supply `target.<triple>.rustflags` and `build.rustflags`
+    pub current_dir: PathBuf,
+    pub cargo_home: Option<PathBuf>,
+}
+
+impl RustflagsSources {
+    /// The sources of a cargo invocation run in the current directory with
+    /// the current environment
+    pub fn from_env() -> Result<RustflagsSources, Error> {
+        Ok(RustflagsSources {
+            cargo_encoded_rustflags: env::var("CARGO_ENCODED_RUSTFLAGS").ok(),
+            rustflags: env::var("RUSTFLAGS").ok(),
+            current_dir: env::current_dir()?,
+            cargo_home: env::var_os("CARGO_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo"))),
+        })
+    }
+
+    /// The rustflags cargo would use when building for the given target.
+    /// The `target.'cfg(...)'.rustflags` tables are not evaluated.
+    pub fn resolve(&self, target: &str) -> Result<Vec<String>, Error> {
+        if let Some(ref encoded) = self.cargo_encoded_rustflags {
+            return Ok(encoded
+                .split(ENCODED_SEPARATOR)
+                .filter(|flag| !flag.is_empty())
+                .map(String::from)
+                .collect());
+        }
+        if let Some(ref rustflags) = self.rustflags {
+            return Ok(split_rustflags(rustflags));
+        }
+        let configs = self.cargo_configs()?;
+        for key in &[vec!["target", target, "rustflags"], vec!["build", "rustflags"]] {
+            let values: Vec<&Value> = configs
+                .iter()
+                .filter_map(|config| lookup(config, key))
+                .collect();
+            if !values.is_empty() {
+                return merge_config_rustflags(&values);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    /// The cargo configuration files, closest to the current directory first
+    fn cargo_configs(&self) -> Result<Vec<Value>, Error> {
+        let mut dirs: Vec<PathBuf> = self
+            .current_dir
+            .ancestors()
+            .map(|dir| dir.join(".cargo"))
+            .collect();
+        if let Some(ref cargo_home) = self.cargo_home {
+            if !dirs.contains(cargo_home) {
+                dirs.push(cargo_home.clone());
+            }
+        }
+        let mut configs = Vec::new();
+        for dir in dirs {
+            for name in &["config", "config.toml"] {
+                let path = dir.join(name);
+                if path.is_file() {
+                    configs.push(read_cargo_config(&path)?);
+                    // cargo prefers the file without an extension
+                    break;
+                }
+            }
+        }
+        Ok(configs)
+    }
+}
+
+fn read_cargo_config(path: &Path) -> Result<Value, Error> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| Error::IO(format!("Could not read {}: {}", path.display(), e)))?;
+    contents
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("Invalid {}: {}", path.display(), e)))
+}
+
+fn lookup<'v>(value: &'v Value, key: &[&str]) -> Option<&'v Value> {
+    key.iter().fold(Some(value), |value, part| value?.get(*part))
+}
+
+/// The flags of the config values of one key, as cargo merges them: the
+/// arrays of all files are concatenated, while a string value is taken from
+/// the closest file only
+fn merge_config_rustflags(values: &[&Value]) -> Result<Vec<String>, Error> {
+    if let Some(s) = values[0].as_str() {
+        return Ok(split_rustflags(s));
+    }
+    let mut flags = Vec::new();
+    for value in values {
+        match *value {
+            Value::Array(ref array) => for flag in array {
+                match flag.as_str() {
+                    Some(flag) => flags.push(flag.to_string()),
+                    None => {
+                        return Err(Error::ConfigError(format!(
+                            "The rustflags in the cargo configuration must be strings, found {}",
+                            flag
+                        )))
+                    }
+                }
+            },
+            Value::String(_) => (),
+            ref other => {
+                return Err(Error::ConfigError(format!(
+                    "The rustflags in the cargo configuration must be a string or an array, \
+                     found {}",
+                    other
+                )))
+            }
+        }
+    }
+    Ok(flags)
+}
+
+/// Split a `RUSTFLAGS`-style string the way cargo does
+fn split_rustflags(s: &str) -> Vec<String> {
+    s.split(' ')
+        .map(str::trim)
+        .filter(|flag| !flag.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Append the fel4 cfgs to the flags, leaving out the cfgs they already
+/// hold, whether given as `--cfg <spec>` or `--cfg=<spec>`
+pub fn merge_fel4_cfgs(
+    mut flags: Vec<String>,
+    feature_flags: &[String],
+    key_value_cfgs: &[(String, String)],
+) -> Vec<String> {
+    let mut cfgs: Vec<String> = Vec::new();
+    {
+        let mut previous: Option<&str> = None;
+        for flag in &flags {
+            if previous == Some("--cfg") {
+                cfgs.push(flag.clone());
+            } else if flag.starts_with("--cfg=") {
+                cfgs.push(flag["--cfg=".len()..].to_string());
+            }
+            previous = Some(flag);
+        }
+    }
+    let fel4_cfgs = feature_flags
+        .iter()
+        .map(|feature| format!("feature=\"{}\"", feature))
+        .chain(
+            key_value_cfgs
+                .iter()
+                .map(|&(ref name, ref value)| format!("{}={}", name, rust_string_literal(value))),
+        );
+    for cfg in fel4_cfgs {
+        if !cfgs.contains(&cfg) {
+            flags.push(format!("--cfg={}", cfg));
+            cfgs.push(cfg);
+        }
+    }
+    flags
+}
+
+/// The flags in the form of `CARGO_ENCODED_RUSTFLAGS`, which keeps the
+/// whitespace within them
+pub fn encode_rustflags(flags: &[String]) -> String {
+    flags.join(&ENCODED_SEPARATOR.to_string())
+}
+
+/// The flags in the form of `RUSTFLAGS`. Flags containing whitespace cannot
+/// be expressed in it and fail the build.
+pub fn space_separated_rustflags(flags: &[String]) -> Result<String, Error> {
+    if let Some(flag) = flags.iter().find(|flag| flag.contains(char::is_whitespace)) {
+        return Err(Error::ConfigError(format!(
+            "The rustflag '{}' contains whitespace, which RUSTFLAGS cannot express, \
+             consider the build-std backend",
+            flag
+        )));
+    }
+    Ok(flags.join(" "))
+}
+
+/// The name and value of the only environment variable the builder is given
+/// the flags in. Xargo adds its `--sysroot` flag to `RUSTFLAGS` for its inner
+/// cargo, which ignores `RUSTFLAGS` when `CARGO_ENCODED_RUSTFLAGS` is set, so
+/// only the build-std backend gets the encoded form.
+pub fn rustflags_env_var(
+    builder: &Builder,
+    flags: &[String],
+) -> Result<(&'static str, String), Error> {
+    match *builder {
+        Builder::Xargo => Ok(("RUSTFLAGS", space_separated_rustflags(flags)?)),
+        Builder::BuildStd(_) => Ok(("CARGO_ENCODED_RUSTFLAGS", encode_rustflags(flags))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs;
+
+    const TARGET: &str = "x86_64-sel4-fel4";
+
+    fn sources_in(dir: &Path) -> RustflagsSources {
+        RustflagsSources {
+            current_dir: dir.to_path_buf(),
+            ..RustflagsSources::default()
+        }
+    }
+
+    fn write_config(dir: &Path, contents: &str) {
+        fs::create_dir_all(dir.join(".cargo")).unwrap();
+        fs::write(dir.join(".cargo").join("config"), contents).unwrap();
+    }
+
+    fn strings(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|flag| flag.to_string()).collect()
+    }
+
+    #[test]
+    fn encoded_rustflags_take_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        write_config(dir.path(), "[build]\nrustflags = [\"-g\"]\n");
+        let sources = RustflagsSources {
+            cargo_encoded_rustflags: Some("--cfg\x1fname=\"a b\"\x1f".to_string()),
+            rustflags: Some("-C opt-level=1".to_string()),
+            ..sources_in(dir.path())
+        };
+        assert_eq!(
+            strings(&["--cfg", "name=\"a b\""]),
+            sources.resolve(TARGET).unwrap()
+        );
+    }
+
+    #[test]
+    fn rustflags_env_var_precedes_cargo_config() {
+        let dir = tempfile::tempdir().unwrap();
+        write_config(dir.path(), "[build]\nrustflags = [\"-g\"]\n");
+        let sources = RustflagsSources {
+            rustflags: Some("  -C  opt-level=1 ".to_string()),
+            ..sources_in(dir.path())
+        };
+        assert_eq!(
+            strings(&["-C", "opt-level=1"]),
+            sources.resolve(TARGET).unwrap()
+        );
+    }
+
+    #[test]
+    fn target_rustflags_precede_build_rustflags() {
+        let dir = tempfile::tempdir().unwrap();
+        write_config(
+            dir.path(),
+            "[build]\nrustflags = [\"-g\"]\n\n\
+             [target.x86_64-sel4-fel4]\nrustflags = \"-C target-cpu=native\"\n",
+        );
+        assert_eq!(
+            strings(&["-C", "target-cpu=native"]),
+            sources_in(dir.path()).resolve(TARGET).unwrap()
+        );
+        assert_eq!(
+            strings(&["-g"]),
+            sources_in(dir.path()).resolve("armv7-sel4-fel4").unwrap()
+        );
+    }
+
+    #[test]
+    fn build_rustflags_merge_across_config_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let project_dir = dir.path().join("project");
+        let cargo_home = dir.path().join("cargo-home");
+        write_config(dir.path(), "[build]\nrustflags = [\"-g\"]\n");
+        write_config(&project_dir, "[build]\nrustflags = [\"--cfg\", \"project\"]\n");
+        fs::create_dir_all(&cargo_home).unwrap();
+        fs::write(
+            cargo_home.join("config"),
+            "[build]\nrustflags = [\"-C\", \"debuginfo=1\"]\n",
+        ).unwrap();
+        let sources = RustflagsSources {
+            cargo_home: Some(cargo_home),
+            ..sources_in(&project_dir)
+        };
+        assert_eq!(
+            strings(&["--cfg", "project", "-g", "-C", "debuginfo=1"]),
+            sources.resolve(TARGET).unwrap()
+        );
+    }
+
+    #[test]
+    fn no_rustflags() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(sources_in(dir.path()).resolve(TARGET).unwrap().is_empty());
+        write_config(dir.path(), "[build]\nrustflags = 1\n");
+        assert!(sources_in(dir.path()).resolve(TARGET).is_err());
+    }
+
+    #[test]
+    fn fel4_cfgs_are_deduplicated() {
+        let flags = merge_fel4_cfgs(
+            strings(&[
+                "-g",
+                "--cfg",
+                "feature=\"KernelPrinting\"",
+                "--cfg=sel4_KernelArch=\"x86\"",
+            ]),
+            &strings(&["KernelDebugBuild", "KernelPrinting", "KernelDebugBuild"]),
+            &[
+                ("sel4_KernelArch".to_string(), "x86".to_string()),
+                ("sel4_KernelPlatform".to_string(), "pc 99".to_string()),
+            ],
+        );
+        assert_eq!(
+            strings(&[
+                "-g",
+                "--cfg",
+                "feature=\"KernelPrinting\"",
+                "--cfg=sel4_KernelArch=\"x86\"",
+                "--cfg=feature=\"KernelDebugBuild\"",
+                "--cfg=sel4_KernelPlatform=\"pc 99\"",
+            ]),
+            flags
+        );
+        assert_eq!(
+            "-g\x1f--cfg\x1ffeature=\"KernelPrinting\"\x1f--cfg=sel4_KernelArch=\"x86\"\x1f\
+             --cfg=feature=\"KernelDebugBuild\"\x1f--cfg=sel4_KernelPlatform=\"pc 99\"",
+            encode_rustflags(&flags)
+        );
+        assert!(space_separated_rustflags(&flags).is_err());
+        assert_eq!(
+            "-g --cfg feature=\"KernelPrinting\" --cfg=sel4_KernelArch=\"x86\"",
+            space_separated_rustflags(&flags[..4]).unwrap()
+        );
+    }
+
+    #[test]
+    fn builder_rustflags_env_var() {
+        let flags = strings(&["--cfg", "sel4_KernelPlatform=\"pc 99\""]);
+        assert!(rustflags_env_var(&Builder::Xargo, &flags).is_err());
+        assert_eq!(
+            (
+                "CARGO_ENCODED_RUSTFLAGS",
+                "--cfg\x1fsel4_KernelPlatform=\"pc 99\"".to_string()
+            ),
+            rustflags_env_var(&Builder::BuildStd(Default::default()), &flags).unwrap()
+        );
+        assert_eq!(
+            ("RUSTFLAGS", "--cfg sel4_KernelArch".to_string()),
+            rustflags_env_var(&Builder::Xargo, &strings(&["--cfg", "sel4_KernelArch"])).unwrap()
+        );
+    }
+}
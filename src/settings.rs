@@ -0,0 +1,472 @@
+use fel4_config::BuildProfile;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use toml;
+
+use super::Error;
+use config::BuilderBackend;
+
+/// Settings that steer cargo-fel4 itself, as opposed to the seL4
+/// configuration properties resolved by fel4-config.
+///
+/// These live in sub-tables of the `[fel4]` table of the fel4.toml manifest,
+/// e.g. `[fel4.build]`, and every one of them is optional.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Fel4Settings {
+    pub build: BuildSettings,
+    pub root_task: RootTaskSettings,
+    pub allocator: AllocatorSettings,
+    pub simulation: SimulationSettings,
+    pub cfg: CfgSettings,
+    /// The `[fel4.uboot]` table, which makes the ARM builds produce a U-Boot
+    /// image next to the feL4img
+    pub uboot: Option<UbootSettings>,
+    /// The `[fel4.deploy.<platform>]` tables, keyed by platform name
+    pub deploy: BTreeMap<String, DeployMethod>,
+    /// The `[[fel4.components]]` array of tables
+    pub components: Vec<ComponentSettings>,
+}
+
+/// The `[fel4.build]` table
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BuildSettings {
+    /// Which tool cross-compiles the sysroot and the root task
+    pub backend: Option<BuilderBackend>,
+}
+
+/// The `[fel4.cfg]` table, selecting the non-boolean properties that are
+/// passed to the build as key/value cfgs, e.g. `sel4_KernelArch="x86_64"`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CfgSettings {
+    /// Prepended to the property names, defaults to `"sel4_"`
+    pub prefix: Option<String>,
+    /// The properties to pass, defaults to every string-valued one
+    pub properties: Option<Vec<String>>,
+}
+
+/// The `[fel4.simulation]` table, overriding parts of the QEMU command line
+/// cargo-fel4 derives from the target and platform
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SimulationSettings {
+    /// The QEMU executable, e.g. `"qemu-system-x86_64"`
+    pub qemu: Option<String>,
+    pub machine: Option<String>,
+    pub cpu: Option<String>,
+    /// QEMU's `-m` value, e.g. `"512M"`
+    pub memory: Option<String>,
+    /// Appended to the QEMU command line
+    pub extra_args: Vec<String>,
+}
+
+/// How the system image is wrapped for U-Boot. The addresses are hexadecimal
+/// (`"0x20000000"`) or decimal strings and default to those of the ELF
+/// headers of the image.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UbootSettings {
+    /// Defaults to a legacy uImage
+    pub format: Option<UbootFormat>,
+    pub load_address: Option<String>,
+    pub entry_address: Option<String>,
+    /// The image name recorded in the header, defaults to `"feL4"`
+    pub name: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UbootFormat {
+    /// A legacy image, as `mkimage -T kernel` produces
+    Uimage,
+    /// A Flattened Image Tree with a single kernel image
+    Fit,
+}
+
+/// How the system image is delivered to a platform, selected by the
+/// `method` key of its `[fel4.deploy.<platform>]` table
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "method", rename_all = "kebab-case")]
+pub enum DeployMethod {
+    Dfu(DfuSettings),
+    Tftp(CopySettings),
+    SdCard(CopySettings),
+    UbootImage(UbootImageSettings),
+    Script(ScriptSettings),
+}
+
+/// Upload the image with dfu-util
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DfuSettings {
+    /// `VID:PID` of the DFU device
+    pub device: Option<String>,
+    /// The DFU alternate setting, by name or number
+    pub alt: Option<String>,
+}
+
+/// Copy the image into a directory, e.g. the root of a TFTP server or a
+/// mounted boot partition
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CopySettings {
+    pub directory: PathBuf,
+    /// Defaults to the name of the image
+    pub file_name: Option<String>,
+}
+
+/// Wrap the image for U-Boot, optionally copying it into a directory
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UbootImageSettings {
+    pub format: Option<UbootFormat>,
+    pub load_address: Option<String>,
+    pub entry_address: Option<String>,
+    pub name: Option<String>,
+    pub directory: Option<PathBuf>,
+    pub file_name: Option<String>,
+}
+
+impl UbootImageSettings {
+    /// The image settings, laid out as in the `[fel4.uboot]` table
+    pub fn image(&self) -> UbootSettings {
+        UbootSettings {
+            format: self.format,
+            load_address: self.load_address.clone(),
+            entry_address: self.entry_address.clone(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+/// Run a user-provided program with the image path as its last argument
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ScriptSettings {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// The `[fel4.root-task]` table, with optional per build profile overrides
+/// in `[fel4.root-task.debug]` and `[fel4.root-task.release]`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RootTaskSettings {
+    /// In bytes
+    pub stack_size: Option<usize>,
+    pub priority: Option<u8>,
+    pub max_controlled_priority: Option<u8>,
+    pub debug: ThreadSettings,
+    pub release: ThreadSettings,
+}
+
+/// Defaults for the threads started by the root task
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ThreadSettings {
+    /// In bytes
+    pub stack_size: Option<usize>,
+    pub priority: Option<u8>,
+    pub max_controlled_priority: Option<u8>,
+}
+
+impl RootTaskSettings {
+    /// The thread settings for a build profile, where profile-specific
+    /// values take precedence
+    pub fn for_profile(&self, profile: &BuildProfile) -> ThreadSettings {
+        let overrides = match *profile {
+            BuildProfile::Debug => &self.debug,
+            BuildProfile::Release => &self.release,
+        };
+        ThreadSettings {
+            stack_size: overrides.stack_size.or(self.stack_size),
+            priority: overrides.priority.or(self.priority),
+            max_controlled_priority: overrides
+                .max_controlled_priority
+                .or(self.max_controlled_priority),
+        }
+    }
+}
+
+/// The `[fel4.allocator]` table, selecting the global allocator the root task
+/// installs when the `alloc` feature is enabled
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AllocatorSettings {
+    pub kind: Option<AllocatorKind>,
+    /// In bytes
+    pub heap_size: Option<usize>,
+    pub backing: Option<HeapBacking>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllocatorKind {
+    WeeAlloc,
+    LinkedList,
+    Bump,
+    None,
+}
+
+/// Where the heap memory comes from
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HeapBacking {
+    /// A static array in the root task image
+    Static,
+    /// Frames retyped from untyped memory and mapped at boot
+    Untyped,
+}
+
+/// A thread of the user application, started by the root task
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ComponentSettings {
+    pub name: String,
+    /// Path to a `fn()` within the user crate, e.g. `"net::run"`
+    pub entry: String,
+    /// In bytes
+    pub stack_size: Option<usize>,
+    pub priority: Option<u8>,
+    pub max_controlled_priority: Option<u8>,
+}
+
+pub fn get_fel4_settings<P: AsRef<Path>>(fel4_manifest_path: P) -> Result<Fel4Settings, Error> {
+    let mut contents = String::new();
+    File::open(fel4_manifest_path.as_ref())
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| {
+            Error::IO(format!(
+                "Could not read {}: {}",
+                fel4_manifest_path.as_ref().display(),
+                e
+            ))
+        })?;
+    parse_fel4_settings(&contents)
+}
+
+pub fn parse_fel4_settings(contents: &str) -> Result<Fel4Settings, Error> {
+    let manifest: toml::Value = contents
+        .parse()
+        .map_err(|e| Error::ConfigError(format!("{}", e)))?;
+    match manifest.get("fel4") {
+        Some(fel4_table) => fel4_table
+            .clone()
+            .try_into()
+            .map_err(|e| Error::ConfigError(format!("Invalid cargo-fel4 settings: {}", e))),
+        None => Ok(Fel4Settings::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fel4_table_gives_defaults() {
+        assert_eq!(
+            parse_fel4_settings("[x86_64-sel4-fel4]\n").unwrap(),
+            Fel4Settings::default()
+        );
+    }
+
+    #[test]
+    fn root_task_profile_overrides() {
+        let settings = parse_fel4_settings(
+            r#"
+[fel4]
+artifact-path = "artifacts"
+target = "x86_64-sel4-fel4"
+
+[fel4.root-task]
+stack-size = 65536
+priority = 100
+
+[fel4.root-task.debug]
+priority = 50
+max-controlled-priority = 60
+
+[fel4.root-task.release]
+stack-size = 8192
+"#,
+        ).unwrap();
+        assert_eq!(
+            settings.root_task.for_profile(&BuildProfile::Debug),
+            ThreadSettings {
+                stack_size: Some(65536),
+                priority: Some(50),
+                max_controlled_priority: Some(60),
+            }
+        );
+        assert_eq!(
+            settings.root_task.for_profile(&BuildProfile::Release),
+            ThreadSettings {
+                stack_size: Some(8192),
+                priority: Some(100),
+                max_controlled_priority: None,
+            }
+        );
+    }
+
+    #[test]
+    fn all_tables() {
+        let settings = parse_fel4_settings(
+            r#"
+[fel4.build]
+backend = "build-std"
+
+[fel4.allocator]
+kind = "linked-list"
+heap-size = 1048576
+backing = "untyped"
+
+[fel4.simulation]
+memory = "1G"
+extra-args = ["-s"]
+
+[fel4.cfg]
+prefix = "fel4_"
+properties = ["KernelArch"]
+
+[fel4.uboot]
+format = "fit"
+load-address = "0x20000000"
+
+[[fel4.components]]
+name = "net"
+entry = "net::run"
+priority = 120
+
+[[fel4.components]]
+name = "log"
+entry = "log::run"
+"#,
+        ).unwrap();
+        assert_eq!(settings.build.backend, Some(BuilderBackend::BuildStd));
+        assert_eq!(
+            settings.allocator,
+            AllocatorSettings {
+                kind: Some(AllocatorKind::LinkedList),
+                heap_size: Some(1048576),
+                backing: Some(HeapBacking::Untyped),
+            }
+        );
+        assert_eq!(settings.simulation.memory, Some("1G".to_string()));
+        assert_eq!(settings.simulation.extra_args, vec!["-s".to_string()]);
+        assert_eq!(settings.cfg.prefix, Some("fel4_".to_string()));
+        assert_eq!(
+            settings.cfg.properties,
+            Some(vec!["KernelArch".to_string()])
+        );
+        let uboot = settings.uboot.unwrap();
+        assert_eq!(uboot.format, Some(UbootFormat::Fit));
+        assert_eq!(uboot.load_address, Some("0x20000000".to_string()));
+        assert_eq!(settings.components.len(), 2);
+        assert_eq!(settings.components[0].entry, "net::run");
+        assert_eq!(settings.components[0].priority, Some(120));
+        assert_eq!(settings.components[1].priority, None);
+    }
+
+    #[test]
+    fn deploy_methods() {
+        let settings = parse_fel4_settings(
+            r#"
+[fel4.deploy.tx1]
+method = "dfu"
+device = "0955:7721"
+
+[fel4.deploy.sabre]
+method = "uboot-image"
+format = "uimage"
+name = "sabre"
+directory = "/srv/tftp"
+
+[fel4.deploy.pc99]
+method = "script"
+path = "scripts/flash.sh"
+"#,
+        ).unwrap();
+        assert_eq!(
+            settings.deploy["tx1"],
+            DeployMethod::Dfu(DfuSettings {
+                device: Some("0955:7721".to_string()),
+                alt: None,
+            })
+        );
+        match settings.deploy["sabre"] {
+            DeployMethod::UbootImage(ref uboot) => {
+                assert_eq!(
+                    uboot.image(),
+                    UbootSettings {
+                        format: Some(UbootFormat::Uimage),
+                        load_address: None,
+                        entry_address: None,
+                        name: Some("sabre".to_string()),
+                    }
+                );
+                assert_eq!(uboot.directory, Some(PathBuf::from("/srv/tftp")));
+            }
+            ref other => panic!("Unexpected deploy method {:?}", other),
+        }
+        assert_eq!(
+            settings.deploy["pc99"],
+            DeployMethod::Script(ScriptSettings {
+                path: PathBuf::from("scripts/flash.sh"),
+                args: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn deploy_method_is_required() {
+        assert!(parse_fel4_settings("[fel4.deploy.tx1]\ndevice = \"0955:7721\"\n").is_err());
+        assert!(parse_fel4_settings("[fel4.deploy.tx1]\nmethod = \"usb\"\n").is_err());
+        // The copy methods need a directory
+        assert!(parse_fel4_settings("[fel4.deploy.sabre]\nmethod = \"tftp\"\n").is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for contents in &[
+            "[fel4.build]\nbackends = \"xargo\"\n",
+            "[fel4.root-task]\nstack_size = 4096\n",
+            "[fel4.root-task.debug]\npriority = 1\nheap-size = 1\n",
+            "[fel4.allocator]\nsize = 4096\n",
+            "[fel4.uboot]\nload-addr = \"0x0\"\n",
+            "[fel4.deploy.tx1]\nmethod = \"dfu\"\ndirectory = \"/srv\"\n",
+            "[fel4.deploy.sabre]\nmethod = \"uboot-image\"\nentry = \"0x0\"\n",
+            "[[fel4.components]]\nname = \"a\"\nentry = \"a::run\"\nstack = 1\n",
+        ] {
+            match parse_fel4_settings(contents) {
+                Err(Error::ConfigError(ref msg)) => {
+                    assert!(msg.contains("unknown field"), "{}", msg)
+                }
+                other => panic!("{:?} was accepted: {:?}", contents, other),
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_types_are_rejected() {
+        for contents in &[
+            "[fel4.build]\nbackend = 1\n",
+            "[fel4.root-task]\npriority = 256\n",
+            "[fel4.root-task]\nstack-size = \"64K\"\n",
+            "[fel4.allocator]\nkind = \"slab\"\n",
+            "[fel4.simulation]\nextra-args = \"-s\"\n",
+            "[fel4.cfg]\nproperties = \"KernelArch\"\n",
+            "[fel4.components]\nname = \"a\"\n",
+        ] {
+            assert!(
+                parse_fel4_settings(contents).is_err(),
+                "{:?} was accepted",
+                contents
+            );
+        }
+    }
+}
@@ -1,38 +1,213 @@
+use libc;
+use std::io::{BufRead, BufReader};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use super::Error;
-use command_ext::CommandExt;
 use config::{get_fel4_manifest_with_root_dir, Fel4BuildProfile, ManifestWithRootDir, SimulateCmd};
-use fel4_config::SupportedPlatform;
+use fel4_config::SupportedTarget;
+use qemu::{QemuInvocation, DEFAULT_GDB_PORT};
+use settings::get_fel4_settings;
+
+/// A console line containing this sentinel followed by an integer, e.g.
+/// `fel4-halt:0`, makes cargo-fel4 stop the simulation and exit with that
+/// integer as its status
+pub const HALT_SENTINEL: &str = "fel4-halt:";
+
+/// How a simulation came to an end
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SimulationOutcome {
+    /// The guest printed the halt sentinel with this exit code
+    Halted(i32),
+    /// The emulator exited on its own with this guest exit code, e.g. through
+    /// the isa-debug-exit device or semihosting
+    Exited(i32),
+    /// The console line handler asked for the simulation to stop
+    Stopped,
+    TimedOut,
+}
 
 pub fn handle_simulate_cmd(cmd: &SimulateCmd) -> Result<(), Error> {
+    let outcome = run_simulation(cmd, |line| {
+        print!("{}", line);
+        false
+    })?;
+    match outcome {
+        SimulationOutcome::Halted(0) | SimulationOutcome::Exited(0) => Ok(()),
+        SimulationOutcome::Stopped => Ok(()),
+        SimulationOutcome::Halted(code) | SimulationOutcome::Exited(code) => {
+            Err(Error::GuestExit(code))
+        }
+        SimulationOutcome::TimedOut => Err(timed_out_error(cmd)),
+    }
+}
+
+pub fn timed_out_error(cmd: &SimulateCmd) -> Error {
+    Error::ExitStatusError(format!(
+        "The simulation timed out after {} seconds",
+        cmd.timeout.unwrap_or(0)
+    ))
+}
+
+/// Create a Command instance that, when run, will launch QEMU on the images
+/// of a previously built feL4 application, along with the target the
+/// application was built for
+pub fn simulation_command(cmd: &SimulateCmd) -> Result<(Command, SupportedTarget), Error> {
     let ManifestWithRootDir {
         fel4_manifest,
         root_dir,
-    } = get_fel4_manifest_with_root_dir(&cmd.cargo_manifest_path)?;
+    } = get_fel4_manifest_with_root_dir(
+        &cmd.cargo_manifest_path,
+        cmd.package.as_ref().map(String::as_str),
+    )?;
     let artifact_path = Path::new(&root_dir)
         .join(&fel4_manifest.artifact_path)
         .join(Fel4BuildProfile::from(cmd).artifact_subdir_path());
+    let settings = get_fel4_settings(root_dir.join("fel4.toml"))?;
 
-    if fel4_manifest.selected_platform == SupportedPlatform::Tx1 {
-        return Err(Error::ConfigError(format!(
-            "The selected {} platform does not support simulation",
-            fel4_manifest.selected_platform.full_name()
-        )));
+    let mut invocation = QemuInvocation::resolve(
+        &fel4_manifest.selected_target,
+        &fel4_manifest.selected_platform,
+        &artifact_path,
+        &settings.simulation,
+    )?;
+    if let Some(port) = cmd.gdb {
+        let port = port.unwrap_or(DEFAULT_GDB_PORT);
+        println!(
+            "Waiting for gdb on port {}, attach with `cargo fel4 debug --port {}`",
+            port, port
+        );
+        invocation = invocation.with_gdbstub(port);
     }
+    Ok((invocation.command(), fel4_manifest.selected_target))
+}
 
-    // The simulation script is relies on being called from its parent directory
-    let sim_script_path = Path::new(artifact_path.file_name().unwrap()).join("simulate");
+/// Translate the exit status of QEMU into the exit code requested by the
+/// guest. The isa-debug-exit device makes QEMU exit with `(value << 1) | 1`,
+/// which leaves a status of 1 indistinguishable from a QEMU failure.
+fn guest_exit_code(target: &SupportedTarget, status: i32) -> i32 {
+    match *target {
+        SupportedTarget::X8664Sel4Fel4 if status > 1 && status & 1 == 1 => status >> 1,
+        _ => status,
+    }
+}
 
-    if !artifact_path.join("simulate").exists() {
-        return Err(Error::ConfigError(format!(
-            "Something went wrong with the build, cannot find the simulation script '{}'",
-            artifact_path.join("simulate").display()
-        )));
+/// Parse the exit code from a console line carrying the halt sentinel
+pub fn parse_halt_sentinel(line: &str) -> Option<i32> {
+    let start = line.find(HALT_SENTINEL)?;
+    line[start + HALT_SENTINEL.len()..]
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Run the simulation, handing every console line to `on_line` until it
+/// returns true, the guest halts or exits, or the timeout expires. The
+/// emulator is always stopped before this returns.
+pub fn run_simulation<F>(cmd: &SimulateCmd, mut on_line: F) -> Result<SimulationOutcome, Error>
+where
+    F: FnMut(&str) -> bool,
+{
+    let (mut simulation, target) = simulation_command(cmd)?;
+    // Keep QEMU from reading the terminal, e.g. in CI
+    simulation.stdin(Stdio::null()).stdout(Stdio::piped());
+    info!("running: {:?}", simulation);
+    let mut child = simulation.spawn().map_err(|e| {
+        Error::ExitStatusError(format!("failed to execute the command: {}", e))
+    })?;
+    let stdout = match child.stdout.take() {
+        Some(stdout) => stdout,
+        None => return Err(Error::IO("Could not capture the simulation output".to_string())),
+    };
+
+    // Read the console on a thread of its own, so that the timeout can be
+    // enforced while waiting for output
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stdout);
+        loop {
+            let mut raw_line = Vec::new();
+            match reader.read_until(b'\n', &mut raw_line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    // The serial console may carry arbitrary bytes
+                    let line = String::from_utf8_lossy(&raw_line).into_owned();
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let deadline = cmd.timeout.map(|t| Instant::now() + Duration::from_secs(t));
+    let outcome = loop {
+        let line = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    break SimulationOutcome::TimedOut;
+                }
+                match receiver.recv_timeout(deadline - now) {
+                    Ok(line) => line,
+                    Err(RecvTimeoutError::Timeout) => break SimulationOutcome::TimedOut,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        break wait_for_exit(&mut child, &target)?
+                    }
+                }
+            }
+            None => match receiver.recv() {
+                Ok(line) => line,
+                Err(_) => break wait_for_exit(&mut child, &target)?,
+            },
+        };
+        if on_line(&line) {
+            break SimulationOutcome::Stopped;
+        }
+        if let Some(code) = parse_halt_sentinel(&line) {
+            break SimulationOutcome::Halted(code);
+        }
+    };
+    stop_simulation(&mut child);
+    Ok(outcome)
+}
+
+fn wait_for_exit(child: &mut Child, target: &SupportedTarget) -> Result<SimulationOutcome, Error> {
+    let status = child.wait()?;
+    match status.code() {
+        Some(code) => Ok(SimulationOutcome::Exited(guest_exit_code(target, code))),
+        None => Err(Error::ExitStatusError(format!(
+            "command status returned: {}",
+            status
+        ))),
     }
+}
 
-    Command::new(&sim_script_path)
-        .current_dir(&artifact_path.parent().unwrap())
-        .run_cmd()
+/// Stop QEMU with SIGTERM rather than SIGKILL, which it handles by shutting
+/// down cleanly
+fn stop_simulation(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halt_sentinel_and_exit_codes() {
+        assert_eq!(Some(0), parse_halt_sentinel("fel4-halt:0\r\n"));
+        assert_eq!(Some(3), parse_halt_sentinel("boot noise fel4-halt:3"));
+        assert_eq!(None, parse_halt_sentinel("fel4-halt:"));
+        assert_eq!(None, parse_halt_sentinel("fel4-test:pass:a"));
+        assert_eq!(3, guest_exit_code(&SupportedTarget::X8664Sel4Fel4, 7));
+        assert_eq!(1, guest_exit_code(&SupportedTarget::X8664Sel4Fel4, 1));
+        assert_eq!(7, guest_exit_code(&SupportedTarget::Armv7Sel4Fel4, 7));
+    }
 }
@@ -1,19 +1,26 @@
-use super::{handle_build_cmd, handle_deploy_cmd, handle_simulate_cmd, Error};
-use config::{BuildCmd, DeployCmd, SimulateCmd, TestCmd, TestSubCmd};
+use std::path::{Path, PathBuf};
+
+use super::{handle_build_cmd, handle_deploy_cmd, Error};
+use config::{
+    get_fel4_manifest_with_root_dir, get_fel4_package, BuildCmd, DeployCmd, Fel4BuildProfile,
+    MessageFormat, SimulateCmd, TestCmd, TestSubCmd,
+};
 use new_cmd::generate_tests_source_files;
+use simulate_cmd::{run_simulation, timed_out_error, SimulationOutcome};
+use test_report::{is_legacy_summary, parse_line, TestEvent, TestReport};
 
 pub fn handle_test_cmd(test_cmd: &TestCmd) -> Result<(), Error> {
     match test_cmd.subcmd {
         Some(ref subcmd) => match subcmd {
             TestSubCmd::Build => {
-                generate_tests_source_files(test_cmd.cargo_manifest_path.parent())?;
+                generate_tests_source_files(Some(&test_package_root_dir(test_cmd)?))?;
                 run_test_build(test_cmd)?;
             }
             TestSubCmd::Simulate => run_test_simulation(test_cmd)?,
             TestSubCmd::Deploy => run_test_deployment(test_cmd)?,
         },
         None => {
-            generate_tests_source_files(test_cmd.cargo_manifest_path.parent())?;
+            generate_tests_source_files(Some(&test_package_root_dir(test_cmd)?))?;
             run_test_build(test_cmd)?;
             run_test_simulation(test_cmd)?
         }
@@ -22,11 +29,23 @@ pub fn handle_test_cmd(test_cmd: &TestCmd) -> Result<(), Error> {
     Ok(())
 }
 
+/// The test sources belong to the feL4 package, which is not necessarily
+/// where the supplied (possibly workspace) manifest lives
+fn test_package_root_dir(test_cmd: &TestCmd) -> Result<PathBuf, Error> {
+    Ok(get_fel4_package(
+        &test_cmd.cargo_manifest_path,
+        test_cmd.package.as_ref().map(String::as_str),
+    )?.root_dir)
+}
+
 fn run_test_build(test_cmd: &TestCmd) -> Result<(), Error> {
     let build_cmd = BuildCmd {
         loudness: test_cmd.loudness.clone(),
         release: test_cmd.release,
         tests: true,
+        message_format: MessageFormat::Human,
+        backend: None,
+        package: test_cmd.package.clone(),
         cargo_manifest_path: test_cmd.cargo_manifest_path.clone(),
     };
 
@@ -35,24 +54,103 @@ fn run_test_build(test_cmd: &TestCmd) -> Result<(), Error> {
     Ok(())
 }
 
+/// Run the test application in the simulator, collecting the results it
+/// reports over the console into a summary and a JUnit XML report
+/// (`test-results.xml` in the test artifact directory)
 fn run_test_simulation(test_cmd: &TestCmd) -> Result<(), Error> {
     let sim_cmd = SimulateCmd {
         loudness: test_cmd.loudness.clone(),
         release: test_cmd.release,
         tests: true,
+        timeout: Some(test_cmd.timeout),
+        gdb: None,
+        package: test_cmd.package.clone(),
         cargo_manifest_path: test_cmd.cargo_manifest_path.clone(),
     };
+    let package = get_fel4_package(
+        &test_cmd.cargo_manifest_path,
+        test_cmd.package.as_ref().map(String::as_str),
+    )?;
+    let manifest = get_fel4_manifest_with_root_dir(
+        &test_cmd.cargo_manifest_path,
+        test_cmd.package.as_ref().map(String::as_str),
+    )?;
+    let artifact_path = manifest
+        .root_dir
+        .join(&manifest.fel4_manifest.artifact_path)
+        .join(Fel4BuildProfile::from(test_cmd).artifact_subdir_path());
+
+    println!("\nrunning tests");
+    let mut report = TestReport::default();
+    let mut legacy_template = false;
+    let outcome = run_simulation(&sim_cmd, |line| {
+        match parse_line(line) {
+            Some(event) => {
+                match event {
+                    TestEvent::Passed(ref name) => println!("test {} ... ok", name),
+                    TestEvent::Failed { ref name, .. } => println!("test {} ... FAILED", name),
+                    _ => (),
+                }
+                report.record(event);
+            }
+            None => {
+                print!("{}", line);
+                if report.is_empty() && is_legacy_summary(line) {
+                    legacy_template = true;
+                    return true;
+                }
+            }
+        }
+        report.is_complete()
+    })?;
+    if legacy_template || report.is_empty() {
+        return Err(missing_protocol_error(
+            &package.root_dir,
+            legacy_template,
+            outcome == SimulationOutcome::TimedOut,
+        ));
+    }
+    report.print_summary();
 
-    handle_simulate_cmd(&sim_cmd)?;
+    let junit_path = artifact_path.join("test-results.xml");
+    report.write_junit_xml(&package.name, &junit_path)?;
+    info!("JUnit report: '{}'", junit_path.display());
 
+    if outcome == SimulationOutcome::TimedOut {
+        return Err(timed_out_error(&sim_cmd));
+    }
+    if !report.success() {
+        return Err(Error::ExitStatusError("test failed".to_string()));
+    }
     Ok(())
 }
 
+/// The test application never reported a result over the console, which is
+/// what a fel4_test.rs generated by an older cargo-fel4 does
+fn missing_protocol_error(root_dir: &Path, legacy_template: bool, timed_out: bool) -> Error {
+    let test_source_path = root_dir.join("src").join("fel4_test.rs");
+    let reason = if legacy_template {
+        "The test application does not report its results in the format cargo fel4 test expects"
+    } else if timed_out {
+        "The simulation timed out before the test application reported any results"
+    } else {
+        "The simulation ended before the test application reported any results"
+    };
+    Error::ExitStatusError(format!(
+        "{}. If '{}' was generated by an older cargo-fel4, move it aside and run `cargo fel4 \
+         test` to regenerate it, then port your tests over (see the README).",
+        reason,
+        test_source_path.display()
+    ))
+}
+
 fn run_test_deployment(test_cmd: &TestCmd) -> Result<(), Error> {
     let deploy_cmd = DeployCmd {
         loudness: test_cmd.loudness.clone(),
         release: test_cmd.release,
         tests: true,
+        dry_run: false,
+        package: test_cmd.package.clone(),
         cargo_manifest_path: test_cmd.cargo_manifest_path.clone(),
     };
 
@@ -0,0 +1,323 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use super::Error;
+
+/// Console lines starting with this prefix make up the protocol by which the
+/// fel4_test thread reports test results, see `templates/fel4_test.rs`:
+///
+/// ```text
+/// fel4-test:start:<name>
+/// fel4-test:pass:<name>
+/// fel4-test:fail:<name>:<message, with '\' and newlines escaped>
+/// fel4-test:summary:<passed>:<failed>
+/// ```
+pub const PROTOCOL_PREFIX: &str = "fel4-test:";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TestEvent {
+    Started(String),
+    Passed(String),
+    Failed { name: String, message: String },
+    Summary { passed: usize, failed: usize },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TestOutcome {
+    /// Started, but never reported a result
+    Unfinished,
+    Passed,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: TestOutcome,
+}
+
+/// The test results collected from the console output of a test simulation
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TestReport {
+    pub results: Vec<TestResult>,
+    /// The (passed, failed) counts reported by the fel4_test thread once all
+    /// of its tests have run
+    pub summary: Option<(usize, usize)>,
+}
+
+/// Parse a line of console output, which may be preceded by unrelated output
+/// on the same line
+pub fn parse_line(line: &str) -> Option<TestEvent> {
+    let start = line.find(PROTOCOL_PREFIX)?;
+    let line = line[start + PROTOCOL_PREFIX.len()..].trim_matches(|c| c == '\r' || c == '\n');
+    let mut fields = line.splitn(2, ':');
+    let kind = fields.next()?;
+    let rest = fields.next()?;
+    match kind {
+        "start" => Some(TestEvent::Started(rest.to_string())),
+        "pass" => Some(TestEvent::Passed(rest.to_string())),
+        "fail" => {
+            let mut fields = rest.splitn(2, ':');
+            let name = fields.next()?.to_string();
+            let message = unescape(fields.next().unwrap_or(""));
+            Some(TestEvent::Failed { name, message })
+        }
+        "summary" => {
+            let mut fields = rest.splitn(2, ':');
+            let passed = fields.next()?.parse().ok()?;
+            let failed = fields.next()?.parse().ok()?;
+            Some(TestEvent::Summary { passed, failed })
+        }
+        _ => None,
+    }
+}
+
+/// Whether a console line is the plain `test result: ...` summary printed by
+/// fel4_test.rs templates that predate the protocol. Those templates never
+/// print the halt sentinel either, so the simulation would run until it
+/// times out.
+pub fn is_legacy_summary(line: &str) -> bool {
+    match line.find("test result: ") {
+        Some(start) => {
+            let rest = &line[start + "test result: ".len()..];
+            (rest.starts_with("ok.") || rest.starts_with("FAILED.")) && rest.contains(" passed;")
+        }
+        None => false,
+    }
+}
+
+fn unescape(message: &str) -> String {
+    let mut output = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some(other) => output.push(other),
+            None => output.push('\\'),
+        }
+    }
+    output
+}
+
+impl TestReport {
+    pub fn record(&mut self, event: TestEvent) {
+        match event {
+            TestEvent::Started(name) => self.results.push(TestResult {
+                name,
+                outcome: TestOutcome::Unfinished,
+            }),
+            TestEvent::Passed(name) => self.set_outcome(name, TestOutcome::Passed),
+            TestEvent::Failed { name, message } => {
+                self.set_outcome(name, TestOutcome::Failed(message))
+            }
+            TestEvent::Summary { passed, failed } => self.summary = Some((passed, failed)),
+        }
+    }
+
+    fn set_outcome(&mut self, name: String, outcome: TestOutcome) {
+        if let Some(result) = self
+            .results
+            .iter_mut()
+            .rev()
+            .find(|r| r.name == name && r.outcome == TestOutcome::Unfinished)
+        {
+            result.outcome = outcome;
+            return;
+        }
+        self.results.push(TestResult { name, outcome });
+    }
+
+    /// True until the first protocol line is recorded
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty() && self.summary.is_none()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.summary.is_some()
+    }
+
+    pub fn passed(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| r.outcome == TestOutcome::Passed)
+            .count()
+    }
+
+    /// Every test that did not pass, including those that never finished
+    pub fn failures(&self) -> Vec<&TestResult> {
+        self.results
+            .iter()
+            .filter(|r| r.outcome != TestOutcome::Passed)
+            .collect()
+    }
+
+    /// True when the run completed, and neither the individual results nor
+    /// the summary report a failure
+    pub fn success(&self) -> bool {
+        match self.summary {
+            Some((_, failed)) => failed == 0 && self.failures().is_empty(),
+            None => false,
+        }
+    }
+
+    /// Print the failure details and the result line in the style of
+    /// `cargo test`
+    pub fn print_summary(&self) {
+        let failures = self.failures();
+        if !failures.is_empty() {
+            println!("\nfailures:\n");
+            for f in &failures {
+                println!("---- {} ----", f.name);
+                match f.outcome {
+                    TestOutcome::Failed(ref message) => println!("{}\n", message),
+                    _ => println!("test did not finish\n"),
+                }
+            }
+            println!("\nfailures:");
+            for f in &failures {
+                println!("    {}", f.name);
+            }
+        }
+        if !self.is_complete() {
+            println!("\nthe test run ended before all tests reported their results");
+        }
+        println!(
+            "\ntest result: {}. {} passed; {} failed\n",
+            if self.success() { "ok" } else { "FAILED" },
+            self.passed(),
+            failures.len()
+        );
+    }
+
+    pub fn to_junit_xml(&self, suite_name: &str) -> String {
+        let failures = self.failures();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!(
+            "<testsuites>\n  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\">\n",
+            escape_xml(suite_name),
+            self.results.len(),
+            failures.len()
+        ));
+        for r in &self.results {
+            let name = escape_xml(&r.name);
+            match r.outcome {
+                TestOutcome::Passed => xml.push_str(&format!(
+                    "    <testcase name=\"{}\" classname=\"{}\"/>\n",
+                    name,
+                    escape_xml(suite_name)
+                )),
+                TestOutcome::Failed(ref message) => xml.push_str(&format!(
+                    "    <testcase name=\"{}\" classname=\"{}\">\n      <failure message=\"test failed\">{}</failure>\n    </testcase>\n",
+                    name,
+                    escape_xml(suite_name),
+                    escape_xml(message)
+                )),
+                TestOutcome::Unfinished => xml.push_str(&format!(
+                    "    <testcase name=\"{}\" classname=\"{}\">\n      <failure message=\"test did not finish\"/>\n    </testcase>\n",
+                    name,
+                    escape_xml(suite_name)
+                )),
+            }
+        }
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+
+    pub fn write_junit_xml<P: AsRef<Path>>(&self, suite_name: &str, path: P) -> Result<(), Error> {
+        File::create(path.as_ref())
+            .and_then(|mut f| f.write_all(self.to_junit_xml(suite_name).as_bytes()))
+            .map_err(|e| {
+                Error::IO(format!(
+                    "Could not write the JUnit report {}: {}",
+                    path.as_ref().display(),
+                    e
+                ))
+            })
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut output = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&apos;"),
+            c => output.push(c),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_lines() {
+        assert_eq!(
+            Some(TestEvent::Started("a_test".to_string())),
+            parse_line("fel4-test:start:a_test\r")
+        );
+        assert_eq!(
+            Some(TestEvent::Failed {
+                name: "a_test".to_string(),
+                message: "bad: x\n\\y".to_string(),
+            }),
+            parse_line("garbage fel4-test:fail:a_test:bad: x\\n\\\\y")
+        );
+        assert_eq!(
+            Some(TestEvent::Summary {
+                passed: 1,
+                failed: 2,
+            }),
+            parse_line("fel4-test:summary:1:2")
+        );
+        assert_eq!(None, parse_line("test result: ok. 2 passed; 0 failed"));
+        assert_eq!(None, parse_line("fel4-test:summary:x:2"));
+    }
+
+    #[test]
+    fn legacy_summary_lines() {
+        assert!(is_legacy_summary("test result: ok. 2 passed; 0 failed\n"));
+        assert!(is_legacy_summary("noise test result: FAILED. 1 passed; 1 failed"));
+        assert!(!is_legacy_summary("fel4-test:summary:2:0"));
+        assert!(!is_legacy_summary("test result: pending"));
+    }
+
+    #[test]
+    fn report_outcomes() {
+        let mut report = TestReport::default();
+        assert!(report.is_empty());
+        for line in &[
+            "fel4-test:start:first",
+            "fel4-test:pass:first",
+            "fel4-test:start:second",
+            "fel4-test:fail:second:<oops>",
+            "fel4-test:start:third",
+        ] {
+            report.record(parse_line(line).unwrap());
+        }
+        assert!(!report.is_empty());
+        assert!(!report.is_complete());
+        assert!(!report.success());
+        assert_eq!(1, report.passed());
+        assert_eq!(2, report.failures().len());
+        report.record(TestEvent::Summary {
+            passed: 1,
+            failed: 1,
+        });
+        assert!(report.is_complete());
+        assert!(!report.success());
+        let xml = report.to_junit_xml("my-project");
+        assert!(xml.contains("tests=\"3\" failures=\"2\""));
+        assert!(xml.contains("&lt;oops&gt;"));
+    }
+}
@@ -0,0 +1,447 @@
+//! U-Boot legacy uImage and FIT image packaging, so that no `mkimage` is
+//! needed to boot a feL4 system image with U-Boot
+
+use fel4_config::SupportedTarget;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Error;
+use elf::{load_image, LoadImage};
+use settings::{UbootFormat, UbootSettings};
+
+const UIMAGE_MAGIC: u32 = 0x2705_1956;
+const UIMAGE_HEADER_SIZE: usize = 64;
+const UIMAGE_NAME_SIZE: usize = 32;
+const IH_OS_LINUX: u8 = 5;
+const IH_TYPE_KERNEL: u8 = 2;
+const IH_COMP_NONE: u8 = 0;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// The image description when none is configured
+const DEFAULT_IMAGE_NAME: &str = "feL4";
+
+/// U-Boot boots the elfloader image of the ARM targets, the x86_64 target
+/// has nothing for it to boot
+pub const UBOOT_TARGETS_MESSAGE: &str = "U-Boot images only apply to the ARM targets";
+
+/// A system image ready to be wrapped, with its addresses resolved
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UbootImage<'a> {
+    pub name: &'a str,
+    /// The architecture name U-Boot knows the target by
+    pub arch: &'static str,
+    pub load_address: u64,
+    pub entry_address: u64,
+    pub timestamp: u32,
+    pub data: &'a [u8],
+}
+
+/// The file name of the U-Boot image produced from an image called
+/// `image_name`
+pub fn uboot_file_name(image_name: &str, settings: &UbootSettings) -> String {
+    match settings.format.unwrap_or(UbootFormat::Uimage) {
+        UbootFormat::Uimage => format!("{}.uImage", image_name),
+        UbootFormat::Fit => format!("{}.itb", image_name),
+    }
+}
+
+/// The path of the U-Boot image produced next to the given ELF image
+pub fn uboot_image_path(elf_path: &Path, settings: &UbootSettings) -> PathBuf {
+    let image_name = elf_path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "feL4img".to_string());
+    elf_path.with_file_name(uboot_file_name(&image_name, settings))
+}
+
+/// Read the ELF image at `elf_path`, flatten its loadable segments and write
+/// them to `output_path` wrapped in the configured U-Boot image format
+pub fn write_uboot_image(
+    elf_path: &Path,
+    output_path: &Path,
+    target: &SupportedTarget,
+    settings: &UbootSettings,
+) -> Result<(), Error> {
+    let contents = fs::read(elf_path)
+        .map_err(|e| Error::IO(format!("Could not read '{}': {}", elf_path.display(), e)))?;
+    let LoadImage {
+        load_address,
+        entry_address,
+        data,
+    } = load_image(&contents).ok_or_else(|| {
+        Error::ConfigError(format!(
+            "Could not find the loadable segments of the ELF image '{}'",
+            elf_path.display()
+        ))
+    })?;
+    let load_address = match settings.load_address {
+        Some(ref address) => parse_address(address)?,
+        None => load_address,
+    };
+    let entry_address = match settings.entry_address {
+        Some(ref address) => parse_address(address)?,
+        None => entry_address,
+    };
+    let image = UbootImage {
+        name: settings
+            .name
+            .as_ref()
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_IMAGE_NAME),
+        arch: uboot_arch(target),
+        load_address,
+        entry_address,
+        timestamp: build_timestamp(),
+        data: &data,
+    };
+    let packaged = match settings.format.unwrap_or(UbootFormat::Uimage) {
+        UbootFormat::Uimage => uimage(&image)?,
+        UbootFormat::Fit => fit_image(&image),
+    };
+    fs::write(output_path, packaged).map_err(|e| {
+        Error::IO(format!(
+            "Could not write '{}': {}",
+            output_path.display(),
+            e
+        ))
+    })
+}
+
+/// The architecture name of a target as known to U-Boot
+pub fn uboot_arch(target: &SupportedTarget) -> &'static str {
+    match *target {
+        SupportedTarget::X8664Sel4Fel4 => "x86_64",
+        SupportedTarget::Armv7Sel4Fel4 => "arm",
+        SupportedTarget::Aarch64Sel4Fel4 => "arm64",
+    }
+}
+
+/// The numeric architecture of a legacy image header
+fn uimage_arch(arch: &str) -> u8 {
+    match arch {
+        "arm" => 2,
+        "arm64" => 22,
+        _ => 24,
+    }
+}
+
+/// Parse an address from fel4.toml, either hexadecimal with a `0x` prefix
+/// or decimal
+pub fn parse_address(address: &str) -> Result<u64, Error> {
+    let address = address.trim();
+    let parsed = if address.starts_with("0x") || address.starts_with("0X") {
+        u64::from_str_radix(&address[2..].replace("_", ""), 16)
+    } else {
+        address.replace("_", "").parse()
+    };
+    parsed.map_err(|_| Error::ConfigError(format!("Invalid U-Boot address '{}'", address)))
+}
+
+/// The image creation time, which honors `SOURCE_DATE_EPOCH` for
+/// reproducible builds
+fn build_timestamp() -> u32 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as u32)
+                .unwrap_or(0)
+        })
+}
+
+/// A legacy image: a 64 byte big-endian header followed by the data
+pub fn uimage(image: &UbootImage) -> Result<Vec<u8>, Error> {
+    if image.load_address > u64::from(u32::max_value())
+        || image.entry_address > u64::from(u32::max_value())
+        || image.data.len() > u32::max_value() as usize
+    {
+        return Err(Error::ConfigError(
+            "The legacy uImage format is limited to 32 bit addresses and sizes, consider the fit format"
+                .to_string(),
+        ));
+    }
+    let mut header = Vec::with_capacity(UIMAGE_HEADER_SIZE + image.data.len());
+    push_be_u32(&mut header, UIMAGE_MAGIC);
+    // The header checksum is computed with this field zeroed
+    push_be_u32(&mut header, 0);
+    push_be_u32(&mut header, image.timestamp);
+    push_be_u32(&mut header, image.data.len() as u32);
+    push_be_u32(&mut header, image.load_address as u32);
+    push_be_u32(&mut header, image.entry_address as u32);
+    push_be_u32(&mut header, crc32(image.data));
+    header.push(IH_OS_LINUX);
+    header.push(uimage_arch(image.arch));
+    header.push(IH_TYPE_KERNEL);
+    header.push(IH_COMP_NONE);
+    let mut name = image.name.as_bytes().to_vec();
+    // Leave room for the terminating NUL
+    name.truncate(UIMAGE_NAME_SIZE - 1);
+    name.resize(UIMAGE_NAME_SIZE, 0);
+    header.extend_from_slice(&name);
+
+    let header_crc = crc32(&header);
+    header[4..8].copy_from_slice(&be_u32(header_crc));
+    header.extend_from_slice(image.data);
+    Ok(header)
+}
+
+/// A Flattened Image Tree holding the data as its only kernel image, with a
+/// single default configuration booting it
+pub fn fit_image(image: &UbootImage) -> Vec<u8> {
+    let wide_addresses = image.load_address > u64::from(u32::max_value())
+        || image.entry_address > u64::from(u32::max_value());
+    let address = |value: u64| {
+        if wide_addresses {
+            let mut cells = be_u32((value >> 32) as u32).to_vec();
+            cells.extend_from_slice(&be_u32(value as u32));
+            cells
+        } else {
+            be_u32(value as u32).to_vec()
+        }
+    };
+
+    let mut fdt = FdtBuilder::new();
+    fdt.begin_node("");
+    fdt.string_property("description", image.name);
+    fdt.u32_property("timestamp", image.timestamp);
+    fdt.u32_property("#address-cells", if wide_addresses { 2 } else { 1 });
+    fdt.begin_node("images");
+    fdt.begin_node("kernel@1");
+    fdt.string_property("description", image.name);
+    fdt.property("data", image.data);
+    fdt.string_property("type", "kernel");
+    fdt.string_property("arch", image.arch);
+    fdt.string_property("os", "linux");
+    fdt.string_property("compression", "none");
+    fdt.property("load", &address(image.load_address));
+    fdt.property("entry", &address(image.entry_address));
+    fdt.begin_node("hash@1");
+    fdt.string_property("algo", "crc32");
+    fdt.u32_property("value", crc32(image.data));
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+    fdt.begin_node("configurations");
+    fdt.string_property("default", "conf@1");
+    fdt.begin_node("conf@1");
+    fdt.string_property("description", image.name);
+    fdt.string_property("kernel", "kernel@1");
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// Builds a flattened device tree blob, version 17
+struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+}
+
+impl FdtBuilder {
+    fn new() -> FdtBuilder {
+        FdtBuilder {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+        }
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        push_be_u32(&mut self.structure, FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        pad_to_u32(&mut self.structure);
+    }
+
+    fn end_node(&mut self) {
+        push_be_u32(&mut self.structure, FDT_END_NODE);
+    }
+
+    fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        push_be_u32(&mut self.structure, FDT_PROP);
+        push_be_u32(&mut self.structure, value.len() as u32);
+        push_be_u32(&mut self.structure, name_offset);
+        self.structure.extend_from_slice(value);
+        pad_to_u32(&mut self.structure);
+    }
+
+    fn string_property(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    fn u32_property(&mut self, name: &str, value: u32) {
+        self.property(name, &be_u32(value));
+    }
+
+    /// The offset of a property name in the strings block, which holds each
+    /// name once
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        push_be_u32(&mut self.structure, FDT_END);
+        // The memory reservation block holds only its terminating entry
+        let reservations_offset = FDT_HEADER_SIZE;
+        let structure_offset = reservations_offset + 16;
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let mut blob = Vec::with_capacity(total_size);
+        push_be_u32(&mut blob, FDT_MAGIC);
+        push_be_u32(&mut blob, total_size as u32);
+        push_be_u32(&mut blob, structure_offset as u32);
+        push_be_u32(&mut blob, strings_offset as u32);
+        push_be_u32(&mut blob, reservations_offset as u32);
+        // The version, and the oldest version it is compatible with
+        push_be_u32(&mut blob, 17);
+        push_be_u32(&mut blob, 16);
+        // boot_cpuid_phys
+        push_be_u32(&mut blob, 0);
+        push_be_u32(&mut blob, self.strings.len() as u32);
+        push_be_u32(&mut blob, self.structure.len() as u32);
+        blob.resize(structure_offset, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+fn be_u32(value: u32) -> [u8; 4] {
+    [
+        (value >> 24) as u8,
+        (value >> 16) as u8,
+        (value >> 8) as u8,
+        value as u8,
+    ]
+}
+
+fn push_be_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&be_u32(value));
+}
+
+fn pad_to_u32(bytes: &mut Vec<u8>) {
+    while bytes.len() % 4 != 0 {
+        bytes.push(0);
+    }
+}
+
+/// The CRC-32 used by U-Boot, zlib and Ethernet: reflected, with the
+/// polynomial 0x04C11DB7
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image<'a>(data: &'a [u8]) -> UbootImage<'a> {
+        UbootImage {
+            name: "feL4",
+            arch: "arm",
+            load_address: 0x2000_0000,
+            entry_address: 0x2000_0040,
+            timestamp: 1_500_000_000,
+            data,
+        }
+    }
+
+    fn read_be_u32(bytes: &[u8], offset: usize) -> u32 {
+        bytes[offset..offset + 4]
+            .iter()
+            .fold(0, |value, byte| (value << 8) | u32::from(*byte))
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+        assert_eq!(0, crc32(b""));
+    }
+
+    #[test]
+    fn uimage_header() {
+        let packaged = uimage(&test_image(b"elfloader")).unwrap();
+        assert_eq!(UIMAGE_HEADER_SIZE + 9, packaged.len());
+        assert_eq!(UIMAGE_MAGIC, read_be_u32(&packaged, 0));
+        assert_eq!(9, read_be_u32(&packaged, 12));
+        assert_eq!(0x2000_0000, read_be_u32(&packaged, 16));
+        assert_eq!(0x2000_0040, read_be_u32(&packaged, 20));
+        assert_eq!(crc32(b"elfloader"), read_be_u32(&packaged, 24));
+        assert_eq!(&[IH_OS_LINUX, 2, IH_TYPE_KERNEL, IH_COMP_NONE], &packaged[28..32]);
+        assert_eq!(b"feL4\0", &packaged[32..37]);
+
+        let mut header = packaged[..UIMAGE_HEADER_SIZE].to_vec();
+        let header_crc = read_be_u32(&header, 4);
+        header[4..8].copy_from_slice(&[0; 4]);
+        assert_eq!(crc32(&header), header_crc);
+
+        let mut wide = test_image(b"elfloader");
+        wide.load_address = 0x1_0000_0000;
+        assert!(uimage(&wide).is_err());
+    }
+
+    #[test]
+    fn fit_image_blob() {
+        let blob = fit_image(&test_image(b"elfloader"));
+        assert_eq!(FDT_MAGIC, read_be_u32(&blob, 0));
+        assert_eq!(blob.len() as u32, read_be_u32(&blob, 4));
+        let structure_offset = read_be_u32(&blob, 8) as usize;
+        let strings_offset = read_be_u32(&blob, 12) as usize;
+        let structure_size = read_be_u32(&blob, 36) as usize;
+        assert_eq!(strings_offset, structure_offset + structure_size);
+        // The root node, with an empty name
+        assert_eq!(FDT_BEGIN_NODE, read_be_u32(&blob, structure_offset));
+        assert_eq!(0, read_be_u32(&blob, structure_offset + 4));
+        assert_eq!(
+            FDT_END,
+            read_be_u32(&blob, structure_offset + structure_size - 4)
+        );
+        // Every property name is stored once
+        let strings = String::from_utf8_lossy(&blob[strings_offset..]).into_owned();
+        assert_eq!(1, strings.matches("description\0").count());
+        assert!(strings.contains("load\0"));
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(0x2000_0000, parse_address("0x20000000").unwrap());
+        assert_eq!(0x8000_0000, parse_address("0x8000_0000").unwrap());
+        assert_eq!(4096, parse_address("4096").unwrap());
+        assert!(parse_address("0xnope").is_err());
+    }
+}
@@ -0,0 +1,368 @@
+use cmake_config::{parse_file_to_raw, RawFlag, SimpleFlag};
+use fel4_config::get_fel4_config;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use super::Error;
+use cmake_codegen::{properties_to_simple_flags, CMakeCodegenError};
+use config::{get_fel4_package, Fel4BuildProfile, Fel4Package, VerifyCmd};
+use machine_message::FlagMismatch;
+
+pub fn handle_verify_cmd(cmd: &VerifyCmd) -> Result<(), Error> {
+    let Fel4Package { root_dir, .. } = get_fel4_package(
+        &cmd.cargo_manifest_path,
+        cmd.package.as_ref().map(String::as_str),
+    )?;
+    let build_profile = Fel4BuildProfile::from(cmd);
+    let fel4_config = get_fel4_config(
+        root_dir.join("fel4.toml"),
+        &build_profile.as_fel4_config_build_profile(),
+    ).map_err(|e| Error::ConfigError(format!("{}", e)))?;
+    let cmake_cache_path: PathBuf = match cmd.cmake_cache {
+        Some(ref path) => path.clone(),
+        None => root_dir
+            .join(&fel4_config.artifact_path)
+            .join(build_profile.artifact_subdir_path())
+            .join("CMakeCache.txt"),
+    };
+    if !cmake_cache_path.exists() {
+        return Err(Error::ConfigError(format!(
+            "Cannot find '{}', build the project with `cargo fel4 build` first \
+             or point --cmake-cache at a CMakeCache.txt",
+            cmake_cache_path.display()
+        )));
+    }
+    let cmake_flags = parse_file_to_raw(&cmake_cache_path).map_err(CMakeCodegenError::from)?;
+    let report = ConsistencyReport::new(
+        &properties_to_simple_flags(&fel4_config.properties),
+        &cmake_flags,
+    );
+
+    println!(
+        "Checking the {} properties of fel4.toml against '{}'\n",
+        build_profile.full_name(),
+        cmake_cache_path.display()
+    );
+    print!("{}", report.render_table());
+    let hints = report.hints();
+    if !hints.is_empty() {
+        println!();
+        for hint in hints {
+            println!("hint: {}", hint);
+        }
+    }
+    report.into_result()
+}
+
+/// How a fel4.toml property compares to the value seL4's CMake build holds
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FlagStatus {
+    Match,
+    /// Both values are the same boolean, spelled differently
+    BoolishSpelling,
+    Mismatch,
+    /// CMakeCache.txt does not hold the property at all
+    Missing,
+}
+
+impl FlagStatus {
+    fn label(&self) -> &'static str {
+        match *self {
+            FlagStatus::Match => "ok",
+            FlagStatus::BoolishSpelling => "spelling",
+            FlagStatus::Mismatch => "MISMATCH",
+            FlagStatus::Missing => "MISSING",
+        }
+    }
+
+    /// Whether the status makes the configuration inconsistent
+    pub fn is_problem(&self) -> bool {
+        *self == FlagStatus::Mismatch || *self == FlagStatus::Missing
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FlagComparison {
+    pub key: String,
+    pub fel4_value: String,
+    pub cmake_value: Option<String>,
+    pub status: FlagStatus,
+}
+
+/// The comparison of every fel4.toml property with CMakeCache.txt, sorted
+/// by key
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConsistencyReport {
+    pub comparisons: Vec<FlagComparison>,
+}
+
+impl ConsistencyReport {
+    /// Compare the properties with every CMake cache entry, rather than only
+    /// the `STRING` and `BOOL` ones `filter_to_interesting_flags` selects, as
+    /// properties may name e.g. `PATH` or `INTERNAL` entries
+    pub fn new(fel4_flags: &[SimpleFlag], cmake_flags: &[RawFlag]) -> ConsistencyReport {
+        let mut comparisons: Vec<FlagComparison> = fel4_flags
+            .iter()
+            .map(|fel4_flag| {
+                let key = fel4_flag.key().0.clone();
+                let fel4_value = fel4_flag.value_string();
+                let cmake_flag = cmake_flags.iter().find(|raw_flag| raw_flag.key == key);
+                FlagComparison {
+                    key,
+                    fel4_value,
+                    cmake_value: cmake_flag.map(|raw_flag| raw_flag.value.clone()),
+                    status: cmake_flag.map_or(FlagStatus::Missing, |raw_flag| {
+                        compare(fel4_flag, raw_flag)
+                    }),
+                }
+            })
+            .collect();
+        comparisons.sort_by(|a, b| a.key.cmp(&b.key));
+        ConsistencyReport { comparisons }
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        !self.comparisons.iter().any(|c| c.status.is_problem())
+    }
+
+    /// The inconsistent properties, as reported by the build's machine
+    /// readable output
+    pub fn mismatches(&self) -> Vec<FlagMismatch> {
+        self.comparisons
+            .iter()
+            .filter(|c| c.status.is_problem())
+            .map(|c| FlagMismatch {
+                key: c.key.clone(),
+                fel4_value: c.fel4_value.clone(),
+                cmake_value: c.cmake_value.clone(),
+            })
+            .collect()
+    }
+
+    /// A table of the comparisons followed by a line of totals
+    pub fn render_table(&self) -> String {
+        let headers = ["PROPERTY", "FEL4.TOML", "CMAKECACHE.TXT", "STATUS"];
+        let rows: Vec<[&str; 4]> = self
+            .comparisons
+            .iter()
+            .map(|c| {
+                [
+                    c.key.as_str(),
+                    c.fel4_value.as_str(),
+                    c.cmake_value.as_ref().map(String::as_str).unwrap_or("-"),
+                    c.status.label(),
+                ]
+            })
+            .collect();
+        let mut widths = [0; 4];
+        for row in rows.iter().chain(Some(&headers)) {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let mut table = String::new();
+        for row in Some(&headers).into_iter().chain(rows.iter()) {
+            let line = format!(
+                "{:w0$}  {:w1$}  {:w2$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2]
+            );
+            let _ = writeln!(table, "{}", line.trim_matches(' '));
+        }
+        let count = |status: FlagStatus| {
+            self.comparisons
+                .iter()
+                .filter(|c| c.status == status)
+                .count()
+        };
+        let _ = writeln!(
+            table,
+            "\n{} matching, {} spelled differently, {} mismatched, {} missing",
+            count(FlagStatus::Match),
+            count(FlagStatus::BoolishSpelling),
+            count(FlagStatus::Mismatch),
+            count(FlagStatus::Missing)
+        );
+        table
+    }
+
+    /// Suggestions for resolving each property that does not match
+    pub fn hints(&self) -> Vec<String> {
+        self.comparisons
+            .iter()
+            .filter_map(|c| {
+                let cmake_value = c.cmake_value.as_ref().map(String::as_str).unwrap_or("");
+                match c.status {
+                    FlagStatus::Match => None,
+                    FlagStatus::BoolishSpelling => Some(format!(
+                        "{} is spelled '{}' in fel4.toml and '{}' in CMakeCache.txt, which \
+                         CMake reads as the same boolean; a TOML true or false avoids the \
+                         ambiguity",
+                        c.key, c.fel4_value, cmake_value
+                    )),
+                    FlagStatus::Mismatch => Some(format!(
+                        "{} is '{}' in fel4.toml but '{}' in CMakeCache.txt; rebuild with \
+                         `cargo fel4 build` to reconfigure seL4, after `cargo fel4 clean` \
+                         if the cache is stale",
+                        c.key, c.fel4_value, cmake_value
+                    )),
+                    FlagStatus::Missing => Some(format!(
+                        "{} is not an seL4 configuration option in CMakeCache.txt; check \
+                         its spelling, or remove it from fel4.toml",
+                        c.key
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    /// An error summarizing the inconsistent properties, if there are any
+    pub fn into_result(self) -> Result<(), Error> {
+        let problems: Vec<String> = self
+            .comparisons
+            .into_iter()
+            .filter(|c| c.status.is_problem())
+            .map(|c| c.key)
+            .collect();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::ConfigError(format!(
+                "The fel4.toml properties {} do not match seL4's CMakeCache.txt",
+                problems.join(", ")
+            )))
+        }
+    }
+}
+
+fn compare(fel4_flag: &SimpleFlag, raw_flag: &RawFlag) -> FlagStatus {
+    let cmake_flag = &SimpleFlag::from(raw_flag);
+    let is_bool = |f: &SimpleFlag| match f {
+        SimpleFlag::Boolish(_, _) => true,
+        _ => false,
+    };
+    if fel4_flag == cmake_flag {
+        FlagStatus::Match
+    } else if !is_bool(fel4_flag) && !is_bool(cmake_flag) {
+        // e.g. a fel4.toml string naming a CMake PATH value
+        if fel4_flag.value_string() == cmake_flag.value_string() {
+            FlagStatus::Match
+        } else {
+            FlagStatus::Mismatch
+        }
+    } else {
+        match (
+            boolean_spelling(&fel4_flag.value_string()),
+            boolean_spelling(&raw_flag.value),
+        ) {
+            (Some(a), Some(b)) if a == b => FlagStatus::BoolishSpelling,
+            _ => FlagStatus::Mismatch,
+        }
+    }
+}
+
+/// The boolean a value unambiguously spells, in the words CMake accepts
+fn boolean_spelling(value: &str) -> Option<bool> {
+    match value.to_uppercase().as_str() {
+        "ON" | "TRUE" | "YES" | "Y" | "1" => Some(true),
+        "OFF" | "FALSE" | "NO" | "N" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cmake_config::{CMakeType, Key};
+
+    fn cmake_flag(key: &str, cmake_type: CMakeType, value: &str) -> RawFlag {
+        RawFlag {
+            key: key.to_string(),
+            cmake_type,
+            value: value.to_string(),
+            help: None,
+            advanced: false,
+        }
+    }
+
+    #[test]
+    fn classify_properties() {
+        let fel4_flags = vec![
+            SimpleFlag::Boolish(Key("KernelPrinting".into()), true),
+            SimpleFlag::Stringish(Key("KernelArch".into()), "x86".into()),
+            SimpleFlag::Stringish(Key("KernelDebugBuild".into()), "ON".into()),
+            SimpleFlag::Integerish(Key("KernelMaxNumNodes".into()), 2),
+            SimpleFlag::Boolish(Key("KernelTypo".into()), false),
+        ];
+        let cmake_flags = vec![
+            cmake_flag("KernelArch", CMakeType::String, "arm"),
+            cmake_flag("KernelDebugBuild", CMakeType::Bool, "TRUE"),
+            cmake_flag("KernelMaxNumNodes", CMakeType::String, "2"),
+            cmake_flag("KernelPrinting", CMakeType::Bool, "ON"),
+        ];
+        let report = ConsistencyReport::new(&fel4_flags, &cmake_flags);
+        let statuses: Vec<(&str, FlagStatus)> = report
+            .comparisons
+            .iter()
+            .map(|c| (c.key.as_str(), c.status))
+            .collect();
+        assert_eq!(
+            vec![
+                ("KernelArch", FlagStatus::Mismatch),
+                ("KernelDebugBuild", FlagStatus::BoolishSpelling),
+                ("KernelMaxNumNodes", FlagStatus::Match),
+                ("KernelPrinting", FlagStatus::Match),
+                ("KernelTypo", FlagStatus::Missing),
+            ],
+            statuses
+        );
+        assert!(!report.is_consistent());
+        assert_eq!(2, report.mismatches().len());
+        assert_eq!(3, report.hints().len());
+        assert_eq!(
+            "PROPERTY           FEL4.TOML  CMAKECACHE.TXT  STATUS\n\
+             KernelArch         x86        arm             MISMATCH\n\
+             KernelDebugBuild   ON         TRUE            spelling\n\
+             KernelMaxNumNodes  2          2               ok\n\
+             KernelPrinting     true       ON              ok\n\
+             KernelTypo         false      -               MISSING\n\
+             \n\
+             2 matching, 1 spelled differently, 1 mismatched, 1 missing\n",
+            report.render_table()
+        );
+        assert_eq!(
+            Err(Error::ConfigError(
+                "The fel4.toml properties KernelArch, KernelTypo do not match seL4's \
+                 CMakeCache.txt"
+                    .to_string()
+            )),
+            report.into_result()
+        );
+    }
+
+    #[test]
+    fn compare_every_cache_type() {
+        let fel4_flags = vec![
+            SimpleFlag::Stringish(Key("KernelCustomDTS".into()), "/dts/tx1.dts".into()),
+            SimpleFlag::Stringish(Key("KernelSel4Arch".into()), "x86_64".into()),
+            SimpleFlag::Stringish(Key("KernelWordSize".into()), "32".into()),
+        ];
+        let cmake_flags = vec![
+            cmake_flag("KernelCustomDTS", CMakeType::Path, "/dts/tx1.dts"),
+            cmake_flag("KernelSel4Arch", CMakeType::Internal, "x86_64"),
+            cmake_flag("KernelWordSize", CMakeType::Internal, "64"),
+        ];
+        let report = ConsistencyReport::new(&fel4_flags, &cmake_flags);
+        let statuses: Vec<FlagStatus> = report.comparisons.iter().map(|c| c.status).collect();
+        assert_eq!(
+            vec![FlagStatus::Match, FlagStatus::Match, FlagStatus::Mismatch],
+            statuses
+        );
+    }
+}
@@ -21,6 +21,11 @@ macro_rules! debug_println {
     ($fmt:expr, $($arg:tt)*) => (debug_print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Lines starting with this prefix are parsed by `cargo fel4 test`, which
+/// reports the results on the host
+#[cfg(feature = "KernelPrinting")]
+const PROTOCOL_PREFIX: &str = "fel4-test";
+
 #[cfg(feature = "KernelPrinting")]
 pub fn run() {
     debug_println!("\n\nrunning example tests");
@@ -28,13 +33,15 @@ pub fn run() {
     let mut num_passed = 0;
     let mut num_failed = 0;
     for found_success in [
-        print_test_result(
+        run_test(
             "test_message_info_predictability",
-            test_message_info_predictability(&mut runner),
+            &mut runner,
+            test_message_info_predictability,
         ),
-        print_test_result(
+        run_test(
             "test_cap_rights_predictability",
-            test_cap_rights_predictability(&mut runner),
+            &mut runner,
+            test_cap_rights_predictability,
         ),
     ].iter()
         {
@@ -45,11 +52,13 @@ pub fn run() {
             }
         }
     debug_println!(
-        "test result: {}. {} passed; {} failed\n\n",
-        if num_failed == 0 { "ok" } else { "FAILED" },
+        "{}:summary:{}:{}",
+        PROTOCOL_PREFIX,
         num_passed,
         num_failed
     );
+    // Ends the simulation, see `cargo fel4 simulate --help`
+    debug_println!("fel4-halt:{}", if num_failed == 0 { 0 } else { 1 });
     halt();
 }
 
@@ -112,19 +121,26 @@ fn test_cap_rights_predictability(
     })
 }
 
-/// Prints a summary of the test output.
+/// Runs a test, reporting its start and result.
 /// Returns true if the test succeeded, false otherwise.
-fn print_test_result<T: fmt::Debug>(
+#[cfg(feature = "KernelPrinting")]
+fn run_test<T: fmt::Debug>(
     test_name: &'static str,
-    result: Result<(), TestError<T>>,
+    runner: &mut TestRunner,
+    test: fn(&mut TestRunner) -> Result<(), TestError<T>>,
 ) -> bool {
-    match result {
+    debug_println!("{}:start:{}", PROTOCOL_PREFIX, test_name);
+    match test(runner) {
         Ok(_) => {
-            debug_println!("{} ... ok", test_name);
+            debug_println!("{}:pass:{}", PROTOCOL_PREFIX, test_name);
             true
         }
         Err(e) => {
-            debug_println!("{} ... FAILED\n\t{}", test_name, e);
+            // The failure message has to fit on a single line
+            let message: String = format!("{}", e)
+                .replace('\\', "\\\\")
+                .replace('\n', "\\n");
+            debug_println!("{}:fail:{}:{}", PROTOCOL_PREFIX, test_name, message);
             false
         }
     }
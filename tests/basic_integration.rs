@@ -77,10 +77,14 @@ sequential_test! {
             },
             release: false,
             tests: false,
+            message_format: cargo_fel4::MessageFormat::Human,
+            backend: None,
+            package: None,
             cargo_manifest_path: target_cargo_manifest.clone(),
         });
         r.expect("could not run fel4 build command");
-        assert!(&target_dir.join("src/bin/root-task.rs").is_file());
+        assert!(&target_dir.join("target/fel4/bar/src/main.rs").is_file());
+        assert!(!&target_dir.join("src/bin").exists());
         assert!(&target_dir.join("artifacts/debug/simulate").is_file());
         assert!(&target_dir.join("artifacts/debug/kernel").is_file());
         assert!(&target_dir.join("artifacts/debug/feL4img").is_file());
@@ -92,6 +96,8 @@ sequential_test! {
             },
             release: false,
             subcmd: Some(cargo_fel4::TestSubCmd::Build),
+            timeout: 300,
+            package: None,
             cargo_manifest_path: target_cargo_manifest.clone(),
         }).expect("Could not run handle_test_command");
         assert!(&target_dir.join("artifacts/test/debug/simulate").is_file());
@@ -103,6 +109,7 @@ sequential_test! {
                 verbose: true,
                 quiet: false,
             },
+            package: None,
             cargo_manifest_path: target_cargo_manifest.clone(),
         }).expect("Could not run clean command");
         assert!(!&target_dir.join("artifacts/debug/simulate").is_file());
@@ -158,10 +165,14 @@ sequential_test! {
             },
             release: false,
             tests: false,
+            message_format: cargo_fel4::MessageFormat::Human,
+            backend: None,
+            package: None,
             cargo_manifest_path: target_cargo_manifest.clone(),
         });
         r.expect("could not run fel4 build command");
-        assert!(&target_dir.join("src/bin/root-task.rs").is_file());
+        assert!(&target_dir.join("target/fel4/armv7/src/main.rs").is_file());
+        assert!(!&target_dir.join("src/bin").exists());
         assert!(&target_dir.join("artifacts/debug/simulate").is_file());
         assert!(&target_dir.join("artifacts/debug/kernel").is_file());
         assert!(&target_dir.join("artifacts/debug/feL4img").is_file());
@@ -173,6 +184,8 @@ sequential_test! {
             },
             release: false,
             subcmd: Some(cargo_fel4::TestSubCmd::Build),
+            timeout: 300,
+            package: None,
             cargo_manifest_path: target_cargo_manifest.clone(),
         }).expect("Could not run handle_test_command");
         assert!(&target_dir.join("artifacts/test/debug/simulate").is_file());
@@ -184,6 +197,7 @@ sequential_test! {
                 verbose: true,
                 quiet: false,
             },
+            package: None,
             cargo_manifest_path: target_cargo_manifest.clone(),
         }).expect("Could not run clean command");
         assert!(!&target_dir.join("artifacts/debug/simulate").is_file());